@@ -0,0 +1,894 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+use std::{env, fs, io};
+
+use crate::auth::{admin_role, require_role, AuthenticatedUser, ANONYMOUS_USER};
+use crate::content_type;
+use crate::metadata::{
+    current_files, read_metadata, update_metadata, StorageLocation, UploadMetadata,
+};
+use crate::namespace::StorageScope;
+
+/// Rejects callers without the admin role
+fn require_admin(user: &AuthenticatedUser) -> Result<(), actix_web::Error> {
+    require_role(user, &admin_role())
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub total_files: usize,
+    pub total_bytes: u64,
+    pub average_file_size: u64,
+    pub uploads_last_24h: usize,
+    pub files_per_user: BTreeMap<String, usize>,
+}
+
+/// Aggregate storage statistics for capacity planning
+pub async fn admin_stats(
+    user: AuthenticatedUser,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_admin(&user)?;
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let files = current_files(&entries);
+
+    let total_files = files.len();
+    let total_bytes: u64 = files.iter().map(|entry| entry.size_bytes).sum();
+    let mut files_per_user = BTreeMap::new();
+    for entry in &files {
+        *files_per_user.entry(entry.user.clone()).or_insert(0) += 1;
+    }
+
+    // Counts every upload event, including re-uploads of an existing name
+    let since = Utc::now() - Duration::hours(24);
+    let uploads_last_24h = entries
+        .iter()
+        .filter(|entry| {
+            DateTime::parse_from_rfc3339(&entry.timestamp)
+                .map(|ts| ts.with_timezone(&Utc) >= since)
+                .unwrap_or(false)
+        })
+        .count();
+
+    Ok(HttpResponse::Ok().json(StatsResponse {
+        total_files,
+        total_bytes,
+        average_file_size: if total_files == 0 {
+            0
+        } else {
+            total_bytes / total_files as u64
+        },
+        uploads_last_24h,
+        files_per_user,
+    }))
+}
+
+#[derive(Serialize, Default)]
+pub struct TypeTotals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct TypeStatsResponse {
+    /// Keyed by lowercase extension without the dot; `(none)` for files without one
+    pub by_extension: BTreeMap<String, TypeTotals>,
+    /// Keyed by `image`, `video`, `document` or `other`
+    pub by_family: BTreeMap<String, TypeTotals>,
+}
+
+/// Broad family of a content type; files without a recorded type count as `other`
+fn type_family(content_type: Option<&str>) -> &'static str {
+    let Some(mime) = content_type else {
+        return "other";
+    };
+    if content_type::matches_filter(mime, "image/*") {
+        "image"
+    } else if content_type::matches_filter(mime, "video/*") {
+        "video"
+    } else if content_type::matches_filter(mime, "text/*")
+        || [
+            "application/pdf",
+            "application/rtf",
+            "application/msword",
+            "application/vnd.ms-excel",
+            "application/vnd.ms-powerpoint",
+        ]
+        .iter()
+        .any(|document| content_type::matches_filter(mime, document))
+        || mime.starts_with("application/vnd.openxmlformats-officedocument.")
+        || mime.starts_with("application/vnd.oasis.opendocument.")
+    {
+        "document"
+    } else {
+        "other"
+    }
+}
+
+/// Current files and bytes grouped by extension and by content type family
+pub async fn admin_type_stats(
+    user: AuthenticatedUser,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_admin(&user)?;
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let mut by_extension: BTreeMap<String, TypeTotals> = BTreeMap::new();
+    let mut by_family: BTreeMap<String, TypeTotals> = BTreeMap::new();
+    for entry in current_files(&entries) {
+        let extension = match entry.filename.rfind('.') {
+            Some(dot) if dot > 0 => entry.filename[dot + 1..].to_lowercase(),
+            _ => "(none)".to_string(),
+        };
+        let family = type_family(entry.content_type.as_deref()).to_string();
+        for totals in [
+            by_extension.entry(extension).or_default(),
+            by_family.entry(family).or_default(),
+        ] {
+            totals.files += 1;
+            totals.bytes += entry.size_bytes;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(TypeStatsResponse {
+        by_extension,
+        by_family,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RebuildQuery {
+    /// `merge` (default) keeps existing entries and adds files missing from them;
+    /// `replace` writes one fresh entry per file on disk
+    pub mode: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RebuildResponse {
+    pub mode: String,
+    pub files_scanned: usize,
+    pub entries_added: usize,
+    pub total_entries: usize,
+}
+
+/// Owner recorded for rebuilt files with no known uploader, from `REBUILD_UNKNOWN_USER`
+fn rebuild_unknown_user() -> String {
+    env::var("REBUILD_UNKNOWN_USER").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Builds a metadata entry for a file found on disk, timestamped with its mtime
+fn scan_file(path: &Path, filename: String, user: String) -> io::Result<UploadMetadata> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+
+    let mut entry = UploadMetadata::new(filename, user, size);
+    if let Ok(modified) = file.metadata().and_then(|m| m.modified()) {
+        entry.timestamp = DateTime::<Utc>::from(modified).to_rfc3339();
+    }
+    entry.checksum = Some(hex::encode(hasher.finalize()));
+    entry.storage = Some(StorageLocation::local(path));
+    Ok(entry)
+}
+
+/// Reconstructs metadata from the files in `UPLOADS_DIR`, e.g. after `uploads.json` is lost
+pub async fn rebuild_metadata(
+    user: AuthenticatedUser,
+    query: web::Query<RebuildQuery>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_admin(&user)?;
+    let mode = query.mode.as_deref().unwrap_or("merge").to_lowercase();
+    if mode != "merge" && mode != "replace" {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Invalid mode, use merge or replace",
+        ));
+    }
+
+    let metadata_file = scope.metadata_file;
+    let known_owners: HashMap<String, String> = read_metadata(&metadata_file)?
+        .into_iter()
+        .map(|entry| (entry.filename, entry.user))
+        .collect();
+    let unknown_user = rebuild_unknown_user();
+
+    // Hashing every stored file is slow, so it runs off the async workers
+    let scanned = web::block(move || -> io::Result<Vec<UploadMetadata>> {
+        let dir = scope.uploads_dir;
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut scanned = Vec::new();
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            if !dir_entry.file_type()?.is_file() {
+                continue;
+            }
+            let Ok(filename) = dir_entry.file_name().into_string() else {
+                log::warn!("Skipping non-UTF-8 filename {:?}", dir_entry.file_name());
+                continue;
+            };
+            let owner = known_owners
+                .get(&filename)
+                .cloned()
+                .unwrap_or_else(|| unknown_user.clone());
+            scanned.push(scan_file(&dir_entry.path(), filename, owner)?);
+        }
+        scanned.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(scanned)
+    })
+    .await?
+    .map_err(|e| {
+        log::error!("Failed to scan uploads directory: {}", e);
+        actix_web::error::ErrorInternalServerError(format!(
+            "Failed to scan uploads directory: {}",
+            e
+        ))
+    })?;
+    let files_scanned = scanned.len();
+
+    let (entries_added, total_entries) = update_metadata(&metadata_file, |entries| {
+        if mode == "replace" {
+            entries.clear();
+        }
+        let before = entries.len();
+        for file in scanned {
+            if !entries.iter().any(|entry| entry.filename == file.filename) {
+                entries.push(file);
+            }
+        }
+        (entries.len() - before, entries.len())
+    })?;
+
+    log::info!(
+        "Admin {} rebuilt metadata ({}): {} files scanned, {} entries added",
+        user.sub,
+        mode,
+        files_scanned,
+        entries_added
+    );
+    Ok(HttpResponse::Ok().json(RebuildResponse {
+        mode,
+        files_scanned,
+        entries_added,
+        total_entries,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct BackfillResponse {
+    /// Current files that had no content type recorded
+    pub files_checked: usize,
+    pub content_types_added: usize,
+    /// Files whose leading bytes match no known type and aren't text
+    pub undetected: usize,
+    /// Files recorded in metadata but missing from disk
+    pub missing: usize,
+}
+
+/// Type of a stored file from its leading bytes; only that prefix is read
+fn sniff_file(path: &Path, sniff_bytes: usize) -> io::Result<Option<String>> {
+    let mut head = Vec::with_capacity(sniff_bytes);
+    fs::File::open(path)?
+        .take(sniff_bytes as u64)
+        .read_to_end(&mut head)?;
+    Ok(content_type::detect(&head, None)
+        .or_else(|| content_type::is_text(None, &head).then(|| "text/plain".to_string())))
+}
+
+/// Detects and records content types for files stored before types were tracked.
+/// Entries that already have a type are left alone.
+pub async fn backfill_content_types(
+    user: AuthenticatedUser,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_admin(&user)?;
+
+    let metadata_file = scope.metadata_file;
+    // Keyed by checksum too, so older entries for a reused name keep no type
+    let pending: Vec<(String, Option<String>)> = current_files(&read_metadata(&metadata_file)?)
+        .into_iter()
+        .filter(|entry| entry.content_type.is_none())
+        .map(|entry| (entry.filename.clone(), entry.checksum.clone()))
+        .collect();
+    let files_checked = pending.len();
+
+    let uploads_dir = scope.uploads_dir;
+    let sniff_bytes = content_type::sniff_bytes();
+    let (detected, undetected, missing) = web::block(move || {
+        let mut detected = HashMap::new();
+        let (mut undetected, mut missing) = (0, 0);
+        for (done, (filename, checksum)) in pending.into_iter().enumerate() {
+            if done > 0 && done % 100 == 0 {
+                log::info!(
+                    "Content type backfill: {}/{} files checked",
+                    done,
+                    files_checked
+                );
+            }
+            match sniff_file(&uploads_dir.join(&filename), sniff_bytes) {
+                Ok(Some(content_type)) => {
+                    detected.insert((filename, checksum), content_type);
+                }
+                Ok(None) => undetected += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => missing += 1,
+                Err(e) => {
+                    log::warn!("Failed to read {}: {}", filename, e);
+                    missing += 1;
+                }
+            }
+        }
+        (detected, undetected, missing)
+    })
+    .await?;
+
+    let content_types_added = update_metadata(&metadata_file, |entries| {
+        let mut added = 0;
+        for entry in entries
+            .iter_mut()
+            .filter(|entry| entry.content_type.is_none())
+        {
+            let key = (entry.filename.clone(), entry.checksum.clone());
+            if let Some(content_type) = detected.get(&key) {
+                entry.content_type = Some(content_type.clone());
+                added += 1;
+            }
+        }
+        added
+    })?;
+
+    log::info!(
+        "Admin {} backfilled {} content types ({} files checked, {} undetected, {} missing)",
+        user.sub,
+        content_types_added,
+        files_checked,
+        undetected,
+        missing
+    );
+    Ok(HttpResponse::Ok().json(BackfillResponse {
+        files_checked,
+        content_types_added,
+        undetected,
+        missing,
+    }))
+}
+
+/// Whether `GET /api/admin/orphaned-users` looks subjects up in Keycloak, from
+/// `ORPHANED_USERS_KEYCLOAK_CHECK`. The `CLIENT_ID` service account needs the
+/// `view-users` realm-management role.
+fn orphaned_users_keycloak_check() -> bool {
+    env::var("ORPHANED_USERS_KEYCLOAK_CHECK")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// What Keycloak knows about a subject found in metadata
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    Active,
+    Disabled,
+    /// Deleted from the realm
+    Missing,
+}
+
+#[derive(Serialize)]
+pub struct SubjectUsage {
+    pub user: String,
+    pub files: usize,
+    pub bytes: u64,
+    /// Absent unless Keycloak was checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<AccountStatus>,
+}
+
+#[derive(Serialize)]
+pub struct OrphanedUsersResponse {
+    pub keycloak_checked: bool,
+    /// Bytes held by subjects that are missing or disabled upstream
+    pub reclaimable_bytes: u64,
+    pub users: Vec<SubjectUsage>,
+}
+
+/// Token for Keycloak's admin API via the client credentials grant
+async fn keycloak_admin_token(
+    client: &reqwest::Client,
+    keycloak_url: &str,
+    realm: &str,
+) -> Result<String, String> {
+    let client_id = env::var("CLIENT_ID").map_err(|_| "CLIENT_ID is not set".to_string())?;
+    let client_secret =
+        env::var("CLIENT_SECRET").map_err(|_| "CLIENT_SECRET is not set".to_string())?;
+    let token_url = format!(
+        "{}/realms/{}/protocol/openid-connect/token",
+        keycloak_url, realm
+    );
+    let params = [
+        ("grant_type", "client_credentials"),
+        ("client_id", client_id.as_str()),
+        ("client_secret", client_secret.as_str()),
+    ];
+    let response = client
+        .post(&token_url)
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to connect to Keycloak: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Keycloak refused the client credentials grant: {}",
+            response.status()
+        ));
+    }
+    let body: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse token response: {}", e))?;
+    body["access_token"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| "Token response has no access_token".to_string())
+}
+
+/// Looks a subject up through `GET /admin/realms/{realm}/users/{id}`
+async fn keycloak_account_status(
+    client: &reqwest::Client,
+    users_url: &str,
+    token: &str,
+    sub: &str,
+) -> Result<AccountStatus, String> {
+    let response = client
+        .get(format!("{}/{}", users_url, sub))
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to connect to Keycloak: {}", e))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(AccountStatus::Missing);
+    }
+    if !response.status().is_success() {
+        return Err(format!(
+            "Keycloak user lookup failed: {}",
+            response.status()
+        ));
+    }
+    let body: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse user response: {}", e))?;
+    Ok(if body["enabled"].as_bool() == Some(false) {
+        AccountStatus::Disabled
+    } else {
+        AccountStatus::Active
+    })
+}
+
+/// Storage held per subject, flagged against Keycloak when `ORPHANED_USERS_KEYCLOAK_CHECK`
+/// is on so admins can reclaim the files of deprovisioned users
+pub async fn orphaned_users(
+    user: AuthenticatedUser,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    require_admin(&user)?;
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let mut usage: BTreeMap<String, SubjectUsage> = BTreeMap::new();
+    for entry in current_files(&entries) {
+        // Anonymous uploads have no account to look up
+        if entry.user == ANONYMOUS_USER {
+            continue;
+        }
+        let subject = usage
+            .entry(entry.user.clone())
+            .or_insert_with(|| SubjectUsage {
+                user: entry.user.clone(),
+                files: 0,
+                bytes: 0,
+                status: None,
+            });
+        subject.files += 1;
+        subject.bytes += entry.size_bytes;
+    }
+    let mut users: Vec<SubjectUsage> = usage.into_values().collect();
+
+    let keycloak_checked = orphaned_users_keycloak_check();
+    if keycloak_checked {
+        let keycloak_url = env::var("KEYCLOAK_URL")
+            .map_err(|_| actix_web::error::ErrorInternalServerError("KEYCLOAK_URL is not set"))?;
+        let realm = env::var("KEYCLOAK_REALM").unwrap_or_else(|_| "upload-realm".to_string());
+        let client = reqwest::Client::new();
+        let upstream_error = |e: String| {
+            log::error!("Orphaned user check failed: {}", e);
+            actix_web::error::ErrorBadGateway(e)
+        };
+        let token = keycloak_admin_token(&client, &keycloak_url, &realm)
+            .await
+            .map_err(upstream_error)?;
+        let users_url = format!("{}/admin/realms/{}/users", keycloak_url, realm);
+        for subject in &mut users {
+            let status = keycloak_account_status(&client, &users_url, &token, &subject.user)
+                .await
+                .map_err(upstream_error)?;
+            subject.status = Some(status);
+        }
+    }
+
+    let reclaimable_bytes = users
+        .iter()
+        .filter(|subject| {
+            matches!(
+                subject.status,
+                Some(AccountStatus::Missing | AccountStatus::Disabled)
+            )
+        })
+        .map(|subject| subject.bytes)
+        .sum();
+    log::info!(
+        "Admin {} listed {} subjects ({} reclaimable bytes)",
+        user.sub,
+        users.len(),
+        reclaimable_bytes
+    );
+    Ok(HttpResponse::Ok().json(OrphanedUsersResponse {
+        keycloak_checked,
+        reclaimable_bytes,
+        users,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        app, Form, MockKeycloak, TestEnv, TEST_ROLES_HEADER, TEST_USER_HEADER,
+    };
+    use actix_http::Request;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+
+    #[actix_web::test]
+    async fn admin_stats_aggregate_current_files() {
+        let env = TestEnv::new();
+        let mut old = UploadMetadata::new("a.txt".into(), "alice".into(), 100);
+        old.timestamp = (Utc::now() - Duration::days(2)).to_rfc3339();
+        env.seed(&[
+            old,
+            UploadMetadata::new("a.txt".into(), "alice".into(), 300),
+            UploadMetadata::new("b.txt".into(), "alice".into(), 200),
+            UploadMetadata::new("c.txt".into(), "bob".into(), 700),
+        ]);
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/stats")
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stats: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(stats["total_files"], 3);
+        assert_eq!(stats["total_bytes"], 1200);
+        assert_eq!(stats["average_file_size"], 400);
+        assert_eq!(stats["uploads_last_24h"], 3);
+        assert_eq!(stats["files_per_user"]["alice"], 2);
+        assert_eq!(stats["files_per_user"]["bob"], 1);
+    }
+
+    #[actix_web::test]
+    async fn admin_stats_are_forbidden_without_the_admin_role() {
+        let _env = TestEnv::new().with("ADMIN_ROLE", "ops");
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/stats")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .insert_header((TEST_ROLES_HEADER, "admin,user"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = TestRequest::get()
+            .uri("/api/admin/stats")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .insert_header((TEST_ROLES_HEADER, "ops"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    /// Uploads `content` as `filename` for `user`
+    async fn upload<S, B>(app: &S, user: &str, filename: &str, content: &[u8])
+    where
+        S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        let req = Form::new()
+            .file(filename, content)
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, user))
+            .to_request();
+        assert_eq!(call_service(app, req).await.status(), StatusCode::OK);
+    }
+
+    /// `POST uri` as an admin
+    fn admin_post(uri: &str) -> Request {
+        TestRequest::post()
+            .uri(uri)
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request()
+    }
+
+    #[actix_web::test]
+    async fn rebuild_restores_lost_metadata_from_disk() {
+        let env = TestEnv::new().with("REBUILD_UNKNOWN_USER", "recovered");
+        let app = init_service(app()).await;
+        upload(&app, "alice", "a.txt", b"hello").await;
+        upload(&app, "bob", "b.txt", b"goodbye").await;
+        fs::remove_file(env.metadata_file()).unwrap();
+
+        let resp = call_service(&app, admin_post("/api/admin/metadata/rebuild")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["files_scanned"], 2);
+        assert_eq!(body["entries_added"], 2);
+
+        let mut entries = env.entries();
+        entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].filename, "a.txt");
+        assert_eq!(entries[0].user, "recovered");
+        assert_eq!(entries[0].size_bytes, 5);
+        let checksum = hex::encode(Sha256::digest(b"hello"));
+        assert_eq!(entries[0].checksum.as_deref(), Some(checksum.as_str()));
+        assert_eq!(entries[1].filename, "b.txt");
+        assert_eq!(entries[1].size_bytes, 7);
+    }
+
+    #[actix_web::test]
+    async fn rebuild_merges_missing_files_into_existing_metadata() {
+        let env = TestEnv::new();
+        let app = init_service(app()).await;
+        upload(&app, "alice", "a.txt", b"hello").await;
+        fs::write(env.uploads_dir().join("stray.txt"), b"found").unwrap();
+
+        let resp = call_service(&app, admin_post("/api/admin/metadata/rebuild?mode=merge")).await;
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["entries_added"], 1);
+        assert_eq!(body["total_entries"], 2);
+        let owners: Vec<(String, String)> = env
+            .entries()
+            .into_iter()
+            .map(|entry| (entry.filename, entry.user))
+            .collect();
+        assert!(owners.contains(&("a.txt".into(), "alice".into())));
+        assert!(owners.contains(&("stray.txt".into(), "unknown".into())));
+
+        let resp = call_service(&app, admin_post("/api/admin/metadata/rebuild?mode=wipe")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn backfill_fills_only_missing_content_types() {
+        const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let env = TestEnv::new();
+        fs::create_dir_all(env.uploads_dir()).unwrap();
+        let mut entries = Vec::new();
+        for (name, content, content_type) in [
+            ("image.bin", Some(PNG), None),
+            ("notes", Some(&b"plain words"[..]), None),
+            ("typed.bin", Some(PNG), Some("application/x-custom")),
+            ("noise.bin", Some(&b"\x00\x9f\x92\x96\xff"[..]), None),
+            ("gone.png", None, None),
+        ] {
+            if let Some(content) = content {
+                fs::write(env.uploads_dir().join(name), content).unwrap();
+            }
+            let mut entry = UploadMetadata::new(name.into(), "alice".into(), 1);
+            entry.content_type = content_type.map(str::to_string);
+            entries.push(entry);
+        }
+        env.seed(&entries);
+        let app = init_service(app()).await;
+
+        let resp = call_service(&app, admin_post("/api/admin/backfill/content-types")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "files_checked": 4,
+                "content_types_added": 2,
+                "undetected": 1,
+                "missing": 1,
+            })
+        );
+
+        let types: Vec<(String, Option<String>)> = env
+            .entries()
+            .into_iter()
+            .map(|entry| (entry.filename, entry.content_type))
+            .collect();
+        let typed = |name: &str, content_type: Option<&str>| {
+            (name.to_string(), content_type.map(str::to_string))
+        };
+        assert_eq!(
+            types,
+            [
+                typed("image.bin", Some("image/png")),
+                typed("notes", Some("text/plain")),
+                typed("typed.bin", Some("application/x-custom")),
+                typed("noise.bin", None),
+                typed("gone.png", None),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn type_stats_group_current_files() {
+        let env = TestEnv::new();
+        let file = |name: &str, size: u64, content_type: Option<&str>| {
+            let mut entry = UploadMetadata::new(name.into(), "alice".into(), size);
+            entry.content_type = content_type.map(str::to_string);
+            entry
+        };
+        env.seed(&[
+            file("a.PNG", 999, Some("image/png")),
+            file("a.PNG", 10, Some("image/png")),
+            file("b.jpg", 20, Some("image/jpeg")),
+            file("clip.mp4", 300, Some("video/mp4")),
+            file("report.pdf", 40, Some("application/pdf")),
+            file(
+                "sheet.xlsx",
+                50,
+                Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+            ),
+            file("notes.txt", 6, Some("text/plain; charset=utf-8")),
+            file("bundle.zip", 700, Some("application/zip")),
+            file("Makefile", 8, None),
+        ]);
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/stats/types")
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let totals =
+            |files: usize, bytes: u64| serde_json::json!({ "files": files, "bytes": bytes });
+        assert_eq!(
+            body["by_extension"],
+            serde_json::json!({
+                "(none)": totals(1, 8),
+                "jpg": totals(1, 20),
+                "mp4": totals(1, 300),
+                "pdf": totals(1, 40),
+                "png": totals(1, 10),
+                "txt": totals(1, 6),
+                "xlsx": totals(1, 50),
+                "zip": totals(1, 700),
+            })
+        );
+        assert_eq!(
+            body["by_family"],
+            serde_json::json!({
+                "document": totals(3, 96),
+                "image": totals(2, 30),
+                "other": totals(2, 708),
+                "video": totals(1, 300),
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn orphaned_users_sums_storage_per_subject() {
+        let mut env = TestEnv::new();
+        env.remove("ORPHANED_USERS_KEYCLOAK_CHECK");
+        env.seed(&[
+            UploadMetadata::new("a.txt".into(), "alice".into(), 100),
+            UploadMetadata::new("b.txt".into(), "alice".into(), 200),
+            UploadMetadata::new("c.txt".into(), "bob".into(), 700),
+            UploadMetadata::new("d.txt".into(), ANONYMOUS_USER.into(), 50),
+        ]);
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/orphaned-users")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let req = TestRequest::get()
+            .uri("/api/admin/orphaned-users")
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["keycloak_checked"], false);
+        assert_eq!(body["reclaimable_bytes"], 0);
+        assert_eq!(
+            body["users"],
+            serde_json::json!([
+                { "user": "alice", "files": 2, "bytes": 300 },
+                { "user": "bob", "files": 1, "bytes": 700 },
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn orphaned_users_flags_subjects_keycloak_no_longer_has() {
+        let keycloak = MockKeycloak::start().await;
+        keycloak.add_user("alice", true);
+        keycloak.add_user("carol", false);
+        let mut env = TestEnv::new().with("ORPHANED_USERS_KEYCLOAK_CHECK", "true");
+        keycloak.configure(&mut env);
+        env.seed(&[
+            UploadMetadata::new("a.txt".into(), "alice".into(), 100),
+            UploadMetadata::new("b.txt".into(), "bob".into(), 700),
+            UploadMetadata::new("c.txt".into(), "carol".into(), 40),
+        ]);
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/orphaned-users")
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["keycloak_checked"], true);
+        assert_eq!(body["reclaimable_bytes"], 740);
+        let statuses: Vec<(&str, &str)> = body["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| {
+                (
+                    user["user"].as_str().unwrap(),
+                    user["status"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("alice", "active"),
+                ("bob", "missing"),
+                ("carol", "disabled")
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn orphaned_users_reports_an_unreachable_keycloak() {
+        let _env = TestEnv::new()
+            .with("ORPHANED_USERS_KEYCLOAK_CHECK", "true")
+            .with("KEYCLOAK_URL", "http://127.0.0.1:1")
+            .with("CLIENT_ID", "upload-client")
+            .with("CLIENT_SECRET", "secret");
+        let app = init_service(app()).await;
+
+        let req = TestRequest::get()
+            .uri("/api/admin/orphaned-users")
+            .insert_header((TEST_USER_HEADER, "root"))
+            .insert_header((TEST_ROLES_HEADER, "admin"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+}
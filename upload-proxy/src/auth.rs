@@ -1,11 +1,19 @@
-use actix_web;
 use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
 use actix_web_httpauth::extractors::AuthenticationError;
-use actix_web::dev::ServiceRequest;
+use actix_web_httpauth::headers::www_authenticate::bearer::Error as BearerError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, ResponseError};
 use jsonwebtoken::{decode, DecodingKey, Validation, errors::ErrorKind};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::future::{ready, Ready};
+
+use crate::jwks::JwksCache;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -14,12 +22,171 @@ pub enum Audience {
     Multiple(Vec<String>),
 }
 
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RoleClaim {
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Claims {
     pub sub: Option<String>, // Now optional to avoid hard failure
     pub exp: usize,
     #[serde(default)]
+    pub iss: Option<String>,
+    #[serde(default)]
     pub aud: Option<Audience>,
+    #[serde(default)]
+    pub iat: Option<usize>,
+    /// Client the token was issued to (authorized party)
+    #[serde(default)]
+    pub azp: Option<String>,
+    #[serde(default)]
+    pub realm_access: Option<RoleClaim>,
+    #[serde(default)]
+    pub resource_access: Option<HashMap<String, RoleClaim>>,
+}
+
+/// Owner recorded for uploads made while `REQUIRE_AUTH_UPLOAD` is off
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+/// Identity of the caller, attached to the request by the auth middleware
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub sub: String,
+    /// Realm roles plus roles granted on any client
+    pub roles: Vec<String>,
+}
+
+impl AuthenticatedUser {
+    fn from_claims(claims: Claims) -> Self {
+        let mut roles: Vec<String> = claims.realm_access.unwrap_or_default().roles;
+        for client_roles in claims.resource_access.unwrap_or_default().into_values() {
+            roles.extend(client_roles.roles);
+        }
+        Self {
+            sub: claims.sub.unwrap_or_else(|| "unknown".to_string()),
+            roles,
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// Role required by admin endpoints, from `ADMIN_ROLE` (default `admin`)
+pub fn admin_role() -> String {
+    env::var("ADMIN_ROLE").unwrap_or_else(|_| "admin".to_string())
+}
+
+/// 403 for a caller whose token is valid but doesn't grant `role`. Failing to
+/// authenticate is a 401; lacking a permission never is.
+pub fn require_role(user: &AuthenticatedUser, role: &str) -> Result<(), actix_web::Error> {
+    if !user.has_role(role) {
+        log::warn!("User {} lacks required role {}", user.sub, role);
+        return Err(actix_web::error::ErrorForbidden(format!(
+            "Missing required role '{}'",
+            role
+        )));
+    }
+    Ok(())
+}
+
+/// A correctly signed token whose `iss` is not the configured realm
+#[derive(Debug)]
+pub struct IssuerMismatch {
+    pub found: Option<String>,
+    pub expected: String,
+}
+
+impl IssuerMismatch {
+    /// Detailed description for `VERBOSE_AUTH_ERRORS`; the token's issuer is reduced to
+    /// a short run of safe characters before being echoed back
+    fn description(&self) -> String {
+        let found = match &self.found {
+            Some(iss) => {
+                let safe: String = iss
+                    .chars()
+                    .filter(|c| c.is_ascii_graphic() && *c != '"' && *c != '\\')
+                    .take(128)
+                    .collect();
+                format!("'{}'", safe)
+            }
+            None => "missing".to_string(),
+        };
+        format!(
+            "Token issuer {} does not match expected issuer '{}'",
+            found, self.expected
+        )
+    }
+}
+
+impl fmt::Display for IssuerMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token issuer does not match the configured issuer")
+    }
+}
+
+impl ResponseError for IssuerMismatch {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+/// Whether auth failures carry a detailed `error_description`, from `VERBOSE_AUTH_ERRORS`
+fn verbose_auth_errors() -> bool {
+    env::var("VERBOSE_AUTH_ERRORS")
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Not authenticated")),
+        )
+    }
+}
+
+/// Authorization schemes accepted in place of `Bearer`, from `ACCEPT_TOKEN_SCHEMES`
+/// (e.g. `Bearer,Token`). Unset keeps the strict, case-sensitive `Bearer` only.
+fn accepted_token_schemes() -> Vec<String> {
+    env::var("ACCEPT_TOKEN_SCHEMES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|scheme| scheme.trim().to_lowercase())
+        .filter(|scheme| !scheme.is_empty())
+        .collect()
+}
+
+/// Rewrites `Authorization: <scheme> <token>` to `Bearer <token>` when the scheme is
+/// one of `ACCEPT_TOKEN_SCHEMES`, compared case-insensitively, so the bearer
+/// middleware can validate it. Other schemes pass through and are rejected there.
+pub async fn normalize_token_scheme(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let normalized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| *scheme != "Bearer")
+        .filter(|(scheme, _)| accepted_token_schemes().contains(&scheme.to_lowercase()))
+        .and_then(|(_, token)| {
+            header::HeaderValue::from_str(&format!("Bearer {}", token.trim())).ok()
+        });
+    if let Some(value) = normalized {
+        req.headers_mut().insert(header::AUTHORIZATION, value);
+    }
+    next.call(req).await
 }
 
 pub async fn validator(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
@@ -27,20 +194,92 @@ pub async fn validator(req: ServiceRequest, credentials: BearerAuth) -> Result<S
     log::info!("=== AUTHENTICATION MIDDLEWARE ===");
     log::info!("Validating token in middleware");
 
-    match validate_token(token).await {
+    let Some(jwks) = req.app_data::<web::Data<JwksCache>>().cloned() else {
+        log::error!("No JWKS cache registered");
+        let error = actix_web::error::ErrorInternalServerError("JWKS cache unavailable");
+        return Err((error, req));
+    };
+    match validate_token(token, &jwks).await {
         Ok(user) => {
-            log::info!("Authentication successful for user: {}", user);
+            log::info!("Authentication successful for user: {}", user.sub);
+            req.extensions_mut().insert(user);
             Ok(req)
         }
         Err(e) => {
             log::error!("Authentication failed: {:?}", e);
             let config = req.app_data::<Config>().cloned().unwrap_or_default();
-            Err((AuthenticationError::from(config).into(), req))
+            let mut error = AuthenticationError::from(config);
+            // A valid token that isn't allowed here gets 403 with the reason
+            if e.as_response_error().status_code() == StatusCode::FORBIDDEN {
+                error = error
+                    .with_error(BearerError::InsufficientScope)
+                    .with_error_description(e.to_string());
+            }
+            if let Some(mismatch) = e.as_error::<IssuerMismatch>() {
+                if verbose_auth_errors() {
+                    error = error
+                        .with_error(BearerError::InvalidToken)
+                        .with_error_description(mismatch.description());
+                }
+            }
+            Err((error.into(), req))
+        }
+    }
+}
+
+/// Rejects tokens issued more than `MAX_TOKEN_AGE_SECS` ago, regardless of `exp`
+fn check_token_age(claims: &Claims) -> Result<(), actix_web::Error> {
+    let max_age = match env::var("MAX_TOKEN_AGE_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        Some(max_age) if max_age > 0 => max_age,
+        _ => return Ok(()),
+    };
+
+    let iat = claims.iat.ok_or_else(|| {
+        log::warn!("Token has no iat claim but MAX_TOKEN_AGE_SECS is set");
+        actix_web::error::ErrorUnauthorized("Token missing issued-at claim")
+    })?;
+    let age = jsonwebtoken::get_current_timestamp().saturating_sub(iat as u64);
+    if age > max_age {
+        log::warn!("Token issued {}s ago exceeds max age {}s", age, max_age);
+        return Err(actix_web::error::ErrorUnauthorized(
+            "Token too old, please log in again",
+        ));
+    }
+    Ok(())
+}
+
+/// Rejects tokens whose `azp` is not one of `EXPECTED_AZP` (comma-separated), when set
+fn check_authorized_party(claims: &Claims) -> Result<(), actix_web::Error> {
+    let Ok(expected) = env::var("EXPECTED_AZP") else {
+        return Ok(());
+    };
+    let allowed: Vec<&str> = expected
+        .split(',')
+        .map(|azp| azp.trim())
+        .filter(|azp| !azp.is_empty())
+        .collect();
+    if allowed.is_empty() {
+        return Ok(());
+    }
+
+    match claims.azp.as_deref() {
+        Some(azp) if allowed.contains(&azp) => Ok(()),
+        found => {
+            log::warn!("Token azp {:?} is not one of {:?}", found, allowed);
+            Err(actix_web::error::ErrorForbidden(
+                "Token was not issued for this client",
+            ))
         }
     }
 }
 
-pub async fn validate_token(token: &str) -> Result<String, actix_web::Error> {
+pub async fn validate_token(
+    token: &str,
+    jwks_cache: &JwksCache,
+) -> Result<AuthenticatedUser, actix_web::Error> {
     log::info!("=== JWT VALIDATION START ===");
     log::info!("Token length: {}", token.len());
     log::info!("Token preview: {}...", &token[..token.len().min(50)]);
@@ -56,25 +295,20 @@ pub async fn validate_token(token: &str) -> Result<String, actix_web::Error> {
         "{}/realms/{}/protocol/openid-connect/certs",
         keycloak_url, keycloak_realm
     );
-    log::info!("Fetching JWKS from: {}", jwks_url);
-
-    let client = reqwest::Client::new();
-    let jwks: Value = client
-        .get(&jwks_url)
-        .send()
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to fetch JWKS: {}", e)))?
-        .json()
-        .await
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Failed to parse JWKS: {}", e)))?;
 
     let token_header = jsonwebtoken::decode_header(token)
         .map_err(|e| actix_web::error::ErrorUnauthorized(format!("Invalid token header: {}", e)))?;
 
     let kid = token_header.kid.ok_or_else(|| actix_web::error::ErrorUnauthorized("Token missing key ID"))?;
 
-    let keys_array = jwks["keys"].as_array().ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid JWKS format"))?;
-    let matching_key = keys_array.iter().find(|key| key["kid"].as_str() == Some(&kid))
+    let mut jwks = jwks_cache.get(&jwks_url, false).await?;
+    if jwks.find(&kid).is_none() {
+        // The signing key may have been rotated since the cache was filled
+        log::info!("Key {} not in cached JWKS, refreshing", kid);
+        jwks = jwks_cache.get(&jwks_url, true).await?;
+    }
+    let matching_key = jwks
+        .find(&kid)
         .ok_or_else(|| actix_web::error::ErrorUnauthorized("No matching key found"))?;
 
     let jwk_n = matching_key["n"].as_str().ok_or_else(|| actix_web::error::ErrorUnauthorized("Invalid JWK"))?;
@@ -86,14 +320,16 @@ pub async fn validate_token(token: &str) -> Result<String, actix_web::Error> {
     let mut validation = Validation::new(jsonwebtoken::Algorithm::RS256);
     let audiences: Vec<&str> = jwt_audience.split(',').map(|s| s.trim()).collect();
     validation.set_audience(&audiences);
-    validation.set_issuer(&[&format!("{}/realms/{}", keycloak_url, keycloak_realm)]);
+    let expected_issuer = format!("{}/realms/{}", keycloak_url, keycloak_realm);
+    validation.set_issuer(&[&expected_issuer]);
     validation.leeway = 60;
 
     match decode::<Claims>(token, &decoding_key, &validation) {
         Ok(token_data) => {
+            check_token_age(&token_data.claims)?;
+            check_authorized_party(&token_data.claims)?;
             log::info!("Token validated successfully!");
-            let sub = token_data.claims.sub.unwrap_or_else(|| "unknown".to_string());
-            Ok(sub)
+            Ok(AuthenticatedUser::from_claims(token_data.claims))
         }
         Err(err) => {
             match err.kind() {
@@ -101,6 +337,25 @@ pub async fn validate_token(token: &str) -> Result<String, actix_web::Error> {
                     log::warn!("Token expired — session timeout.");
                     Err(actix_web::error::ErrorUnauthorized("Session expired, please log in again"))
                 }
+                ErrorKind::InvalidIssuer => {
+                    // The signature already checked out, so decode again without the
+                    // issuer check to report what the token claims
+                    let mut unchecked = validation.clone();
+                    unchecked.iss = None;
+                    let found = decode::<Claims>(token, &decoding_key, &unchecked)
+                        .ok()
+                        .and_then(|data| data.claims.iss);
+                    log::warn!(
+                        "Token issuer {:?} does not match {}",
+                        found,
+                        expected_issuer
+                    );
+                    Err(IssuerMismatch {
+                        found,
+                        expected: expected_issuer,
+                    }
+                    .into())
+                }
                 _ => {
                     log::error!("JWT validation failed: {}", err);
                     Err(actix_web::error::ErrorUnauthorized(format!("Invalid token: {}", err)))
@@ -109,3 +364,232 @@ pub async fn validate_token(token: &str) -> Result<String, actix_web::Error> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockKeycloak, TestEnv};
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+
+    /// A test environment whose tokens are validated against `keycloak`
+    fn realm_env(keycloak: &MockKeycloak) -> TestEnv {
+        let mut env = TestEnv::new();
+        keycloak.configure(&mut env);
+        env
+    }
+
+    fn status(error: &actix_web::Error) -> StatusCode {
+        error.as_response_error().status_code()
+    }
+
+    #[actix_web::test]
+    async fn valid_token_yields_its_subject() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak);
+        let user = validate_token(&keycloak.token("alice"), &JwksCache::new())
+            .await
+            .unwrap();
+        assert_eq!(user.sub, "alice");
+    }
+
+    #[actix_web::test]
+    async fn token_older_than_max_age_is_rejected() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak).with("MAX_TOKEN_AGE_SECS", "60");
+        let cache = JwksCache::new();
+
+        let mut claims = keycloak.claims("alice");
+        claims["iat"] = (jsonwebtoken::get_current_timestamp() - 3600).into();
+        let error = validate_token(&keycloak.sign("test-key-1", &claims), &cache)
+            .await
+            .unwrap_err();
+        assert_eq!(status(&error), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.to_string(), "Token too old, please log in again");
+
+        assert!(validate_token(&keycloak.token("alice"), &cache)
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn token_without_iat_is_rejected_when_max_age_is_set() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak).with("MAX_TOKEN_AGE_SECS", "60");
+
+        let mut claims = keycloak.claims("alice");
+        claims.as_object_mut().unwrap().remove("iat");
+        let error = validate_token(&keycloak.sign("test-key-1", &claims), &JwksCache::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Token missing issued-at claim");
+    }
+
+    /// Status and WWW-Authenticate of a request with `authorization` through the
+    /// middleware `main` uses
+    async fn authorize(authorization: &str) -> (StatusCode, Option<String>) {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(JwksCache::new()))
+                .wrap(HttpAuthentication::bearer(validator))
+                .wrap(actix_web::middleware::from_fn(normalize_token_scheme))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .insert_header((header::AUTHORIZATION, authorization))
+            .to_request();
+        let response = call_service(&app, req).await;
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .map(|value| value.to_str().unwrap().to_string());
+        (response.status(), challenge)
+    }
+
+    /// WWW-Authenticate of a request carrying `token` through the bearer middleware
+    async fn challenge(token: &str) -> String {
+        let (status, challenge) = authorize(&format!("Bearer {}", token)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        challenge.unwrap()
+    }
+
+    /// A correctly signed token claiming the issuer `iss`
+    fn token_from(keycloak: &MockKeycloak, iss: &str) -> String {
+        let mut claims = keycloak.claims("alice");
+        claims["iss"] = iss.into();
+        keycloak.sign("test-key-1", &claims)
+    }
+
+    #[actix_web::test]
+    async fn issuer_mismatch_is_described_when_verbose() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak).with("VERBOSE_AUTH_ERRORS", "true");
+
+        let challenge = challenge(&token_from(&keycloak, "https://other.example/\"realm\"")).await;
+        assert!(
+            challenge.contains("error=\"invalid_token\""),
+            "{}",
+            challenge
+        );
+        let expected = format!(
+            "error_description=\"Token issuer 'https://other.example/realm' does not match \
+             expected issuer '{}'\"",
+            keycloak.issuer()
+        );
+        assert!(challenge.contains(&expected), "{}", challenge);
+    }
+
+    #[actix_web::test]
+    async fn issuer_mismatch_is_generic_by_default() {
+        let keycloak = MockKeycloak::start().await;
+        let mut env = realm_env(&keycloak);
+        env.remove("VERBOSE_AUTH_ERRORS");
+
+        let error = validate_token(
+            &token_from(&keycloak, "https://other.example"),
+            &JwksCache::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Token issuer does not match the configured issuer"
+        );
+        let challenge = challenge(&token_from(&keycloak, "https://other.example")).await;
+        assert!(!challenge.contains("error_description"), "{}", challenge);
+        assert!(!challenge.contains("other.example"), "{}", challenge);
+    }
+
+    #[actix_web::test]
+    async fn only_bearer_is_accepted_by_default() {
+        let keycloak = MockKeycloak::start().await;
+        let mut env = realm_env(&keycloak);
+        env.remove("ACCEPT_TOKEN_SCHEMES");
+        let token = keycloak.token("alice");
+
+        let (status, _) = authorize(&format!("Bearer {}", token)).await;
+        assert_eq!(status, StatusCode::OK);
+        for scheme in ["bearer", "Token"] {
+            let (status, _) = authorize(&format!("{} {}", scheme, token)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", scheme);
+        }
+    }
+
+    #[actix_web::test]
+    async fn configured_schemes_are_accepted_case_insensitively() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak).with("ACCEPT_TOKEN_SCHEMES", "Bearer, token");
+        let token = keycloak.token("alice");
+
+        for scheme in ["Bearer", "bearer", "Token", "TOKEN"] {
+            let (status, _) = authorize(&format!("{} {}", scheme, token)).await;
+            assert_eq!(status, StatusCode::OK, "{}", scheme);
+        }
+        let (status, _) = authorize(&format!("JWT {}", token)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn authorized_party_must_be_expected_when_configured() {
+        let keycloak = MockKeycloak::start().await;
+        let mut env = realm_env(&keycloak).with("EXPECTED_AZP", "web-app, upload-client");
+        let cache = JwksCache::new();
+        assert!(validate_token(&keycloak.token("alice"), &cache)
+            .await
+            .is_ok());
+
+        let mut claims = keycloak.claims("alice");
+        claims.as_object_mut().unwrap().remove("azp");
+        let without_azp = keycloak.sign("test-key-1", &claims);
+        let error = validate_token(&without_azp, &cache).await.unwrap_err();
+        assert_eq!(status(&error), StatusCode::FORBIDDEN);
+
+        env.set("EXPECTED_AZP", "web-app");
+        let error = validate_token(&keycloak.token("alice"), &cache)
+            .await
+            .unwrap_err();
+        assert_eq!(status(&error), StatusCode::FORBIDDEN);
+        assert_eq!(error.to_string(), "Token was not issued for this client");
+
+        env.remove("EXPECTED_AZP");
+        assert!(validate_token(&without_azp, &cache).await.is_ok());
+    }
+
+    #[test]
+    fn missing_roles_are_forbidden() {
+        let user = AuthenticatedUser {
+            sub: "alice".to_string(),
+            roles: vec!["user".to_string()],
+        };
+        assert!(require_role(&user, "user").is_ok());
+        let error = require_role(&user, "admin").unwrap_err();
+        assert_eq!(status(&error), StatusCode::FORBIDDEN);
+        assert_eq!(error.to_string(), "Missing required role 'admin'");
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_tokens_get_403_and_invalid_ones_401() {
+        let keycloak = MockKeycloak::start().await;
+        let _env = realm_env(&keycloak).with("EXPECTED_AZP", "web-app");
+
+        let (status, challenge) = authorize(&format!("Bearer {}", keycloak.token("alice"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let challenge = challenge.unwrap();
+        assert!(
+            challenge.contains("error=\"insufficient_scope\""),
+            "{}",
+            challenge
+        );
+        assert!(
+            challenge.contains("Token was not issued for this client"),
+            "{}",
+            challenge
+        );
+
+        let (status, _) = authorize("Bearer not.a.token").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
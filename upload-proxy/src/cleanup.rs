@@ -0,0 +1,116 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::handlers::uploads_dir;
+use crate::images::cache_dir;
+use crate::namespace::namespaces_dir;
+
+/// Suffix of files still being written: upload placeholders and transcodes in progress
+const TEMP_SUFFIX: &str = ".partial";
+
+/// Age after which a leftover temporary file is treated as orphaned, from
+/// `TEMP_CLEANUP_AGE_SECS` (default 1 hour); `0` disables the startup sweep
+pub fn temp_cleanup_age() -> Option<Duration> {
+    let secs = env::var("TEMP_CLEANUP_AGE_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(3600);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Removes `*.partial` files under `dir` last modified more than `max_age` ago, so those
+/// of uploads still in flight on another instance are left alone
+fn remove_stale(dir: &Path, max_age: Duration, now: SystemTime) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            removed += remove_stale(&path, max_age, now);
+            continue;
+        }
+        if !entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+            continue;
+        }
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => log::warn!("Failed to remove orphaned {}: {}", path.display(), e),
+        }
+    }
+    removed
+}
+
+/// Sweeps temporary files left behind by a crash or restart from the uploads, namespace
+/// and transcode cache directories; returns how many were removed
+pub fn cleanup_temp_files(max_age: Duration) -> usize {
+    let now = SystemTime::now();
+    [uploads_dir(), namespaces_dir(), cache_dir()]
+        .iter()
+        .map(|dir| remove_stale(dir, max_age, now))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    /// Creates `path` with its modification time `age` in the past
+    fn write_aged(path: &Path, age: Duration) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let file = fs::File::create(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn startup_sweep_removes_only_old_temp_files() {
+        let mut env = TestEnv::new();
+        let namespaces = env.path().join("namespaces");
+        let cache = env.path().join("cache");
+        env.set("NAMESPACES_DIR", &namespaces.display().to_string());
+        env.set("TRANSCODE_CACHE_DIR", &cache.display().to_string());
+        let hour = Duration::from_secs(3600);
+
+        let old = [
+            env.uploads_dir().join("a.txt.partial"),
+            namespaces.join("team").join("b.txt.partial"),
+            cache.join("c.webp.partial"),
+        ];
+        for path in &old {
+            write_aged(path, 2 * hour);
+        }
+        let recent = env.uploads_dir().join("d.txt.partial");
+        write_aged(&recent, Duration::from_secs(60));
+        let finished = env.uploads_dir().join("e.txt");
+        write_aged(&finished, 2 * hour);
+
+        assert_eq!(cleanup_temp_files(hour), 3);
+        assert!(old.iter().all(|path| !path.exists()));
+        assert!(recent.exists());
+        assert!(finished.exists());
+    }
+
+    #[test]
+    fn cleanup_age_defaults_to_an_hour() {
+        let mut env = TestEnv::new();
+        env.remove("TEMP_CLEANUP_AGE_SECS");
+        assert_eq!(temp_cleanup_age(), Some(Duration::from_secs(3600)));
+        env.set("TEMP_CLEANUP_AGE_SECS", "0");
+        assert_eq!(temp_cleanup_age(), None);
+    }
+}
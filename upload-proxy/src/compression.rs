@@ -0,0 +1,39 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, ContentEncoding};
+use actix_web::middleware::Next;
+use std::env;
+
+/// Whether responses are compressed for clients that accept it, from `COMPRESS_RESPONSES`
+pub fn compress_responses() -> bool {
+    env::var("COMPRESS_RESPONSES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Smallest body worth compressing, from `COMPRESSION_MIN_BYTES`
+pub fn compression_min_bytes() -> u64 {
+    env::var("COMPRESSION_MIN_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1024)
+}
+
+/// Marks responses with a known size below `COMPRESSION_MIN_BYTES` as
+/// `Content-Encoding: identity`, which the outer `Compress` middleware leaves alone.
+/// Streamed bodies of unknown size are still compressed.
+pub async fn skip_small_responses(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+    if let BodySize::Sized(size) = res.response().body().size() {
+        if size < compression_min_bytes() && !res.headers().contains_key(header::CONTENT_ENCODING) {
+            res.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                ContentEncoding::Identity.to_header_value(),
+            );
+        }
+    }
+    Ok(res)
+}
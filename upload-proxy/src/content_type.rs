@@ -0,0 +1,347 @@
+use std::env;
+use std::path::Path;
+
+/// Leading bytes of an upload inspected to detect its type, from `MIME_SNIFF_BYTES`.
+///
+/// Some formats (e.g. tar, whose magic sits at offset 257) need a longer prefix.
+pub fn sniff_bytes() -> usize {
+    env::var("MIME_SNIFF_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+        .unwrap_or(8192)
+}
+
+/// Extensions accepted for a sniffed type, keyed by its canonical extension.
+/// Types not listed only accept their canonical extension.
+const EXTENSION_ALIASES: &[(&str, &[&str])] = &[
+    ("jpg", &["jpg", "jpeg", "jpe", "jfif"]),
+    ("tif", &["tif", "tiff"]),
+    ("mp4", &["mp4", "m4v"]),
+    ("mp3", &["mp3", "mpga"]),
+    ("mkv", &["mkv", "mka"]),
+    ("gz", &["gz", "tgz"]),
+    ("html", &["html", "htm"]),
+    ("xml", &["xml", "svg"]),
+    // Office documents, JARs and APKs are ZIP containers
+    (
+        "zip",
+        &[
+            "zip", "jar", "apk", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+        ],
+    ),
+    ("doc", &["doc", "xls", "ppt", "msg", "msi"]),
+];
+
+/// Whether uploads must carry an extension matching their sniffed type,
+/// from `ENFORCE_TYPE_EXTENSION_MATCH`
+pub fn enforce_extension_match() -> bool {
+    env::var("ENFORCE_TYPE_EXTENSION_MATCH")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Checks that `filename`'s extension fits the type detected from `head`.
+///
+/// Content of no recognizable type and names without an extension are accepted.
+pub fn check_extension_match(filename: &str, head: &[u8]) -> Result<(), String> {
+    let Some(kind) = infer::get(head) else {
+        return Ok(());
+    };
+    let Some(extension) = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+    else {
+        return Ok(());
+    };
+
+    let canonical = kind.extension();
+    if extension_accepted(canonical, &extension) {
+        Ok(())
+    } else {
+        Err(format!(
+            "File content is {} ({}) but the filename has extension .{}",
+            kind.mime_type(),
+            canonical,
+            extension
+        ))
+    }
+}
+
+/// Whether `extension` (lowercase) is acceptable for content whose canonical extension
+/// is `canonical`
+fn extension_accepted(canonical: &str, extension: &str) -> bool {
+    EXTENSION_ALIASES
+        .iter()
+        .find(|(name, _)| *name == canonical)
+        .map(|(_, aliases)| aliases.contains(&extension))
+        .unwrap_or(extension == canonical)
+}
+
+/// Whether every file part must declare a `Content-Type`, from `REQUIRE_CONTENT_TYPE`
+pub fn require_declared() -> bool {
+    env::var("REQUIRE_CONTENT_TYPE")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Whether stored names get the extension of their detected type, from `CORRECT_EXTENSION`
+pub fn correct_extension() -> bool {
+    env::var("CORRECT_EXTENSION")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// `filename` with its extension replaced (or, if it has none, extended) by the
+/// canonical one for the type detected from `head`; `None` when it already fits or the
+/// type is unknown
+pub fn corrected_filename(filename: &str, head: &[u8]) -> Option<String> {
+    let kind = infer::get(head)?;
+    let canonical = kind.extension();
+    let stem = match filename.rfind('.') {
+        Some(dot) if dot > 0 => {
+            if extension_accepted(canonical, &filename[dot + 1..].to_lowercase()) {
+                return None;
+            }
+            &filename[..dot]
+        }
+        _ => filename,
+    };
+    Some(format!("{}.{}", stem, canonical))
+}
+
+/// Whether uploads that are also valid as another, active type are rejected, from
+/// `REJECT_POLYGLOTS`
+pub fn reject_polyglots() -> bool {
+    env::var("REJECT_POLYGLOTS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Script or markup a browser may run if an image is ever served as HTML or script
+const ACTIVE_CONTENT_MARKERS: &[&str] = &[
+    "<script",
+    "<html",
+    "<iframe",
+    "<svg",
+    "<?php",
+    "javascript:",
+];
+
+/// Why `head` looks like an image that doubles as another type, e.g. a GIF whose header
+/// is also the start of a JavaScript comment; `None` for anything else.
+///
+/// Only images are checked, since documents and archives legitimately embed script or
+/// other containers.
+pub fn polyglot_signature(head: &[u8]) -> Option<String> {
+    let kind = infer::get(head)?;
+    if kind.matcher_type() != infer::MatcherType::Image {
+        return None;
+    }
+    // `GIF89a/*` parses as an identifier followed by a comment, so the rest of the file
+    // can be JavaScript
+    if head.starts_with(b"GIF8") && head.get(6..8) == Some(b"/*") {
+        return Some("GIF header doubles as JavaScript".to_string());
+    }
+    let lower = head.to_ascii_lowercase();
+    if let Some(marker) = ACTIVE_CONTENT_MARKERS
+        .iter()
+        .find(|marker| contains(&lower, marker.as_bytes()))
+    {
+        return Some(format!("{} image contains {}", kind.extension(), marker));
+    }
+    // A ZIP local file header after the image data makes it a valid JAR or ZIP too
+    if contains(&head[1..], b"PK\x03\x04") {
+        return Some(format!("{} image embeds a ZIP archive", kind.extension()));
+    }
+    None
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// MIME type of an upload: detected from its leading bytes, else the declared part type
+pub fn detect(head: &[u8], declared: Option<&str>) -> Option<String> {
+    infer::get(head)
+        .map(|kind| kind.mime_type().to_string())
+        .or_else(|| declared.map(|mime| mime.to_lowercase()))
+}
+
+/// Matches a content type against a filter like `image/png`, `image/*` or `*`,
+/// case-insensitively and ignoring parameters such as `; charset=utf-8`
+pub fn matches_filter(content_type: &str, filter: &str) -> bool {
+    let essence = |mime: &str| {
+        mime.split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    };
+    let (content_type, filter) = (essence(content_type), essence(filter));
+    match filter.strip_suffix("/*") {
+        _ if filter == "*" || filter == "*/*" => true,
+        Some(top_level) => content_type
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind == top_level),
+        None => content_type == filter,
+    }
+}
+
+/// Whether downloads re-detect types that were never recorded properly,
+/// from `CORRECT_CONTENT_TYPE_ON_DOWNLOAD`
+pub fn correct_on_download() -> bool {
+    env::var("CORRECT_CONTENT_TYPE_ON_DOWNLOAD")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Whether a stored type is missing or only the generic `application/octet-stream`
+pub fn needs_correction(stored: Option<&str>) -> bool {
+    stored.is_none_or(|mime| matches_filter(mime, "application/octet-stream"))
+}
+
+/// Whether a stored file is text: its recorded type is `text/*`, or, when no specific
+/// type was recorded, its leading bytes are a recognizable type-free run of UTF-8
+pub fn is_text(stored: Option<&str>, head: &[u8]) -> bool {
+    if let Some(mime) = stored.filter(|mime| !needs_correction(Some(mime))) {
+        return matches_filter(mime, "text/*");
+    }
+    if infer::get(head).is_some() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // The sniffed prefix may end partway through a character
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Whether a download of `content_type` is served inline, per the comma-separated
+/// filters in `INLINE_CONTENT_TYPES` (e.g. `image/*,application/pdf`); everything else
+/// is an attachment. `None` when unset, leaving the default disposition.
+pub fn serves_inline(content_type: &str) -> Option<bool> {
+    let filters = env::var("INLINE_CONTENT_TYPES").ok()?;
+    Some(
+        filters
+            .split(',')
+            .map(|filter| filter.trim())
+            .filter(|filter| !filter.is_empty())
+            .any(|filter| matches_filter(content_type, filter)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const PDF: &[u8] = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n";
+
+    #[test]
+    fn extension_must_fit_the_sniffed_type() {
+        assert!(check_extension_match("photo.png", PNG).is_ok());
+        assert!(check_extension_match("PHOTO.PNG", PNG).is_ok());
+        assert_eq!(
+            check_extension_match("invoice.png", PDF).unwrap_err(),
+            "File content is application/pdf (pdf) but the filename has extension .png"
+        );
+    }
+
+    #[test]
+    fn aliases_unknown_content_and_bare_names_are_accepted() {
+        let jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF\0";
+        assert!(check_extension_match("photo.jpeg", jpeg).is_ok());
+        assert!(check_extension_match("photo.jfif", jpeg).is_ok());
+        assert!(check_extension_match("notes.png", b"just some text").is_ok());
+        assert!(check_extension_match("invoice", PDF).is_ok());
+    }
+
+    #[test]
+    fn sniff_bytes_defaults_to_8k() {
+        let mut env = TestEnv::new();
+        env.remove("MIME_SNIFF_BYTES");
+        assert_eq!(sniff_bytes(), 8192);
+        env.set("MIME_SNIFF_BYTES", "0");
+        assert_eq!(sniff_bytes(), 8192);
+        env.set("MIME_SNIFF_BYTES", "300");
+        assert_eq!(sniff_bytes(), 300);
+    }
+
+    #[test]
+    fn filters_match_exact_types_and_wildcards() {
+        assert!(matches_filter("image/png", "image/*"));
+        assert!(matches_filter("Image/PNG", "image/png"));
+        assert!(matches_filter("text/plain; charset=utf-8", "text/plain"));
+        assert!(matches_filter("video/mp4", "*/*"));
+        assert!(!matches_filter("image/png", "image/jpeg"));
+        assert!(!matches_filter("application/pdf", "image/*"));
+        assert!(!matches_filter("imagery/png", "image/*"));
+    }
+
+    #[test]
+    fn inline_types_come_from_the_environment() {
+        let mut env = TestEnv::new();
+        env.remove("INLINE_CONTENT_TYPES");
+        assert_eq!(serves_inline("application/pdf"), None);
+
+        env.set("INLINE_CONTENT_TYPES", "image/*, application/pdf,");
+        assert_eq!(serves_inline("application/pdf"), Some(true));
+        assert_eq!(serves_inline("image/webp"), Some(true));
+        assert_eq!(serves_inline("application/zip"), Some(false));
+        assert_eq!(serves_inline("text/plain"), Some(false));
+    }
+
+    #[test]
+    fn misnamed_files_get_the_detected_extension() {
+        assert_eq!(
+            corrected_filename("photo.txt", PNG).as_deref(),
+            Some("photo.png")
+        );
+        assert_eq!(
+            corrected_filename("photo", PNG).as_deref(),
+            Some("photo.png")
+        );
+        assert_eq!(
+            corrected_filename(".hidden", PNG).as_deref(),
+            Some(".hidden.png")
+        );
+        assert_eq!(corrected_filename("photo.PNG", PNG), None);
+        assert_eq!(corrected_filename("doc.pdf", PDF), None);
+        assert_eq!(corrected_filename("notes.md", b"just text"), None);
+    }
+
+    /// A GIF whose header opens a comment, so the file also runs as JavaScript
+    const GIF_JS: &[u8] = b"GIF89a/*\x01\x00\x01\x00\x00\x00\x00;*/=1;alert(document.domain);";
+
+    #[test]
+    fn polyglot_images_are_recognised() {
+        assert_eq!(
+            polyglot_signature(GIF_JS).as_deref(),
+            Some("GIF header doubles as JavaScript")
+        );
+        let mut png = PNG.to_vec();
+        png.extend_from_slice(b"<SCRIPT>alert(1)</script>");
+        assert_eq!(
+            polyglot_signature(&png).as_deref(),
+            Some("png image contains <script")
+        );
+        let mut png = PNG.to_vec();
+        png.extend_from_slice(b"PK\x03\x04");
+        assert_eq!(
+            polyglot_signature(&png).as_deref(),
+            Some("png image embeds a ZIP archive")
+        );
+
+        assert!(polyglot_signature(PNG).is_none());
+        assert!(polyglot_signature(b"GIF89a\x01\x00\x01\x00").is_none());
+        // Only images are checked
+        let mut pdf = PDF.to_vec();
+        pdf.extend_from_slice(b"<script>");
+        assert!(polyglot_signature(&pdf).is_none());
+    }
+}
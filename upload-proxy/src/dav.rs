@@ -0,0 +1,239 @@
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::fmt::Write;
+
+use crate::auth::AuthenticatedUser;
+use crate::metadata::{current_files, read_metadata, UploadMetadata};
+use crate::namespace::StorageScope;
+use crate::xml::escape;
+
+/// Methods served under `/dav`; the collection is read-only
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+pub fn propfind_method() -> Method {
+    Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method token")
+}
+
+/// Percent-encodes a filename for use as one path segment of an `href`
+fn encode_segment(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            let _ = write!(out, "%{:02X}", byte);
+        }
+    }
+    out
+}
+
+fn write_collection(out: &mut String, href: &str) {
+    let _ = write!(
+        out,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>files</D:displayname>\
+         <D:resourcetype><D:collection/></D:resourcetype>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(href)
+    );
+}
+
+fn write_file(out: &mut String, href: &str, entry: &UploadMetadata) {
+    let _ = write!(
+        out,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname><D:resourcetype/>\
+         <D:getcontentlength>{}</D:getcontentlength>",
+        escape(href),
+        escape(&entry.filename),
+        entry.size_bytes
+    );
+    if let Some(content_type) = &entry.content_type {
+        let _ = write!(
+            out,
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape(content_type)
+        );
+    }
+    if let Some(checksum) = &entry.checksum {
+        let _ = write!(out, "<D:getetag>\"{}\"</D:getetag>", checksum);
+    }
+    if let Ok(stored_at) = DateTime::parse_from_rfc3339(&entry.timestamp) {
+        let _ = write!(
+            out,
+            "<D:creationdate>{}</D:creationdate><D:getlastmodified>{}</D:getlastmodified>",
+            stored_at.to_rfc3339(),
+            stored_at
+                .with_timezone(&Utc)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+        );
+    }
+    let _ = write!(
+        out,
+        "</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+    );
+}
+
+fn multistatus(responses: String) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>\n",
+            responses
+        ))
+}
+
+/// Advertises WebDAV class 1 so file managers will mount the collection
+pub async fn dav_options() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("DAV", "1"))
+        .insert_header((header::ALLOW, ALLOWED_METHODS))
+        .finish()
+}
+
+/// PROPFIND on `/dav/`: the collection and, unless `Depth: 0`, the caller's files as
+/// its members (the collection is flat, so `infinity` is treated as `1`)
+pub async fn dav_propfind_collection(
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let collection = format!("{}/", req.path().trim_end_matches('/'));
+    let mut out = String::new();
+    write_collection(&mut out, &collection);
+
+    let depth = req.headers().get("Depth").and_then(|v| v.to_str().ok());
+    if depth != Some("0") {
+        let entries = read_metadata(&scope.metadata_file)?;
+        let mut files: Vec<&UploadMetadata> = current_files(&entries)
+            .into_iter()
+            .filter(|entry| user.as_ref().is_none_or(|user| entry.user == user.sub))
+            .collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        for entry in files {
+            let href = format!("{}{}", collection, encode_segment(&entry.filename));
+            write_file(&mut out, &href, entry);
+        }
+    }
+    Ok(multistatus(out))
+}
+
+/// PROPFIND on `/dav/{filename}`: that file's properties, for its owner only
+pub async fn dav_propfind_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filename = path.into_inner();
+    let entries = read_metadata(&scope.metadata_file)?;
+    let entry = current_files(&entries)
+        .into_iter()
+        .find(|entry| entry.filename == filename)
+        .filter(|entry| user.as_ref().is_none_or(|user| entry.user == user.sub))
+        .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+
+    let mut out = String::new();
+    write_file(&mut out, req.path(), entry);
+    Ok(multistatus(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    #[test]
+    fn hrefs_encode_each_filename_as_one_segment() {
+        assert_eq!(
+            encode_segment("report-v1.2_final~.pdf"),
+            "report-v1.2_final~.pdf"
+        );
+        assert_eq!(encode_segment("a b/c?.txt"), "a%20b%2Fc%3F.txt");
+        assert_eq!(encode_segment("café"), "caf%C3%A9");
+    }
+
+    fn propfind(uri: &str, user: &str, depth: &str) -> actix_http::Request {
+        TestRequest::default()
+            .method(propfind_method())
+            .uri(uri)
+            .insert_header((TEST_USER_HEADER, user))
+            .insert_header(("Depth", depth))
+            .to_request()
+    }
+
+    #[actix_web::test]
+    async fn propfind_lists_the_callers_files_with_sizes() {
+        let _env = TestEnv::new();
+        let app = init_service(app()).await;
+        for (user, name, content) in [
+            ("alice", "notes & ideas.txt", &b"hello"[..]),
+            ("alice", "a.txt", b"0123456789"),
+            ("bob", "bob.txt", b"secret"),
+        ] {
+            let req = Form::new()
+                .file(name, content)
+                .post("/api/upload")
+                .insert_header((TEST_USER_HEADER, user))
+                .to_request();
+            assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        let resp = call_service(&app, propfind("/dav/", "alice", "1")).await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        let hrefs: Vec<&str> = body
+            .split("<D:href>")
+            .skip(1)
+            .map(|rest| rest.split("</D:href>").next().unwrap())
+            .collect();
+        assert_eq!(
+            hrefs,
+            ["/dav/", "/dav/a.txt", "/dav/notes%20%26%20ideas.txt"]
+        );
+        assert!(body.contains("<D:displayname>notes &amp; ideas.txt</D:displayname>"));
+        assert!(body.contains("<D:getcontentlength>10</D:getcontentlength>"));
+        assert!(body.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(!body.contains("bob.txt"));
+
+        let resp = call_service(&app, propfind("/dav/", "alice", "0")).await;
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(body.matches("<D:response>").count(), 1);
+
+        let resp = call_service(&app, propfind("/dav/a.txt", "alice", "0")).await;
+        assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+        let resp = call_service(&app, propfind("/dav/bob.txt", "alice", "0")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn files_download_through_the_collection() {
+        let _env = TestEnv::new();
+        let app = init_service(app()).await;
+        let req = Form::new()
+            .file("a.txt", b"hello dav")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = TestRequest::get()
+            .uri("/dav/a.txt")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(read_body(resp).await.as_ref(), b"hello dav");
+
+        let req = TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/dav/")
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.headers().get("DAV").unwrap(), "1");
+        assert_eq!(resp.headers().get(header::ALLOW).unwrap(), ALLOWED_METHODS);
+    }
+}
@@ -0,0 +1,58 @@
+use actix_web::http::StatusCode;
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Free space below which uploads are refused, from `DISK_SOFT_LIMIT_BYTES`
+pub fn disk_soft_limit() -> Option<u64> {
+    env::var("DISK_SOFT_LIMIT_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Bytes available to unprivileged writers on the filesystem holding `path`
+pub fn free_bytes(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid, writable statvfs
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Describes the disk pressure on `dir`'s filesystem, `None` when free space is above
+/// the soft limit (or no limit is set)
+pub fn soft_limit_exceeded(dir: &Path) -> Option<String> {
+    let limit = disk_soft_limit()?;
+    match free_bytes(dir) {
+        Ok(free) if free < limit => Some(format!(
+            "{} bytes free, below the soft limit of {}",
+            free, limit
+        )),
+        Ok(_) => None,
+        Err(e) => {
+            log::warn!("Could not read free space of {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+/// 507 for uploads while free space is below `DISK_SOFT_LIMIT_BYTES`; reads are unaffected
+pub fn check_soft_limit(dir: &Path) -> Result<(), actix_web::Error> {
+    match soft_limit_exceeded(dir) {
+        Some(reason) => {
+            log::warn!("Rejecting upload under disk pressure: {}", reason);
+            Err(actix_web::error::InternalError::new(
+                "Insufficient storage: uploads are paused until disk space is freed",
+                StatusCode::INSUFFICIENT_STORAGE,
+            )
+            .into())
+        }
+        None => Ok(()),
+    }
+}
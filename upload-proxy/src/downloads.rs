@@ -0,0 +1,273 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Seconds clients are told to wait when every download permit is taken
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Caps the downloads being read at once, from `MAX_CONCURRENT_DOWNLOADS`, so a burst
+/// of range requests can't exhaust file descriptors
+pub struct DownloadLimiter {
+    limit: Option<usize>,
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl DownloadLimiter {
+    /// Reads `MAX_CONCURRENT_DOWNLOADS`; unset or 0 is unlimited
+    pub fn from_env() -> Self {
+        let limit = env::var("MAX_CONCURRENT_DOWNLOADS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|&v| v > 0);
+        Self {
+            limit,
+            permits: limit.map(|limit| Arc::new(Semaphore::new(limit))),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
+
+/// Response body that releases its download permit once fully sent or dropped
+struct PermitBody {
+    body: BoxBody,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MessageBody for PermitBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+/// 503 with `Retry-After` when every download permit is in use; the permit is held
+/// until the response body has been streamed
+pub async fn limit_downloads(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let permits = req
+        .app_data::<web::Data<DownloadLimiter>>()
+        .and_then(|limiter| limiter.permits.clone());
+    let Some(permits) = permits else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let Ok(permit) = permits.try_acquire_owned() else {
+        log::warn!("Download limit reached, rejecting {}", req.path());
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()))
+            .json(serde_json::json!({
+                "error": "too_many_downloads",
+                "retry_after_secs": RETRY_AFTER_SECS,
+            }));
+        return Ok(req.into_response(response));
+    };
+    let response = next.call(req).await?;
+    Ok(response.map_body(|_, body| {
+        BoxBody::new(PermitBody {
+            body: body.boxed(),
+            _permit: permit,
+        })
+    }))
+}
+
+struct CachedFile {
+    bytes: Bytes,
+    /// Modification time when read, so a file replaced on disk is read again
+    modified: Option<SystemTime>,
+    /// Value of `Cache::clock` when last served, for least-recently-used eviction
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    files: HashMap<PathBuf, CachedFile>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+/// In-memory cache of small stored files, so repeated downloads of hot files are
+/// served without reading them from disk. Bounded by `DOWNLOAD_CACHE_BYTES`, evicting
+/// the least recently used files first.
+pub struct DownloadCache {
+    capacity: u64,
+    max_file_bytes: u64,
+    cache: Mutex<Cache>,
+}
+
+impl DownloadCache {
+    /// Reads `DOWNLOAD_CACHE_BYTES` (unset or 0 disables the cache) and
+    /// `DOWNLOAD_CACHE_MAX_FILE_BYTES` (default 256 KiB)
+    pub fn from_env() -> Self {
+        let bytes = |var: &str| env::var(var).ok().and_then(|v| v.parse::<u64>().ok());
+        let capacity = bytes("DOWNLOAD_CACHE_BYTES").unwrap_or(0);
+        Self {
+            capacity,
+            max_file_bytes: bytes("DOWNLOAD_CACHE_MAX_FILE_BYTES")
+                .unwrap_or(256 * 1024)
+                .min(capacity),
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Whether a file of `len` bytes may be cached
+    pub fn admits(&self, len: u64) -> bool {
+        self.enabled() && len <= self.max_file_bytes
+    }
+
+    /// Cached content of `path`, if it was read while the file had `modified` as its
+    /// modification time
+    pub fn get(&self, path: &Path, modified: Option<SystemTime>) -> Option<Bytes> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.clock += 1;
+        let clock = cache.clock;
+        let file = cache.files.get_mut(path)?;
+        if file.modified == modified {
+            file.last_used = clock;
+            return Some(file.bytes.clone());
+        }
+        Self::remove(&mut cache, path);
+        None
+    }
+
+    pub fn insert(&self, path: &Path, bytes: Bytes, modified: Option<SystemTime>) {
+        if !self.admits(bytes.len() as u64) {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        Self::remove(&mut cache, path);
+        while cache.total_bytes + bytes.len() as u64 > self.capacity {
+            let Some(oldest) = cache
+                .files
+                .iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            Self::remove(&mut cache, &oldest);
+        }
+        cache.clock += 1;
+        cache.total_bytes += bytes.len() as u64;
+        let last_used = cache.clock;
+        cache.files.insert(
+            path.to_path_buf(),
+            CachedFile {
+                bytes,
+                modified,
+                last_used,
+            },
+        );
+    }
+
+    /// Drops `path`, e.g. because it was overwritten or deleted
+    pub fn invalidate(&self, path: &Path) {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        Self::remove(&mut cache, path);
+    }
+
+    fn remove(cache: &mut Cache, path: &Path) {
+        if let Some(file) = cache.files.remove(path) {
+            cache.total_bytes -= file.bytes.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    #[test]
+    fn download_limit_is_read_from_the_environment() {
+        let mut env = TestEnv::new();
+        env.remove("MAX_CONCURRENT_DOWNLOADS");
+        assert_eq!(DownloadLimiter::from_env().limit(), None);
+        env.set("MAX_CONCURRENT_DOWNLOADS", "0");
+        assert_eq!(DownloadLimiter::from_env().limit(), None);
+        env.set("MAX_CONCURRENT_DOWNLOADS", "8");
+        let limiter = DownloadLimiter::from_env();
+        assert_eq!(limiter.limit(), Some(8));
+        assert_eq!(limiter.permits.unwrap().available_permits(), 8);
+    }
+
+    fn cache(capacity: u64, max_file_bytes: u64) -> DownloadCache {
+        let _env = TestEnv::new()
+            .with("DOWNLOAD_CACHE_BYTES", &capacity.to_string())
+            .with("DOWNLOAD_CACHE_MAX_FILE_BYTES", &max_file_bytes.to_string());
+        DownloadCache::from_env()
+    }
+
+    #[test]
+    fn cached_files_are_dropped_when_changed_or_invalidated() {
+        let cache = cache(100, 10);
+        let path = Path::new("uploads/a.txt");
+        let modified = Some(SystemTime::UNIX_EPOCH);
+        assert!(!cache.admits(11));
+
+        cache.insert(path, Bytes::from_static(b"hello"), modified);
+        assert_eq!(cache.get(path, modified).unwrap(), "hello");
+        // A different modification time means the file was replaced
+        assert!(cache.get(path, Some(SystemTime::now())).is_none());
+        assert!(cache.get(path, modified).is_none());
+
+        cache.insert(path, Bytes::from_static(b"hello"), modified);
+        cache.invalidate(path);
+        assert!(cache.get(path, modified).is_none());
+        assert_eq!(cache.cache.lock().unwrap().total_bytes, 0);
+    }
+
+    #[test]
+    fn least_recently_used_files_are_evicted_first() {
+        let cache = cache(20, 10);
+        let (a, b, c) = (Path::new("a"), Path::new("b"), Path::new("c"));
+        cache.insert(a, Bytes::from_static(b"aaaaaaaaaa"), None);
+        cache.insert(b, Bytes::from_static(b"bbbbbbbbbb"), None);
+        assert!(cache.get(a, None).is_some());
+
+        cache.insert(c, Bytes::from_static(b"cccccccccc"), None);
+        assert!(cache.get(a, None).is_some());
+        assert!(cache.get(b, None).is_none());
+        assert!(cache.get(c, None).is_some());
+        assert_eq!(cache.cache.lock().unwrap().total_bytes, 20);
+    }
+
+    #[test]
+    fn cache_is_off_unless_sized() {
+        let mut env = TestEnv::new();
+        env.remove("DOWNLOAD_CACHE_BYTES");
+        let cache = DownloadCache::from_env();
+        assert!(!cache.enabled());
+        assert!(!cache.admits(0));
+    }
+}
@@ -0,0 +1,472 @@
+use actix_web::web::Bytes;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use futures::Stream;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Plaintext bytes per payload chunk, fixed by the age format
+const CHUNK_BYTES: usize = 64 * 1024;
+
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: impl Iterator<Item = u8>) -> u32 {
+    const GENERATORS: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (bit, generator) in GENERATORS.iter().enumerate() {
+            if (top >> bit) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+/// An age X25519 recipient, e.g. `age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p`
+pub struct AgeRecipient([u8; 32]);
+
+impl AgeRecipient {
+    /// Decodes the Bech32 `age1…` form
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid age recipient '{}'", value);
+        let value = value.trim().to_lowercase();
+        let (hrp, data) = value.rsplit_once('1').ok_or_else(invalid)?;
+        if hrp != "age" || data.len() < 6 {
+            return Err(invalid());
+        }
+        let data: Vec<u8> = data
+            .bytes()
+            .map(|c| BECH32_CHARSET.iter().position(|&x| x == c).map(|v| v as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        let expanded = hrp
+            .bytes()
+            .map(|c| c >> 5)
+            .chain([0])
+            .chain(hrp.bytes().map(|c| c & 31))
+            .chain(data.iter().copied());
+        if bech32_polymod(expanded) != 1 {
+            return Err(invalid());
+        }
+
+        // Regroup the 5-bit values, minus the checksum, into bytes
+        let mut key = Vec::with_capacity(32);
+        let (mut acc, mut bits) = (0u32, 0u32);
+        for &value in &data[..data.len() - 6] {
+            acc = (acc << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                key.push((acc >> bits) as u8);
+            }
+        }
+        if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+            return Err(invalid());
+        }
+        key.try_into().map(Self).map_err(|_| invalid())
+    }
+}
+
+fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    // 32 bytes is the HKDF-SHA256 output length, so expansion can't fail
+    let _ = Salt::new(HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(&[info], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut out));
+    out
+}
+
+fn chacha_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("32-byte ChaCha20 key"))
+}
+
+fn crypto_error(_: ring::error::Unspecified) -> io::Error {
+    io::Error::other("Encryption failed")
+}
+
+/// The random values behind one encrypted file
+struct FileSecrets {
+    file_key: [u8; 16],
+    /// Public half of the ephemeral X25519 key
+    share: Vec<u8>,
+    /// Secret the ephemeral key agrees with the recipient
+    shared_secret: Vec<u8>,
+    payload_nonce: [u8; 16],
+}
+
+impl FileSecrets {
+    fn generate(recipient: &AgeRecipient) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let mut file_key = [0u8; 16];
+        rng.fill(&mut file_key).map_err(crypto_error)?;
+        let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).map_err(crypto_error)?;
+        let share = ephemeral.compute_public_key().map_err(crypto_error)?;
+        let share = share.as_ref().to_vec();
+        let shared_secret = agreement::agree_ephemeral(
+            ephemeral,
+            &UnparsedPublicKey::new(&X25519, recipient.0),
+            |secret| secret.to_vec(),
+        )
+        .map_err(crypto_error)?;
+        let mut payload_nonce = [0u8; 16];
+        rng.fill(&mut payload_nonce).map_err(crypto_error)?;
+        Ok(Self {
+            file_key,
+            share,
+            shared_secret,
+            payload_nonce,
+        })
+    }
+}
+
+/// age header for `recipient` plus the payload nonce, and the key for the payload
+fn header(recipient: &AgeRecipient, secrets: &FileSecrets) -> io::Result<(Vec<u8>, LessSafeKey)> {
+    let FileSecrets {
+        file_key,
+        share,
+        shared_secret,
+        payload_nonce,
+    } = secrets;
+
+    // X25519 stanza: the file key wrapped with a key agreed with an ephemeral share
+    let salt = [share.as_slice(), &recipient.0].concat();
+    let wrap_key = hkdf(&salt, shared_secret, b"age-encryption.org/v1/X25519");
+    let mut wrapped = file_key.to_vec();
+    chacha_key(&wrap_key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key([0; 12]),
+            Aad::empty(),
+            &mut wrapped,
+        )
+        .map_err(crypto_error)?;
+
+    let mut header = format!(
+        "age-encryption.org/v1\n-> X25519 {}\n{}\n---",
+        STANDARD_NO_PAD.encode(share),
+        STANDARD_NO_PAD.encode(&wrapped)
+    );
+    let mac_key = hmac::Key::new(hmac::HMAC_SHA256, &hkdf(&[], file_key, b"header"));
+    let mac = hmac::sign(&mac_key, header.as_bytes());
+    header.push_str(&format!(" {}\n", STANDARD_NO_PAD.encode(mac.as_ref())));
+
+    let payload_key = chacha_key(&hkdf(payload_nonce, file_key, b"payload"));
+    let mut out = header.into_bytes();
+    out.extend_from_slice(payload_nonce);
+    Ok((out, payload_key))
+}
+
+/// Reads up to one full chunk, stopping early only at end of input
+async fn read_chunk(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+    while chunk.len() < CHUNK_BYTES {
+        let read = (&mut *reader)
+            .take((CHUNK_BYTES - chunk.len()) as u64)
+            .read_to_end(&mut chunk)
+            .await?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(chunk)
+}
+
+struct EncryptState<R> {
+    reader: R,
+    key: LessSafeKey,
+    header: Option<Vec<u8>>,
+    /// Chunk read ahead, to tell whether the one before it was the last
+    next: Option<Vec<u8>>,
+    counter: u64,
+    done: bool,
+}
+
+/// Streams `reader` encrypted to `recipient` in the age v1 format, one 64 KiB chunk at
+/// a time
+pub fn encrypt_stream(
+    reader: impl AsyncRead + Unpin + 'static,
+    recipient: &AgeRecipient,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let secrets = FileSecrets::generate(recipient)?;
+    Ok(encrypt_with(reader, header(recipient, &secrets)?))
+}
+
+/// Streams `reader` encrypted under `key`, after `header`
+fn encrypt_with(
+    reader: impl AsyncRead + Unpin + 'static,
+    (header, key): (Vec<u8>, LessSafeKey),
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let state = EncryptState {
+        reader,
+        key,
+        header: Some(header),
+        next: None,
+        counter: 0,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        if let Some(header) = state.header.take() {
+            return Some((Ok(Bytes::from(header)), state));
+        }
+        if state.done {
+            return None;
+        }
+        let result = async {
+            let mut chunk = match state.next.take() {
+                Some(chunk) => chunk,
+                None => read_chunk(&mut state.reader).await?,
+            };
+            // A full chunk is only the last one if nothing follows it
+            let last = if chunk.len() < CHUNK_BYTES {
+                true
+            } else {
+                let next = read_chunk(&mut state.reader).await?;
+                let last = next.is_empty();
+                state.next = Some(next).filter(|next| !next.is_empty());
+                last
+            };
+            let mut nonce = [0u8; 12];
+            nonce[3..11].copy_from_slice(&state.counter.to_be_bytes());
+            nonce[11] = last as u8;
+            state
+                .key
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::empty(),
+                    &mut chunk,
+                )
+                .map_err(crypto_error)?;
+            state.counter += 1;
+            state.done = last;
+            Ok(Bytes::from(chunk))
+        }
+        .await;
+        if result.is_err() {
+            state.done = true;
+        }
+        Some((result, state))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use futures::TryStreamExt;
+
+    /// A fresh X25519 identity and its `age1…` recipient. ring only exposes single-use
+    /// private keys, which is all one decryption needs.
+    fn identity() -> (EphemeralPrivateKey, AgeRecipient, String) {
+        let private = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).unwrap();
+        let public: [u8; 32] = private
+            .compute_public_key()
+            .unwrap()
+            .as_ref()
+            .try_into()
+            .unwrap();
+
+        let mut data = Vec::new();
+        let (mut acc, mut bits) = (0u32, 0u32);
+        for byte in public {
+            acc = (acc << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                data.push(((acc >> bits) & 31) as u8);
+            }
+        }
+        data.push(((acc << (5 - bits)) & 31) as u8);
+        let expanded = [3, 3, 3, 0, 1, 7, 5].into_iter().chain(data.clone());
+        let checksum = bech32_polymod(expanded.chain([0; 6])) ^ 1;
+        data.extend((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+        let encoded: String = data
+            .iter()
+            .map(|&value| BECH32_CHARSET[value as usize] as char)
+            .collect();
+        (private, AgeRecipient(public), format!("age1{}", encoded))
+    }
+
+    /// Decrypts an age v1 file for the X25519 identity `private`
+    fn decrypt(private: EphemeralPrivateKey, recipient: &AgeRecipient, age: &[u8]) -> Vec<u8> {
+        let header_end = age.windows(4).position(|w| w == b"\n---").unwrap() + 4;
+        let header = std::str::from_utf8(&age[..header_end]).unwrap();
+        let mac_end = header_end + age[header_end..].iter().position(|&b| b == b'\n').unwrap();
+        let mac = STANDARD_NO_PAD
+            .decode(std::str::from_utf8(&age[header_end + 1..mac_end]).unwrap())
+            .unwrap();
+        let lines: Vec<&str> = header.lines().collect();
+        assert_eq!(lines[0], "age-encryption.org/v1");
+        let share = STANDARD_NO_PAD
+            .decode(lines[1].strip_prefix("-> X25519 ").unwrap())
+            .unwrap();
+        let mut file_key = STANDARD_NO_PAD.decode(lines[2]).unwrap();
+
+        let shared_secret = agreement::agree_ephemeral(
+            private,
+            &UnparsedPublicKey::new(&X25519, &share),
+            |secret| secret.to_vec(),
+        )
+        .unwrap();
+        let salt = [share.as_slice(), &recipient.0].concat();
+        let wrap_key = hkdf(&salt, &shared_secret, b"age-encryption.org/v1/X25519");
+        let file_key = chacha_key(&wrap_key)
+            .open_in_place(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::empty(),
+                &mut file_key,
+            )
+            .unwrap()
+            .to_vec();
+        let mac_key = hmac::Key::new(hmac::HMAC_SHA256, &hkdf(&[], &file_key, b"header"));
+        hmac::verify(&mac_key, header.as_bytes(), &mac).unwrap();
+
+        let nonce = &age[mac_end + 1..mac_end + 17];
+        let payload_key = chacha_key(&hkdf(nonce, &file_key, b"payload"));
+        let chunks: Vec<&[u8]> = age[mac_end + 17..].chunks(CHUNK_BYTES + 16).collect();
+        let mut plaintext = Vec::new();
+        for (counter, chunk) in chunks.iter().enumerate() {
+            let mut nonce = [0u8; 12];
+            nonce[3..11].copy_from_slice(&(counter as u64).to_be_bytes());
+            nonce[11] = (counter == chunks.len() - 1) as u8;
+            let mut chunk = chunk.to_vec();
+            let opened = payload_key
+                .open_in_place(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::empty(),
+                    &mut chunk,
+                )
+                .unwrap();
+            plaintext.extend_from_slice(opened);
+        }
+        plaintext
+    }
+
+    async fn encrypted(content: &[u8], recipient: &AgeRecipient) -> Vec<u8> {
+        let chunks: Vec<Bytes> = encrypt_stream(std::io::Cursor::new(content.to_vec()), recipient)
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        chunks.concat()
+    }
+
+    #[test]
+    fn recipients_are_bech32_checked() {
+        let recipient = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+        assert!(AgeRecipient::parse(recipient).is_ok());
+        assert!(AgeRecipient::parse(&recipient.to_uppercase()).is_ok());
+        let typo = recipient.replace("ql3z", "ql4z");
+        assert!(AgeRecipient::parse(&typo).is_err());
+        assert!(AgeRecipient::parse("age1").is_err());
+        assert!(AgeRecipient::parse("npub1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8z").is_err());
+
+        let (_, key, encoded) = identity();
+        assert_eq!(AgeRecipient::parse(&encoded).unwrap().0, key.0);
+    }
+
+    #[actix_web::test]
+    async fn encrypted_streams_decrypt_with_the_matching_key() {
+        let sizes = [0, 10, CHUNK_BYTES, CHUNK_BYTES * 2 + 7];
+        for size in sizes {
+            let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (private, recipient, _) = identity();
+            let age = encrypted(&content, &recipient).await;
+            assert_eq!(
+                decrypt(private, &recipient, &age),
+                content,
+                "{} bytes",
+                size
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn encryption_matches_an_independent_implementation() {
+        // testdata/age/generate.py with the same values: the RFC 7748 section 6.1 keys,
+        // Alice's as the ephemeral share and Bob's as the recipient
+        let recipient =
+            AgeRecipient::parse("age1m60dkltm0hqmf56mv8pweep4xulcxs7gtduxwnddl3lpgmug9d8s0dmj33")
+                .unwrap();
+        assert_eq!(
+            hex::encode(recipient.0),
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"
+        );
+        let secrets = FileSecrets {
+            file_key: std::array::from_fn(|i| i as u8),
+            share: hex::decode("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+                .unwrap(),
+            shared_secret: hex::decode(
+                "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742",
+            )
+            .unwrap(),
+            payload_nonce: std::array::from_fn(|i| 0x10 + i as u8),
+        };
+
+        let reader = std::io::Cursor::new(b"age interop vector\n".to_vec());
+        let chunks: Vec<Bytes> = encrypt_with(reader, header(&recipient, &secrets).unwrap())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks.concat(),
+            include_bytes!("../testdata/age/x25519.age").as_slice()
+        );
+    }
+
+    #[actix_web::test]
+    async fn downloads_are_encrypted_to_the_requested_recipient() {
+        let _env = TestEnv::new();
+        let app = init_service(app()).await;
+        let content = b"quarterly numbers".repeat(5000);
+        let req = Form::new()
+            .file("report.txt", &content)
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let (private, recipient, encoded) = identity();
+        let req = TestRequest::get()
+            .uri(&format!("/api/files/report.txt?encrypt_to={}", encoded))
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let disposition = resp
+            .headers()
+            .get("content-disposition")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(disposition.contains("report.txt.age"), "{}", disposition);
+        let age = read_body(resp).await;
+        assert_eq!(decrypt(private, &recipient, &age), content);
+
+        let rejected = [
+            "/api/files/report.txt?encrypt_to=age1nope".to_string(),
+            format!(
+                "/api/files/report.txt?transform=grayscale&encrypt_to={}",
+                encoded
+            ),
+        ];
+        for uri in rejected {
+            let req = TestRequest::get()
+                .uri(&uri)
+                .insert_header((TEST_USER_HEADER, "alice"))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+}
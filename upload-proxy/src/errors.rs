@@ -0,0 +1,156 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::Next;
+use actix_web::HttpResponse;
+
+/// Quality the `Accept` header gives `mime`, taken from its most specific matching range
+pub fn accept_quality(accept: &str, mime: &str) -> f32 {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        let specificity = if name == mime {
+            2
+        } else if name.strip_suffix("/*") == Some(kind) {
+            1
+        } else if name == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(seen, _)| specificity > seen) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+/// Whether the client prefers an HTML page to JSON, as browsers navigating to a URL do;
+/// ties and a missing `Accept` go to JSON
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept_quality(accept, "text/html") > accept_quality(accept, "application/json")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders plain-text error responses as a small HTML page for browsers and as
+/// `{"error": ...}` for everyone else. Handlers that already answer with JSON, and
+/// errors without a body, are passed through unchanged.
+pub async fn negotiate_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let html = prefers_html(req.headers());
+    match next.call(req).await {
+        Ok(res) => {
+            let (request, res) = res.into_parts();
+            let res = render_error(res.map_into_boxed_body(), html).await;
+            Ok(ServiceResponse::new(request, res))
+        }
+        // Errors from inner middleware have no request attached yet
+        Err(e) => {
+            let res = render_error(e.error_response(), html).await;
+            Err(InternalError::from_response(e, res).into())
+        }
+    }
+}
+
+async fn render_error(res: HttpResponse, html: bool) -> HttpResponse {
+    let status = res.status();
+    let plain_text = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/plain"));
+    if !(status.is_client_error() || status.is_server_error()) || !plain_text {
+        return res;
+    }
+
+    let headers = res.headers().clone();
+    let message = match to_bytes(res.into_body()).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(e) => {
+            log::error!("Failed to read error body: {}", e);
+            status.canonical_reason().unwrap_or_default().to_string()
+        }
+    };
+
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in headers.iter() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            builder.append_header((name.clone(), value.clone()));
+        }
+    }
+    builder.append_header((header::VARY, "Accept"));
+    if html {
+        let title = format!(
+            "{} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("Error")
+        );
+        builder
+            .content_type("text/html; charset=utf-8")
+            .body(format!(
+                "<!DOCTYPE html>\n<html><head><title>{title}</title></head>\
+             <body><h1>{title}</h1><p>{}</p></body></html>\n",
+                escape_html(&message),
+            ))
+    } else {
+        builder.json(serde_json::json!({ "error": message }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_quality_uses_the_most_specific_range() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(accept_quality(browser, "text/html"), 1.0);
+        assert_eq!(accept_quality(browser, "application/json"), 0.8);
+        assert_eq!(
+            accept_quality("text/*;q=0.5, text/html;q=0.2", "text/html"),
+            0.2
+        );
+        assert_eq!(accept_quality("text/*;q=0.5", "text/plain"), 0.5);
+        assert_eq!(accept_quality("image/png", "text/html"), 0.0);
+    }
+
+    #[test]
+    fn html_is_only_chosen_when_preferred() {
+        let prefers = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            prefers_html(&headers)
+        };
+        assert!(prefers("text/html,*/*;q=0.8"));
+        assert!(!prefers("application/json"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers("text/html, application/json"));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn html_pages_escape_the_message() {
+        assert_eq!(
+            escape_html(r#"<script>"a" & b</script>"#),
+            "&lt;script&gt;&quot;a&quot; &amp; b&lt;/script&gt;"
+        );
+    }
+}
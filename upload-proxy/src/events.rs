@@ -0,0 +1,186 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::auth::AuthenticatedUser;
+use crate::metadata::UploadMetadata;
+use crate::timestamps::{Timestamp, TimestampFormat};
+
+/// A change to a user's stored files, pushed to connected clients
+#[derive(Clone, Debug, Serialize)]
+pub struct FileEvent {
+    pub event: String,
+    pub filename: String,
+    pub user: String,
+    pub size_bytes: u64,
+    pub timestamp: Timestamp,
+}
+
+impl FileEvent {
+    pub fn uploaded(metadata: &UploadMetadata, format: TimestampFormat) -> Self {
+        Self {
+            event: "upload".to_string(),
+            filename: metadata.filename.clone(),
+            user: metadata.user.clone(),
+            size_bytes: metadata.size_bytes,
+            timestamp: format.render(&metadata.timestamp),
+        }
+    }
+}
+
+/// Broadcast channel shared by all workers; receivers filter to their own user's events
+pub struct EventBus {
+    sender: broadcast::Sender<FileEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Publishes an event; having no subscribers is not an error
+    pub fn publish(&self, event: FileEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<FileEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// WebSocket feed of the caller's own upload events
+pub async fn events_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    user: AuthenticatedUser,
+    events: web::Data<EventBus>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut receiver = events.subscribe();
+    log::info!("WebSocket connected for user: {}", user.sub);
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Ok(event) if event.user == user.sub => {
+                        let Ok(json) = serde_json::to_string(&event) else { continue };
+                        if session.text(json).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("WebSocket for {} dropped {} events", user.sub, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                message = messages.next() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => {
+                        let _ = session.close(reason).await;
+                        log::info!("WebSocket closed by client: {}", user.sub);
+                        return;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        log::warn!("WebSocket protocol error for {}: {}", user.sub, e);
+                        break;
+                    }
+                    None => break,
+                },
+            }
+        }
+        let _ = session.close(None).await;
+        log::info!("WebSocket disconnected: {}", user.sub);
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{serve, Form, TestEnv, TEST_USER_HEADER};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    /// Opens `/api/ws` on `url` as `user`, returning the stream once the handshake is done
+    async fn connect(url: &str, user: &str) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+        let mut stream = BufReader::new(stream);
+        let handshake = format!(
+            "GET /api/ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n{}: {}\r\n\r\n",
+            TEST_USER_HEADER, user
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut status = String::new();
+        stream.read_line(&mut status).await.unwrap();
+        assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+        }
+        stream
+    }
+
+    /// Reads one unmasked server frame, returning its opcode and payload
+    async fn read_frame(stream: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        let len = match head[1] & 0x7f {
+            126 => stream.read_u16().await.unwrap() as usize,
+            127 => stream.read_u64().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    async fn upload(url: &str, user: &str, filename: &str) {
+        let (content_type, body) = Form::new().file(filename, b"hello").finish();
+        let resp = reqwest::Client::new()
+            .post(format!("{}/api/upload", url))
+            .header("Content-Type", content_type)
+            .header(TEST_USER_HEADER, user)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn websocket_pushes_the_callers_own_uploads() {
+        let _env = TestEnv::new();
+        let url = serve();
+        let mut socket = connect(&url, "alice").await;
+
+        upload(&url, "bob", "theirs.txt").await;
+        upload(&url, "alice", "mine.txt").await;
+
+        let (opcode, payload) =
+            tokio::time::timeout(Duration::from_secs(5), read_frame(&mut socket))
+                .await
+                .expect("no event arrived");
+        assert_eq!(opcode, 0x1);
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(event["event"], "upload");
+        assert_eq!(event["filename"], "mine.txt");
+        assert_eq!(event["user"], "alice");
+        assert_eq!(event["size_bytes"], 5);
+    }
+}
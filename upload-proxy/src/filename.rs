@@ -0,0 +1,397 @@
+use actix_web::web;
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+use std::collections::HashSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::naming::{name_strategy_from_env, NameStrategy};
+
+/// Filename rules loaded once at startup and shared with the upload handler
+#[derive(Clone)]
+pub struct FilenameRules {
+    pub allowed_pattern: Option<Regex>,
+    /// Globs from `DENY_FILENAME_PATTERNS`, matched case-insensitively
+    pub denied_patterns: Vec<Pattern>,
+    /// How stored names are derived from the client's, from `NAME_STRATEGY`
+    pub name_strategy: Arc<dyn NameStrategy>,
+}
+
+impl FilenameRules {
+    /// Builds the rules from the environment, failing on an invalid `FILENAME_REGEX`,
+    /// `DENY_FILENAME_PATTERNS` entry or `NAME_STRATEGY`
+    pub fn from_env() -> Result<Self, String> {
+        let allowed_pattern = match env::var("FILENAME_REGEX") {
+            Ok(pattern) if !pattern.trim().is_empty() => Some(
+                Regex::new(&pattern)
+                    .map_err(|e| format!("Invalid FILENAME_REGEX '{}': {}", pattern, e))?,
+            ),
+            _ => None,
+        };
+
+        let denied_patterns = env::var("DENY_FILENAME_PATTERNS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                Pattern::new(p)
+                    .map_err(|e| format!("Invalid DENY_FILENAME_PATTERNS entry '{}': {}", p, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            allowed_pattern,
+            denied_patterns,
+            name_strategy: name_strategy_from_env()?.into(),
+        })
+    }
+
+    /// Checks a filename against the configured rules
+    pub fn validate(&self, filename: &str) -> Result<(), String> {
+        if let Some(pattern) = &self.allowed_pattern {
+            if !pattern.is_match(filename) {
+                return Err(format!(
+                    "Filename '{}' does not match the allowed pattern",
+                    filename
+                ));
+            }
+        }
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::default()
+        };
+        if let Some(pattern) = self
+            .denied_patterns
+            .iter()
+            .find(|pattern| pattern.matches_with(filename, options))
+        {
+            return Err(format!(
+                "Filename '{}' is not allowed (matches '{}')",
+                filename,
+                pattern.as_str()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether filenames are lowercased on upload, from `LOWERCASE_FILENAMES`, for
+/// backends that treat `Photo.JPG` and `photo.jpg` as the same file
+fn lowercase_filenames() -> bool {
+    env::var("LOWERCASE_FILENAMES")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Reduces a client-supplied filename to a safe basename.
+///
+/// Strips any directory components (either separator), control characters and
+/// surrounding whitespace, and lowercases it when `LOWERCASE_FILENAMES` is on, so
+/// collision handling and deduplication only ever see the normalized name. Returns
+/// `None` when nothing usable remains.
+pub fn sanitize_filename(raw: &str) -> Option<String> {
+    let basename = raw.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = basename.chars().filter(|c| !c.is_control()).collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        return None;
+    }
+    if lowercase_filenames() {
+        return Some(cleaned.to_lowercase());
+    }
+    Some(cleaned.to_string())
+}
+
+/// Inserts ` (n)` before the extension: `report.pdf` becomes `report (1).pdf`
+pub fn suffixed_filename(filename: &str, n: usize) -> String {
+    match filename.rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &filename[..dot], n, &filename[dot..]),
+        _ => format!("{} ({})", filename, n),
+    }
+}
+
+/// What an upload does when its filename already exists in the uploads directory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// Replace the existing file (previous behaviour)
+    Overwrite,
+    /// Store as `name (1).ext`, `name (2).ext`, ...
+    Suffix,
+    /// Reject the upload with 409
+    Reject,
+}
+
+impl CollisionPolicy {
+    /// Reads `COLLISION_POLICY`, defaulting to `overwrite`
+    pub fn from_env() -> Self {
+        match env::var("COLLISION_POLICY")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "overwrite" => Self::Overwrite,
+            "suffix" => Self::Suffix,
+            "reject" => Self::Reject,
+            other => {
+                log::warn!("Unknown COLLISION_POLICY '{}', using 'overwrite'", other);
+                Self::Overwrite
+            }
+        }
+    }
+}
+
+/// Names claimed by in-flight uploads, so concurrent requests for the same name
+/// resolve to distinct targets instead of racing on one file
+#[derive(Default)]
+pub struct NameReservations {
+    /// Full target paths, so the same name in different directories doesn't clash
+    reserved: Mutex<HashSet<PathBuf>>,
+}
+
+impl NameReservations {
+    /// Picks the name a new file called `filename` is written under in `dir` and
+    /// reserves it until the returned guard drops.
+    ///
+    /// Candidates are tried in order under one lock, so two racing uploads of
+    /// `a.txt` deterministically get `a (1).txt` and `a (2).txt`.
+    pub fn resolve_target(
+        reservations: &web::Data<NameReservations>,
+        dir: &Path,
+        filename: &str,
+        policy: CollisionPolicy,
+    ) -> Result<NameReservation, actix_web::Error> {
+        let mut reserved = reservations
+            .reserved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let name = pick_target(&reserved, dir, filename, policy)?;
+
+        let path = dir.join(&name);
+        reserved.insert(path.clone());
+        Ok(NameReservation {
+            reservations: reservations.clone(),
+            name,
+            path,
+        })
+    }
+
+    /// The name an upload of `filename` would get right now, without reserving it
+    pub fn preview_target(
+        &self,
+        dir: &Path,
+        filename: &str,
+        policy: CollisionPolicy,
+    ) -> Result<String, actix_web::Error> {
+        let reserved = self.reserved.lock().unwrap_or_else(|e| e.into_inner());
+        pick_target(&reserved, dir, filename, policy)
+    }
+}
+
+/// Applies `policy` to `filename` given the files in `dir` and the reserved paths
+fn pick_target(
+    reserved: &HashSet<PathBuf>,
+    dir: &Path,
+    filename: &str,
+    policy: CollisionPolicy,
+) -> Result<String, actix_web::Error> {
+    let in_use = |name: &str| {
+        let path = dir.join(name);
+        reserved.contains(&path) || path.exists()
+    };
+
+    if !in_use(filename) {
+        return Ok(filename.to_string());
+    }
+    match policy {
+        // Another upload is writing this very file; don't interleave with it
+        CollisionPolicy::Overwrite if reserved.contains(&dir.join(filename)) => {
+            Err(actix_web::error::ErrorConflict(format!(
+                "An upload of {} is already in progress",
+                filename
+            )))
+        }
+        CollisionPolicy::Overwrite => Ok(filename.to_string()),
+        CollisionPolicy::Reject => Err(actix_web::error::ErrorConflict(format!(
+            "A file named {} already exists",
+            filename
+        ))),
+        CollisionPolicy::Suffix => (1..=10_000)
+            .map(|n| suffixed_filename(filename, n))
+            .find(|candidate| !in_use(candidate))
+            .ok_or_else(|| {
+                actix_web::error::ErrorConflict(format!("No free name found for {}", filename))
+            }),
+    }
+}
+
+/// A reserved target name, released when dropped
+pub struct NameReservation {
+    reservations: web::Data<NameReservations>,
+    name: String,
+    path: PathBuf,
+}
+
+impl NameReservation {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for NameReservation {
+    fn drop(&mut self) {
+        let mut reserved = self
+            .reservations
+            .reserved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        reserved.remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    #[test]
+    fn filename_regex_allows_only_matching_names() {
+        let _env = TestEnv::new().with("FILENAME_REGEX", r"^[a-z0-9_-]+\.(pdf|txt)$");
+        let rules = FilenameRules::from_env().unwrap();
+        assert!(rules.validate("report_2024.pdf").is_ok());
+        assert!(rules.validate("notes.txt").is_ok());
+        assert!(rules
+            .validate("Report.PDF")
+            .unwrap_err()
+            .contains("does not match the allowed pattern"));
+        assert!(rules.validate("script.sh").is_err());
+    }
+
+    #[test]
+    fn blank_filename_regex_allows_everything() {
+        let _env = TestEnv::new().with("FILENAME_REGEX", "  ");
+        let rules = FilenameRules::from_env().unwrap();
+        assert!(rules.allowed_pattern.is_none());
+        assert!(rules.validate("anything at all.bin").is_ok());
+    }
+
+    #[test]
+    fn invalid_filename_regex_is_a_startup_error() {
+        let _env = TestEnv::new().with("FILENAME_REGEX", "([a-z");
+        let error = FilenameRules::from_env().err().unwrap();
+        assert!(error.starts_with("Invalid FILENAME_REGEX"));
+    }
+
+    #[test]
+    fn suffixed_filename_goes_before_the_extension() {
+        assert_eq!(suffixed_filename("report.pdf", 1), "report (1).pdf");
+        assert_eq!(suffixed_filename("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(suffixed_filename("README", 3), "README (3)");
+        assert_eq!(suffixed_filename(".env", 1), ".env (1)");
+    }
+
+    #[test]
+    fn deny_patterns_match_case_insensitively() {
+        let _env = TestEnv::new().with("DENY_FILENAME_PATTERNS", "*.php, web.config,.htaccess,");
+        let rules = FilenameRules::from_env().unwrap();
+        assert_eq!(rules.denied_patterns.len(), 3);
+        assert_eq!(
+            rules.validate("Shell.PHP").unwrap_err(),
+            "Filename 'Shell.PHP' is not allowed (matches '*.php')"
+        );
+        assert!(rules.validate("WEB.CONFIG").is_err());
+        assert!(rules.validate(".htaccess").is_err());
+        assert!(rules.validate("notes.txt").is_ok());
+        assert!(rules.validate("php.txt").is_ok());
+    }
+
+    #[test]
+    fn invalid_deny_pattern_is_a_startup_error() {
+        let _env = TestEnv::new().with("DENY_FILENAME_PATTERNS", "*.php,[a-");
+        let error = FilenameRules::from_env().err().unwrap();
+        assert!(error.starts_with("Invalid DENY_FILENAME_PATTERNS entry '[a-'"));
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_only_a_clean_basename() {
+        let mut env = TestEnv::new();
+        env.remove("LOWERCASE_FILENAMES");
+        assert_eq!(sanitize_filename("../../etc/passwd").unwrap(), "passwd");
+        assert_eq!(
+            sanitize_filename("C:\\Users\\a\\Report.pdf").unwrap(),
+            "Report.pdf"
+        );
+        assert_eq!(sanitize_filename(" bad\nname.txt ").unwrap(), "badname.txt");
+        assert_eq!(sanitize_filename("dir/.."), None);
+        assert_eq!(sanitize_filename("uploads/"), None);
+    }
+
+    #[test]
+    fn sanitize_filename_lowercases_when_configured() {
+        let _env = TestEnv::new().with("LOWERCASE_FILENAMES", "true");
+        assert_eq!(sanitize_filename("Photo.JPG").unwrap(), "photo.jpg");
+        assert_eq!(sanitize_filename("dir/ÜBER.txt").unwrap(), "über.txt");
+    }
+
+    #[test]
+    fn held_reservations_give_racing_uploads_distinct_names() {
+        let env = TestEnv::new();
+        let dir = env.uploads_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"existing").unwrap();
+        let reservations = web::Data::new(NameReservations::default());
+        let resolve =
+            |policy| NameReservations::resolve_target(&reservations, &dir, "a.txt", policy);
+
+        let first = resolve(CollisionPolicy::Suffix).unwrap();
+        let second = resolve(CollisionPolicy::Suffix).unwrap();
+        assert_eq!(first.name(), "a (1).txt");
+        assert_eq!(second.name(), "a (2).txt");
+        assert_eq!(
+            reservations
+                .preview_target(&dir, "a.txt", CollisionPolicy::Suffix)
+                .unwrap(),
+            "a (3).txt"
+        );
+
+        // Released names are handed out again
+        drop(first);
+        assert_eq!(
+            resolve(CollisionPolicy::Suffix).unwrap().name(),
+            "a (1).txt"
+        );
+    }
+
+    #[test]
+    fn overwrite_refuses_a_name_still_being_written() {
+        let env = TestEnv::new();
+        let dir = env.uploads_dir();
+        let reservations = web::Data::new(NameReservations::default());
+        let resolve =
+            |policy| NameReservations::resolve_target(&reservations, &dir, "a.txt", policy);
+
+        let writing = resolve(CollisionPolicy::Overwrite).unwrap();
+        assert_eq!(writing.name(), "a.txt");
+        let error = resolve(CollisionPolicy::Overwrite).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "An upload of a.txt is already in progress"
+        );
+        let error = resolve(CollisionPolicy::Reject).err().unwrap();
+        assert_eq!(error.to_string(), "A file named a.txt already exists");
+        drop(writing);
+        assert!(resolve(CollisionPolicy::Overwrite).is_ok());
+    }
+
+    #[test]
+    fn collision_policy_defaults_to_overwrite() {
+        let mut env = TestEnv::new();
+        env.remove("COLLISION_POLICY");
+        assert_eq!(CollisionPolicy::from_env(), CollisionPolicy::Overwrite);
+        env.set("COLLISION_POLICY", "Suffix");
+        assert_eq!(CollisionPolicy::from_env(), CollisionPolicy::Suffix);
+        env.set("COLLISION_POLICY", "reject");
+        assert_eq!(CollisionPolicy::from_env(), CollisionPolicy::Reject);
+    }
+}
@@ -1,13 +1,475 @@
-use actix_multipart::Multipart;
-use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
-use chrono::Utc;
+use actix_files::NamedFile;
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::http::header;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Result as ActixResult};
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
+use md5::Md5;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, fs};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
 
-use crate::metadata::{create_upload_response, log_upload_metadata};
+use crate::auth::{AuthenticatedUser, ANONYMOUS_USER};
+use crate::content_type;
+use crate::disk;
+use crate::downloads::DownloadCache;
+use crate::encrypt;
+use crate::events::{EventBus, FileEvent};
+use crate::filename::{
+    sanitize_filename, suffixed_filename, CollisionPolicy, FilenameRules, NameReservation,
+    NameReservations,
+};
+use crate::hooks::{self, DeliveryStatus};
+use crate::images;
+use crate::jwks::JwksCache;
+use crate::metadata::{
+    create_upload_response, current_files, log_upload_metadata, read_metadata, update_metadata,
+    StorageLocation, UploadMetadata, UploadResponse,
+};
+use crate::namespace::StorageScope;
+use crate::ndjson::{self, ResultLine, ResultLines};
+use crate::pipe::{self, PipeOutput};
+use crate::progress::{validate_upload_id, ProgressTracker, UPLOAD_ID_HEADER};
+use crate::ratelimit::{too_many_requests, RateLimiter};
+use crate::receipts;
+use crate::routing::RequestDeadline;
+use crate::tags;
+use crate::timestamps::{Timestamp, TimestampConfig, TimestampFormat};
+use crate::treehash::{self, TreeHasher, TREE_HASH_HEADER};
+use crate::window::UploadWindow;
+use crate::xml;
+
+/// What to do with already-written files when the metadata entry can't be stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetadataFailurePolicy {
+    /// Return 500 and leave the file on disk (previous behaviour)
+    Fail,
+    /// Keep the file and return 200 with a warning
+    Keep,
+    /// Delete the just-written file and return 500
+    Rollback,
+}
+
+impl MetadataFailurePolicy {
+    /// Reads `ON_METADATA_FAILURE`, defaulting to `fail`
+    pub fn from_env() -> Self {
+        match env::var("ON_METADATA_FAILURE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "fail" => Self::Fail,
+            "keep" => Self::Keep,
+            "rollback" => Self::Rollback,
+            other => {
+                log::warn!("Unknown ON_METADATA_FAILURE '{}', using 'fail'", other);
+                Self::Fail
+            }
+        }
+    }
+}
+
+/// Directory uploaded files are stored in, from `UPLOADS_DIR`
+pub fn uploads_dir() -> PathBuf {
+    PathBuf::from(env::var("UPLOADS_DIR").unwrap_or_else(|_| "./uploads".to_string()))
+}
+
+/// Per-request upload size cap from `MAX_UPLOAD_BYTES`; unset or 0 disables it
+pub fn max_upload_bytes() -> Option<u64> {
+    env::var("MAX_UPLOAD_BYTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Files up to `SMALL_FILE_BUFFER_BYTES` are buffered in memory and written in one call;
+/// unset or 0 always streams
+fn small_file_buffer_bytes() -> u64 {
+    env::var("SMALL_FILE_BUFFER_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Whether uploads must arrive over HTTPS, from `REQUIRE_HTTPS`; behind a proxy the
+/// scheme comes from `Forwarded` or `X-Forwarded-Proto`
+fn require_https() -> bool {
+    env::var("REQUIRE_HTTPS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Checks the request headers before any of the body is read. actix-http has already
+/// answered `Expect: 100-continue` by then, so clients may have started sending
+fn check_upload_preconditions(req: &HttpRequest) -> Result<(), actix_web::Error> {
+    if require_https() && !req.connection_info().scheme().eq_ignore_ascii_case("https") {
+        log::warn!("Rejecting upload over {}", req.connection_info().scheme());
+        let response = HttpResponse::build(actix_web::http::StatusCode::UPGRADE_REQUIRED)
+            .insert_header((header::UPGRADE, "TLS/1.2, HTTP/1.1"))
+            .content_type("text/plain; charset=utf-8")
+            .body("Uploads require HTTPS");
+        return Err(actix_web::error::InternalError::from_response(
+            "Uploads require HTTPS",
+            response,
+        )
+        .into());
+    }
+    if let Some(window) = req
+        .app_data::<web::Data<Option<UploadWindow>>>()
+        .and_then(|window| window.as_ref().as_ref())
+    {
+        window.check(Utc::now())?;
+    }
+
+    if let Some(expect) = req.headers().get(header::EXPECT) {
+        if !expect
+            .to_str()
+            .map(|v| v.eq_ignore_ascii_case("100-continue"))
+            .unwrap_or(false)
+        {
+            return Err(actix_web::error::InternalError::new(
+                "Unsupported Expect header",
+                actix_web::http::StatusCode::EXPECTATION_FAILED,
+            )
+            .into());
+        }
+    }
+
+    if let Some(limit) = max_upload_bytes() {
+        let declared = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if let Some(declared) = declared.filter(|&len| len > limit) {
+            log::warn!(
+                "Rejecting upload before body: declared {} bytes exceeds limit {}",
+                declared,
+                limit
+            );
+            return Err(payload_too_large(
+                "request_bytes",
+                limit,
+                format!("Upload exceeds the maximum size of {} bytes", limit),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Takes a token from the global upload limit; called once every cheap rejection has
+/// passed, so requests refused for other reasons don't use up the budget
+fn acquire_upload_token(req: &HttpRequest) -> Result<(), actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        if let Err(retry_after) = limiter.acquire_upload() {
+            log::warn!("Global upload limit reached, retry in {}s", retry_after);
+            return Err(actix_web::error::InternalError::from_response(
+                "Global upload limit reached",
+                too_many_requests(retry_after),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// 413 whose JSON body names the limit that was hit (`request_bytes` or
+/// `files_per_user`) and its configured value, so clients can report it precisely
+fn payload_too_large(limit: &str, value: u64, message: String) -> actix_web::Error {
+    let response = HttpResponse::PayloadTooLarge().json(serde_json::json!({
+        "error": message,
+        "limit": limit,
+        "value": value,
+    }));
+    actix_web::error::InternalError::from_response(message, response).into()
+}
+
+/// `If-Unmodified-Since` of an upload; unparsable dates are ignored as RFC 9110 requires
+fn unmodified_since(req: &HttpRequest) -> Option<DateTime<Utc>> {
+    let value = req.headers().get(header::IF_UNMODIFIED_SINCE)?;
+    match value.to_str().ok()?.parse::<header::HttpDate>() {
+        Ok(date) => Some(std::time::SystemTime::from(date).into()),
+        Err(_) => {
+            log::warn!("Ignoring invalid If-Unmodified-Since {:?}", value);
+            None
+        }
+    }
+}
+
+/// Maximum number of distinct files a user may store, from `MAX_FILES_PER_USER`
+fn max_files_per_user() -> Option<usize> {
+    env::var("MAX_FILES_PER_USER")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Usage share of `MAX_FILES_PER_USER` at which uploads carry `X-Quota-Warning`,
+/// from `QUOTA_WARN_PERCENT`
+fn quota_warn_percent() -> u64 {
+    env::var("QUOTA_WARN_PERCENT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Whether a user re-uploading one of their own filenames gets 409 instead of
+/// overwriting or renaming, from `FILENAME_UNIQUE_PER_USER`
+fn filename_unique_per_user() -> bool {
+    env::var("FILENAME_UNIQUE_PER_USER")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Best-effort removal of files written by a request that is being rejected
+async fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            log::error!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A file being received under a temporary name, and the name it is stored as
+struct PendingFile {
+    partial: PathBuf,
+    target: PathBuf,
+}
+
+/// Files an upload is receiving. Each is written to a hidden `.partial` file next to its
+/// target and only renamed onto it by `store`, so a rejected upload never truncates or
+/// removes a file it would have replaced. Partial files still pending are removed when
+/// this is dropped, e.g. when `REQUEST_DEADLINE_SECS` aborts the handler mid-stream.
+#[derive(Default)]
+struct WrittenFiles {
+    files: Vec<PendingFile>,
+}
+
+impl WrittenFiles {
+    /// A temporary file to receive `target` in
+    fn partial_for(target: &Path) -> PathBuf {
+        let dir = target.parent().unwrap_or_else(|| Path::new(""));
+        dir.join(format!(".{}.partial", Uuid::new_v4()))
+    }
+
+    fn add(&mut self, partial: PathBuf, target: PathBuf) {
+        self.files.push(PendingFile { partial, target });
+    }
+
+    /// Store the file pending for `from` under `to` instead
+    fn retarget(&mut self, from: &Path, to: PathBuf) {
+        if let Some(file) = self.files.iter_mut().find(|file| file.target == from) {
+            file.target = to;
+        }
+    }
+
+    /// Drop the file pending for `target`, leaving whatever is stored there untouched
+    async fn discard(&mut self, target: &Path) {
+        let (discarded, pending) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition(|file| file.target == target);
+        self.files = pending;
+        let partials: Vec<PathBuf> = discarded.into_iter().map(|file| file.partial).collect();
+        remove_files(&partials).await;
+    }
+
+    /// Drop every pending file
+    async fn discard_all(&mut self) {
+        let partials: Vec<PathBuf> = self.files.drain(..).map(|file| file.partial).collect();
+        remove_files(&partials).await;
+    }
+
+    /// Move every pending file onto its target, returning the paths stored
+    async fn store(&mut self) -> std::io::Result<Vec<PathBuf>> {
+        let mut stored = Vec::with_capacity(self.files.len());
+        while let Some(file) = self.files.first() {
+            if let Err(e) = tokio::fs::rename(&file.partial, &file.target).await {
+                log::error!(
+                    "Failed to rename {} to {}: {}",
+                    file.partial.display(),
+                    file.target.display(),
+                    e
+                );
+                return Err(e);
+            }
+            stored.push(self.files.remove(0).target);
+        }
+        Ok(stored)
+    }
+
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+impl Drop for WrittenFiles {
+    fn drop(&mut self) {
+        for file in &self.files {
+            match fs::remove_file(&file.partial) {
+                Ok(()) => log::info!("Removed unfinished upload {}", file.partial.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log::error!("Failed to remove {}: {}", file.partial.display(), e),
+            }
+        }
+    }
+}
+
+/// `Cache-Control` and `Expires` values for a download response.
+///
+/// Public downloads use `CACHE_CONTROL_HEADER` so CDNs can cache them; authenticated
+/// ones use the stricter `CACHE_CONTROL_PRIVATE_HEADER`. `Expires` mirrors `max-age`.
+fn download_cache_headers(public: bool) -> (String, Option<String>) {
+    let cache_control = if public {
+        env::var("CACHE_CONTROL_HEADER").unwrap_or_else(|_| "public, max-age=86400".to_string())
+    } else {
+        env::var("CACHE_CONTROL_PRIVATE_HEADER")
+            .unwrap_or_else(|_| "private, max-age=300".to_string())
+    };
+    let expires = cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .find_map(|secs| secs.parse::<u64>().ok())
+        .map(|secs| {
+            let at = std::time::SystemTime::now() + std::time::Duration::from_secs(secs);
+            header::HttpDate::from(at).to_string()
+        });
+    (cache_control, expires)
+}
+
+/// Rejects stored-file names taken from the URL that could escape the uploads directory
+fn validate_stored_name(filename: &str) -> Result<(), actix_web::Error> {
+    if filename.is_empty()
+        || filename == "."
+        || filename == ".."
+        || filename.contains('/')
+        || filename.contains('\\')
+    {
+        return Err(actix_web::error::ErrorBadRequest("Invalid filename"));
+    }
+    Ok(())
+}
+
+/// Returns the most recent metadata entry for `filename`, enforcing that `user` owns it.
+///
+/// `None` means the route is public, so any stored file may be read.
+fn find_owned_entry<'a>(
+    entries: &'a [UploadMetadata],
+    filename: &str,
+    user: Option<&AuthenticatedUser>,
+) -> Result<&'a UploadMetadata, actix_web::Error> {
+    let entry = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.filename == filename)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+    let Some(user) = user else {
+        return Ok(entry);
+    };
+    if entry.user != user.sub {
+        log::warn!("User {} denied access to {}", user.sub, filename);
+        return Err(actix_web::error::ErrorForbidden(
+            "You do not have access to this file",
+        ));
+    }
+    Ok(entry)
+}
+
+/// How fields in one multipart request that claim the same filename are handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateFieldPolicy {
+    /// Reject the whole request with 400
+    Reject,
+    /// Store later fields as `name (1).ext`, `name (2).ext`, ...
+    Suffix,
+    /// The last field with the name wins
+    Overwrite,
+}
+
+impl DuplicateFieldPolicy {
+    /// Reads `DUPLICATE_FILENAME_POLICY`, defaulting to `suffix`
+    pub fn from_env() -> Self {
+        match env::var("DUPLICATE_FILENAME_POLICY")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "suffix" => Self::Suffix,
+            "reject" => Self::Reject,
+            "overwrite" => Self::Overwrite,
+            other => {
+                log::warn!(
+                    "Unknown DUPLICATE_FILENAME_POLICY '{}', using 'suffix'",
+                    other
+                );
+                Self::Suffix
+            }
+        }
+    }
+}
+
+/// What a multi-file upload does when one of its files is rejected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MultiUploadPolicy {
+    /// Reject the whole request and remove the files already written
+    AllOrNothing,
+    /// Keep the accepted files and report the rejected ones alongside them
+    BestEffort,
+}
+
+impl MultiUploadPolicy {
+    /// Reads `MULTI_UPLOAD_POLICY`, defaulting to `all-or-nothing`
+    pub fn from_env() -> Self {
+        match env::var("MULTI_UPLOAD_POLICY")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "all-or-nothing" => Self::AllOrNothing,
+            "best-effort" => Self::BestEffort,
+            other => {
+                log::warn!(
+                    "Unknown MULTI_UPLOAD_POLICY '{}', using 'all-or-nothing'",
+                    other
+                );
+                Self::AllOrNothing
+            }
+        }
+    }
+}
+
+/// A file rejected from a best-effort multi-file upload
+#[derive(Serialize)]
+pub struct FailedUpload {
+    /// Name the file was sent with
+    pub filename: String,
+    /// Status the file alone would have been rejected with
+    pub status_code: u16,
+    pub error: String,
+}
+
+impl FailedUpload {
+    fn new(filename: &str, error: &actix_web::Error) -> Self {
+        Self {
+            filename: filename.to_string(),
+            status_code: error.as_response_error().status_code().as_u16(),
+            error: error.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MultiUploadResponse {
+    pub status: String,
+    pub message: String,
+    pub files: Vec<UploadResponse>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<FailedUpload>,
+}
 
 #[derive(Serialize)]
 pub struct HealthResponse {
@@ -26,10 +488,177 @@ pub async fn health_check() -> ActixResult<HttpResponse> {
     Ok(HttpResponse::Ok().json(response))
 }
 
+/// Liveness probe: the process is up and serving requests
+pub async fn health_live() -> ActixResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(HealthResponse {
+        status: "alive".to_string(),
+        message: "Upload proxy service is running".to_string(),
+        timestamp: Utc::now().to_rfc3339(),
+    }))
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub status: String,
+    /// `ok` or the failure reason, per dependency
+    pub checks: BTreeMap<String, String>,
+    pub timestamp: String,
+}
+
+/// Readiness probe: 503 unless the uploads directory is writable and Keycloak's
+/// signing keys can be loaded; disk pressure under `DISK_SOFT_LIMIT_BYTES` is
+/// reported but keeps the service ready
+pub async fn health_ready(jwks_cache: web::Data<JwksCache>) -> ActixResult<HttpResponse> {
+    let mut checks = BTreeMap::new();
+
+    let probe = uploads_dir().join(".ready-probe");
+    let storage = match fs::create_dir_all(uploads_dir()).and_then(|_| fs::write(&probe, b"")) {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            "ok".to_string()
+        }
+        Err(e) => format!("uploads directory not writable: {}", e),
+    };
+    checks.insert("uploads_dir".to_string(), storage);
+
+    let jwks = match env::var("KEYCLOAK_URL") {
+        Ok(keycloak_url) => {
+            let keycloak_realm =
+                env::var("KEYCLOAK_REALM").unwrap_or_else(|_| "upload-realm".to_string());
+            let jwks_url = format!(
+                "{}/realms/{}/protocol/openid-connect/certs",
+                keycloak_url, keycloak_realm
+            );
+            match jwks_cache.get(&jwks_url, false).await {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("signing keys unavailable: {}", e),
+            }
+        }
+        Err(_) => "KEYCLOAK_URL is not set".to_string(),
+    };
+    checks.insert("jwks".to_string(), jwks);
+
+    let ready = checks.values().all(|check| check == "ok");
+    // Reported without failing readiness: downloads and listings still work
+    if disk::disk_soft_limit().is_some() {
+        let disk = disk::soft_limit_exceeded(&uploads_dir()).map_or_else(
+            || "ok".to_string(),
+            |reason| format!("uploads paused: {}", reason),
+        );
+        checks.insert("disk".to_string(), disk);
+    }
+    if !ready {
+        log::warn!("Readiness check failed: {:?}", checks);
+    }
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.json(ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" }.to_string(),
+        checks,
+        timestamp: Utc::now().to_rfc3339(),
+    }))
+}
+
+/// Default service: JSON 404 for routes that don't exist
+pub async fn not_found(req: HttpRequest) -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "error": "not_found",
+        "path": req.path(),
+    }))
+}
+
 /// File upload handler - implements the complete assignment flow
+///
+/// With `Accept: application/x-ndjson` the response is streamed instead: a `file` line
+/// as each file is written, then a `summary` line with the outcome of the whole upload.
 pub async fn upload_file(
+    payload: Multipart,
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    filename_rules: web::Data<FilenameRules>,
+    events: web::Data<EventBus>,
+    progress: web::Data<ProgressTracker>,
+    reservations: web::Data<NameReservations>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !ndjson::prefers_ndjson(req.headers()) {
+        return receive_upload(
+            payload,
+            req,
+            user,
+            filename_rules,
+            events,
+            progress,
+            reservations,
+        )
+        .await;
+    }
+
+    let (lines, receiver) = ResultLines::channel();
+    req.extensions_mut().insert(lines.clone());
+    let deadline = req.extensions().get::<RequestDeadline>().copied();
+    // The payload is received while the response streams, so files can be reported as
+    // they complete; the body drives the upload, within the request's deadline
+    let upload = async move {
+        let upload = receive_upload(
+            payload,
+            req,
+            user,
+            filename_rules,
+            events,
+            progress,
+            reservations,
+        );
+        let result = match deadline {
+            Some(RequestDeadline(expires)) => tokio::time::timeout_at(expires, upload)
+                .await
+                .unwrap_or_else(|_| {
+                    log::warn!("Streamed upload exceeded the request deadline");
+                    Err(actix_web::error::ErrorServiceUnavailable(
+                        "Request deadline exceeded",
+                    ))
+                }),
+            None => upload.await,
+        };
+        let summary = match result {
+            Ok(response) => {
+                let status_code = response.status().as_u16();
+                let body = actix_web::body::to_bytes(response.into_body())
+                    .await
+                    .unwrap_or_default();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+                ResultLine::Summary {
+                    status: "success",
+                    status_code,
+                    files: body["files"].as_array().map_or(1, Vec::len),
+                    message: body["message"].as_str().unwrap_or_default().to_string(),
+                }
+            }
+            Err(e) => ResultLine::Summary {
+                status: "error",
+                status_code: e.as_response_error().status_code().as_u16(),
+                files: 0,
+                message: e.to_string(),
+            },
+        };
+        lines.send(&summary);
+    };
+    let body = ResultLines::stream(receiver, upload);
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(body))
+}
+
+async fn receive_upload(
     mut payload: Multipart,
-    _req: HttpRequest,
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    filename_rules: web::Data<FilenameRules>,
+    events: web::Data<EventBus>,
+    progress: web::Data<ProgressTracker>,
+    reservations: web::Data<NameReservations>,
 ) -> Result<HttpResponse, actix_web::Error> {
     log::info!("=== UPLOAD HANDLER CALLED ===");
     log::info!("Starting file upload process");
@@ -37,12 +666,52 @@ pub async fn upload_file(
 
     // Step 1: Authorization Check - User is already validated by middleware
     log::info!("Step 1: User already validated by middleware");
-    let user = "authenticated_user".to_string(); // We could extract this from the request if needed
+    let tags = tags::upload_tags(&req, user.as_ref())?;
+    let user = user.map_or_else(|| ANONYMOUS_USER.to_string(), |user| user.sub);
+    check_upload_preconditions(&req)?;
+    let scope = StorageScope::for_request(&req)?;
+    let size_limit = max_upload_bytes();
+    let duplicate_policy = DuplicateFieldPolicy::from_env();
+    let multi_policy = MultiUploadPolicy::from_env();
+    let collision_policy = CollisionPolicy::from_env();
+    let buffer_limit = small_file_buffer_bytes();
+    let enforce_type = content_type::enforce_extension_match();
+    let sniff_bytes = content_type::sniff_bytes();
+    let write_timeout = disk_write_timeout();
+    let correct_extension = content_type::correct_extension();
+    let require_content_type = content_type::require_declared();
+    let reject_polyglots = content_type::reject_polyglots();
+    let expected_tree_hash = treehash::expected_tree_hash(&req)?;
+    let pipe_command = pipe::pipe_command();
+    let timestamps = req
+        .app_data::<web::Data<TimestampConfig>>()
+        .map_or_else(TimestampConfig::default, |config| *config.get_ref());
+
+    // Optional progress tracking, dropped (and cleaned up) on every exit path
+    let progress = match req.headers().get(UPLOAD_ID_HEADER) {
+        Some(value) => {
+            let upload_id = value
+                .to_str()
+                .map_err(|_| actix_web::error::ErrorBadRequest("Invalid Upload-Id"))?;
+            validate_upload_id(upload_id)?;
+            let expected_bytes = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            Some(ProgressTracker::start(
+                &progress,
+                upload_id,
+                &user,
+                expected_bytes,
+            )?)
+        }
+        None => None,
+    };
 
     // Step 2: File Processing - Prepare upload directory
     log::info!("Step 2: Preparing file storage");
-    let uploads_path = env::var("UPLOADS_DIR").unwrap_or_else(|_| "./uploads".to_string());
-    let uploads_dir = Path::new(&uploads_path);
+    let uploads_dir = scope.uploads_dir.as_path();
     if !uploads_dir.exists() {
         fs::create_dir_all(uploads_dir).map_err(|e| {
             log::error!("Failed to create uploads directory: {}", e);
@@ -52,79 +721,1648 @@ pub async fn upload_file(
             ))
         })?;
     }
+    disk::check_soft_limit(uploads_dir)?;
+    acquire_upload_token(&req)?;
 
-    let mut filename = String::new();
     let mut total_bytes = 0u64;
+    let mut fields_seen = 0usize;
+    let mut stored: Vec<UploadMetadata> = Vec::new();
+    // Files skipped under `MultiUploadPolicy::BestEffort`, and the first one's error
+    let mut failures: Vec<FailedUpload> = Vec::new();
+    let mut first_failure: Option<actix_web::Error> = None;
+    let mut written_files = WrittenFiles::default();
+    // Names this request writes to, held until its metadata is recorded
+    let mut targets: Vec<NameReservation> = Vec::new();
+
+    // Distinct filenames the user already stores; re-uploading one doesn't add a file
+    let file_limit = max_files_per_user();
+    let unique_per_user = filename_unique_per_user();
+    let (owned_files, owned_names): (HashSet<String>, HashSet<String>) =
+        if file_limit.is_some() || unique_per_user {
+            let entries = read_metadata(&scope.metadata_file)?;
+            current_files(&entries)
+                .into_iter()
+                .filter(|entry| entry.user == user)
+                .map(|entry| (entry.filename.clone(), entry.client_filename().to_string()))
+                .unzip()
+        } else {
+            (HashSet::new(), HashSet::new())
+        };
+    let mut user_files = owned_files.clone();
+
+    // When each existing file was last stored, for `If-Unmodified-Since`
+    let unmodified_since = unmodified_since(&req);
+    let stored_at: HashMap<String, DateTime<Utc>> = match unmodified_since {
+        Some(_) => {
+            let entries = read_metadata(&scope.metadata_file)?;
+            current_files(&entries)
+                .into_iter()
+                .filter_map(|entry| {
+                    let at = DateTime::parse_from_rfc3339(&entry.timestamp).ok()?;
+                    Some((entry.filename.clone(), at.with_timezone(&Utc)))
+                })
+                .collect()
+        }
+        None => HashMap::new(),
+    };
 
     // Step 3: Stream multipart upload and write directly to disk
     log::info!("Step 3: Processing multipart upload stream");
     while let Some(item) = payload.next().await {
-        let mut field = item.map_err(|e| {
-            log::error!("Failed to read multipart field: {}", e);
-            actix_web::error::ErrorBadRequest(format!("Invalid multipart data: {}", e))
-        })?;
+        let mut field = match item {
+            Ok(field) => field,
+            // A body with no parts ends before any part headers can be read
+            Err(MultipartError::Incomplete) if fields_seen == 0 => break,
+            Err(e) => {
+                log::error!("Failed to read multipart field: {}", e);
+                return Err(actix_web::error::ErrorBadRequest(format!(
+                    "Invalid multipart data: {}",
+                    e
+                )));
+            }
+        };
+        fields_seen += 1;
 
-        // Extract filename from Content-Disposition header
-        filename = field
-            .content_disposition()
-            .and_then(|cd| cd.get_filename())
-            .map(|f| f.to_string())
-            .unwrap_or_else(|| format!("file_{}", Utc::now().timestamp()));
+        // Name the part was sent with, to report it if it is rejected
+        let mut field_name = String::new();
+        // Where this part is being written, once a file has been created for it
+        let mut field_path: Option<PathBuf> = None;
+        let outcome: Result<UploadMetadata, actix_web::Error> = 'field: {
+            let declared_type = field
+                .content_type()
+                .map(|mime| mime.essence_str().to_string());
+            if require_content_type && declared_type.is_none() {
+                log::warn!("Rejecting file part without a Content-Type");
+                break 'field Err(actix_web::error::ErrorBadRequest(
+                    "File part is missing a Content-Type",
+                ));
+            }
 
-        log::info!("Processing file: {}", filename);
-        let filepath = uploads_dir.join(&filename);
+            // Extract filename from Content-Disposition header
+            let mut filename = match field.content_disposition().and_then(|cd| cd.get_filename()) {
+                Some(raw) => {
+                    field_name = raw.to_string();
+                    match sanitize_filename(raw) {
+                        Some(filename) => filename,
+                        None => {
+                            log::warn!("Rejected unusable filename: {:?}", raw);
+                            break 'field Err(actix_web::error::ErrorBadRequest(
+                                "Invalid filename",
+                            ));
+                        }
+                    }
+                }
+                None => format!("file_{}", Utc::now().timestamp()),
+            };
+            if field_name.is_empty() {
+                field_name = filename.clone();
+            }
 
-        // Create file and stream data directly to disk
-        let mut file = tokio::fs::File::create(&filepath).await.map_err(|e| {
-            log::error!("Failed to create file {}: {}", filepath.display(), e);
-            actix_web::error::ErrorInternalServerError(format!("Failed to create file: {}", e))
-        })?;
+            log::info!("Processing file: {}", filename);
+            if let Err(e) = filename_rules.validate(&filename) {
+                log::warn!("Rejected upload: {}", e);
+                break 'field Err(actix_web::error::ErrorBadRequest(e));
+            }
 
-        // Stream file chunks directly to disk
-        while let Some(chunk) = field.next().await {
-            let data = chunk.map_err(|e| {
-                log::error!("Failed to read chunk: {}", e);
-                actix_web::error::ErrorBadRequest(format!("Failed to read file data: {}", e))
-            })?;
+            // The name as sent, kept in metadata when the stored name differs
+            let client_filename = filename.clone();
+            let name_strategy = &filename_rules.name_strategy;
+            let deferred_name = name_strategy.uses_checksum();
+            filename = if deferred_name {
+                // Received under a placeholder until the content, and so the name, is known
+                format!(".{}.partial", Uuid::new_v4())
+            } else {
+                name_strategy.generate(&filename, &user, "")
+            };
+
+            // Several fields in this request may claim the same filename
+            if stored.iter().any(|file| file.filename == filename) {
+                match duplicate_policy {
+                    DuplicateFieldPolicy::Reject => {
+                        log::warn!("Rejecting request with duplicate filename {}", filename);
+                        break 'field Err(actix_web::error::ErrorBadRequest(format!(
+                            "Duplicate filename in request: {}",
+                            filename
+                        )));
+                    }
+                    DuplicateFieldPolicy::Suffix => {
+                        let taken: HashSet<&str> =
+                            stored.iter().map(|file| file.filename.as_str()).collect();
+                        let renamed = (1..)
+                            .map(|n| suffixed_filename(&filename, n))
+                            .find(|candidate| !taken.contains(candidate.as_str()))
+                            .unwrap_or_default();
+                        log::info!("Duplicate filename {} stored as {}", filename, renamed);
+                        filename = renamed;
+                    }
+                    DuplicateFieldPolicy::Overwrite => {
+                        log::info!("Duplicate filename {} overwrites earlier field", filename);
+                        stored.retain(|file| file.filename != filename);
+                    }
+                }
+            }
+
+            // HTTP dates have whole-second precision, so compare in seconds
+            if let (Some(since), Some(at)) = (unmodified_since, stored_at.get(&filename)) {
+                if at.timestamp() > since.timestamp() {
+                    log::warn!("{} changed at {} after If-Unmodified-Since", filename, at);
+                    break 'field Err(actix_web::error::ErrorPreconditionFailed(format!(
+                        "{} has been modified since {}",
+                        filename,
+                        since.to_rfc2822()
+                    )));
+                }
+            }
+
+            // Compared by the names the client sent, since generated names never repeat
+            if unique_per_user && owned_names.contains(&client_filename) {
+                log::warn!("User {} already has a file named {}", user, client_filename);
+                break 'field Err(actix_web::error::ErrorConflict(format!(
+                    "You already have a file named {}",
+                    client_filename
+                )));
+            }
 
-            total_bytes += data.len() as u64;
-            file.write_all(&data).await.map_err(|e| {
-                log::error!("Failed to write chunk to file: {}", e);
-                actix_web::error::ErrorInternalServerError(format!("Failed to write file: {}", e))
+            // Collisions with stored files and other in-flight uploads
+            if !targets.iter().any(|target| target.name() == filename) {
+                let target = match NameReservations::resolve_target(
+                    &reservations,
+                    uploads_dir,
+                    &filename,
+                    collision_policy,
+                ) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        log::warn!("Rejected upload of {}: {}", filename, e);
+                        break 'field Err(e);
+                    }
+                };
+                if target.name() != filename {
+                    log::info!("{} exists, storing as {}", filename, target.name());
+                    filename = target.name().to_string();
+                }
+                targets.push(target);
+            }
+
+            if let Some(limit) = file_limit {
+                if !user_files.contains(&filename) && user_files.len() >= limit {
+                    log::warn!("User {} reached the limit of {} files", user, limit);
+                    break 'field Err(payload_too_large(
+                        "files_per_user",
+                        limit as u64,
+                        format!("File limit reached: at most {} files per user", limit),
+                    ));
+                }
+                user_files.insert(filename.clone());
+            }
+            let filepath = uploads_dir.join(&filename);
+            // An earlier field with this name is replaced by this one
+            written_files.discard(&filepath).await;
+
+            // Create file and stream data directly to disk, under a temporary name until
+            // the whole upload has been accepted
+            let partial = WrittenFiles::partial_for(&filepath);
+            let file = tokio::fs::File::create(&partial).await.map_err(|e| {
+                log::error!("Failed to create file {}: {}", partial.display(), e);
+                actix_web::error::ErrorInternalServerError(format!("Failed to create file: {}", e))
             })?;
-        }
+            field_path = Some(filepath.clone());
+            written_files.add(partial, filepath);
+            // With a pipe command the upload goes to its stdin and its stdout is stored
+            let (mut sink, mut pipe_output): (Box<dyn AsyncWrite + Unpin>, _) = match &pipe_command
+            {
+                Some(command) => match pipe::spawn(command, file, sniff_bytes) {
+                    Ok((stdin, output)) => (Box::new(stdin), Some(output)),
+                    Err(e) => {
+                        log::error!("Failed to start pipe command: {}", e);
+                        written_files.discard_all().await;
+                        return Err(actix_web::error::ErrorInternalServerError(
+                            "Failed to start upload processing",
+                        ));
+                    }
+                },
+                None => (Box::new(file), None),
+            };
+            let mut hasher = Sha256::new();
+            let mut file_bytes = 0u64;
+            let mut tree_hasher = expected_tree_hash.as_ref().map(|_| TreeHasher::default());
+            // Leading bytes kept for content type detection
+            let mut head: Vec<u8> = Vec::new();
+            let mut type_checked = !enforce_type;
+            // Holds small files until they outgrow the buffer limit
+            let mut buffered: Option<Vec<u8>> = (buffer_limit > 0).then(Vec::new);
 
-        // Ensure data is written to disk
-        file.flush().await.map_err(|e| {
-            log::error!("Failed to flush file: {}", e);
-            actix_web::error::ErrorInternalServerError(format!("Failed to flush file: {}", e))
-        })?;
+            // Stream file chunks directly to disk
+            while let Some(chunk) = field.next().await {
+                let data = chunk.map_err(|e| {
+                    log::error!("Failed to read chunk: {}", e);
+                    actix_web::error::ErrorBadRequest(format!("Failed to read file data: {}", e))
+                })?;
+
+                file_bytes += data.len() as u64;
+                total_bytes += data.len() as u64;
+                if let Some(limit) = size_limit.filter(|&limit| total_bytes > limit) {
+                    log::warn!("Upload exceeded {} bytes, removing partial files", limit);
+                    drop(sink);
+                    written_files.discard_all().await;
+                    return Err(payload_too_large(
+                        "request_bytes",
+                        limit,
+                        format!("Upload exceeds the maximum size of {} bytes", limit),
+                    ));
+                }
+                hasher.update(&data);
+                if let Some(tree_hasher) = tree_hasher.as_mut() {
+                    tree_hasher.update(&data);
+                }
+                if head.len() < sniff_bytes {
+                    let wanted = sniff_bytes - head.len();
+                    head.extend_from_slice(&data[..data.len().min(wanted)]);
+                }
+                if !type_checked && head.len() >= sniff_bytes {
+                    type_checked = true;
+                    if let Some(e) = type_mismatch(&client_filename, &head) {
+                        break 'field Err(e);
+                    }
+                }
+                if let Some(progress) = &progress {
+                    progress.add_bytes(data.len() as u64);
+                }
+                let written = match buffered.as_mut() {
+                    Some(buffer) => {
+                        buffer.extend_from_slice(&data);
+                        if file_bytes > buffer_limit {
+                            // Too large to buffer: write what we have and stream the rest
+                            let buffer = buffered.take().unwrap_or_default();
+                            write_chunk(&mut sink, &buffer, write_timeout).await
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    None => write_chunk(&mut sink, &data, write_timeout).await,
+                };
+                if let Err(e) = written {
+                    drop(sink);
+                    let e = pipe_write_error(pipe_output.take(), &filename, e).await;
+                    written_files.discard_all().await;
+                    return Err(e);
+                }
+            }
+            if !type_checked {
+                if let Some(e) = type_mismatch(&client_filename, &head) {
+                    break 'field Err(e);
+                }
+            }
+            let tree_hash = tree_hasher.map(TreeHasher::finalize);
+            if let (Some(expected), Some(actual)) = (&expected_tree_hash, &tree_hash) {
+                if expected != actual {
+                    log::warn!(
+                        "Tree hash mismatch for {}: expected {}, got {}",
+                        client_filename,
+                        expected,
+                        actual
+                    );
+                    break 'field Err(actix_web::error::ErrorUnprocessableEntity(format!(
+                        "{} does not match the uploaded content",
+                        TREE_HASH_HEADER
+                    )));
+                }
+            }
+            if reject_polyglots {
+                if let Some(reason) = content_type::polyglot_signature(&head) {
+                    log::warn!("Rejecting polyglot upload {}: {}", client_filename, reason);
+                    break 'field Err(actix_web::error::ErrorUnprocessableEntity(format!(
+                        "File is valid as more than one type: {}",
+                        reason
+                    )));
+                }
+            }
+            let mut written = match buffered {
+                Some(buffer) => write_chunk(&mut sink, &buffer, write_timeout).await,
+                None => Ok(()),
+            };
+            // Ensure data is written to disk
+            if written.is_ok() {
+                written = flush_file(&mut sink, write_timeout).await;
+            }
+            if let Err(e) = written {
+                drop(sink);
+                let e = pipe_write_error(pipe_output.take(), &filename, e).await;
+                written_files.discard_all().await;
+                return Err(e);
+            }
+            drop(sink);
+            let mut checksum = hex::encode(hasher.finalize());
+            if let Some(output) = pipe_output {
+                match output.finish().await {
+                    Ok(piped) => {
+                        log::info!(
+                            "Pipe command turned {} bytes of {} into {}",
+                            file_bytes,
+                            filename,
+                            piped.size_bytes
+                        );
+                        file_bytes = piped.size_bytes;
+                        checksum = piped.checksum;
+                        head = piped.head;
+                    }
+                    Err(reason) => break 'field Err(pipe_rejected(&filename, &reason)),
+                }
+            }
+
+            // The content is only known now, so a name derived from it, or a misnamed file,
+            // is renamed after writing
+            let mut final_name = if deferred_name {
+                name_strategy.generate(&client_filename, &user, &checksum)
+            } else {
+                filename.clone()
+            };
+            // Judged by the name the client sent: a generated name may not carry its
+            // extension (or be a placeholder), but the canonical one is applied to it
+            if correct_extension
+                && content_type::corrected_filename(&client_filename, &head).is_some()
+            {
+                if let Some(corrected) = content_type::corrected_filename(&final_name, &head) {
+                    final_name = corrected;
+                }
+            }
+            if final_name != filename {
+                let target = match NameReservations::resolve_target(
+                    &reservations,
+                    uploads_dir,
+                    &final_name,
+                    collision_policy,
+                ) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        log::warn!("Rejected upload of {}: {}", final_name, e);
+                        break 'field Err(e);
+                    }
+                };
+                let from = uploads_dir.join(&filename);
+                let to = uploads_dir.join(target.name());
+                log::info!("Storing {} as {}", filename, target.name());
+                written_files.retarget(&from, to.clone());
+                field_path = Some(to);
+                if file_limit.is_some() {
+                    if !owned_files.contains(&filename) {
+                        user_files.remove(&filename);
+                    }
+                    user_files.insert(target.name().to_string());
+                }
+                filename = target.name().to_string();
+                targets.push(target);
+            }
+
+            log::info!("File upload completed: {} ({} bytes)", filename, file_bytes);
+            let mut metadata = UploadMetadata::new(filename, user.clone(), file_bytes);
+            metadata.original_filename =
+                (metadata.filename != client_filename).then_some(client_filename);
+            metadata.checksum = Some(checksum);
+            metadata.tree_hash = tree_hash;
+            metadata.tags = tags.clone();
+            metadata.content_type = content_type::detect(&head, declared_type.as_deref());
+            metadata.storage = Some(StorageLocation::local(
+                &uploads_dir.join(&metadata.filename),
+            ));
+            Ok(metadata)
+        };
+
+        let metadata = match outcome {
+            Ok(metadata) => metadata,
+            // The rest of this part is skipped when the next one is read
+            Err(e) if multi_policy == MultiUploadPolicy::BestEffort => {
+                log::warn!("Skipping {} in best-effort upload: {}", field_name, e);
+                if let Some(path) = field_path {
+                    written_files.discard(&path).await;
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    if !owned_files.contains(name.as_ref())
+                        && !stored.iter().any(|file| file.filename == name)
+                    {
+                        user_files.remove(name.as_ref());
+                    }
+                }
+                if let Some(lines) = req.extensions().get::<ResultLines>() {
+                    lines.send(&ResultLine::Failed(FailedUpload::new(&field_name, &e)));
+                }
+                failures.push(FailedUpload::new(&field_name, &e));
+                first_failure.get_or_insert(e);
+                continue;
+            }
+            Err(e) => {
+                written_files.discard_all().await;
+                return Err(e);
+            }
+        };
+        if let Some(lines) = req.extensions().get::<ResultLines>() {
+            lines.send(&ResultLine::File(create_upload_response(
+                &metadata,
+                timestamps.format,
+            )));
+        }
+        stored.push(metadata);
     }
 
-    if filename.is_empty() {
+    if fields_seen == 0 {
+        log::warn!("Multipart request contained no parts");
+        return Err(actix_web::error::ErrorBadRequest("No file part found"));
+    }
+    // A best-effort upload whose every file was rejected fails like its first file
+    if let Some(e) = first_failure.filter(|_| stored.is_empty()) {
+        return Err(e);
+    }
+    if stored.is_empty() {
         log::error!("No file was uploaded");
         return Err(actix_web::error::ErrorBadRequest("No file uploaded"));
     }
 
+    // Step 4: Metadata Logging - Create and append metadata entries
+    log::info!("Step 4: Logging upload metadata");
+    let mut warning = None;
+    if let Err(e) = log_upload_metadata(&stored, &scope.metadata_file).await {
+        match MetadataFailurePolicy::from_env() {
+            MetadataFailurePolicy::Fail => {
+                // The files are still stored; only a rollback discards them
+                let _ = written_files.store().await;
+                return Err(e);
+            }
+            MetadataFailurePolicy::Rollback => {
+                log::warn!(
+                    "Rolling back {} file(s) after metadata failure",
+                    written_files.len()
+                );
+                written_files.discard_all().await;
+                return Err(e);
+            }
+            MetadataFailurePolicy::Keep => {
+                log::warn!("Keeping {} file(s) without metadata: {}", stored.len(), e);
+                warning = Some(format!(
+                    "File stored but metadata could not be recorded: {}",
+                    e
+                ));
+            }
+        }
+    }
+    let stored_paths = written_files.store().await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(format!("Failed to store file: {}", e))
+    })?;
+    if let Some(cache) = req.app_data::<web::Data<DownloadCache>>() {
+        for path in &stored_paths {
+            cache.invalidate(path);
+        }
+    }
+
     log::info!(
-        "File upload completed: {} ({} bytes)",
-        filename,
-        total_bytes
+        "Upload process completed successfully for {} file(s)",
+        stored.len()
     );
+    for metadata in &stored {
+        events.publish(FileEvent::uploaded(metadata, timestamps.format));
+        hooks::spawn_post_upload_hook(metadata, &scope.metadata_file);
+    }
 
-    // Step 4: Metadata Logging - Create and append metadata entry
-    log::info!("Step 4: Logging upload metadata");
-    let metadata_file = env::var("METADATA_FILE").unwrap_or_else(|_| "./uploads.json".to_string());
-    log_upload_metadata(filename.clone(), user.clone(), total_bytes, &metadata_file)?;
+    // Return success response with file details
+    let mut responses: Vec<UploadResponse> = stored
+        .iter()
+        .map(|metadata| {
+            let mut response = create_upload_response(metadata, timestamps.format);
+            response.warning = warning.clone();
+            response.receipt = receipts::issue_receipt(metadata);
+            response
+        })
+        .collect();
+    let mut response = if failures.is_empty() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::MultiStatus()
+    };
+    if let Some(limit) = file_limit {
+        let used = user_files.len();
+        response
+            .insert_header(("X-Quota-Used", used.to_string()))
+            .insert_header(("X-Quota-Limit", limit.to_string()));
+        if used as u64 * 100 >= limit as u64 * quota_warn_percent() {
+            response.insert_header(("X-Quota-Warning", "true"));
+        }
+    }
+    let xml = xml::prefers_xml(req.headers());
+    if responses.len() == 1 && failures.is_empty() {
+        return upload_body(response, xml, responses.remove(0));
+    }
+    let (status, message) = if failures.is_empty() {
+        (
+            "success",
+            format!("{} files uploaded successfully", responses.len()),
+        )
+    } else {
+        (
+            "partial",
+            format!(
+                "{} files uploaded, {} rejected",
+                responses.len(),
+                failures.len()
+            ),
+        )
+    };
+    upload_body(
+        response,
+        xml,
+        MultiUploadResponse {
+            status: status.to_string(),
+            message,
+            files: responses,
+            failed: failures,
+        },
+    )
+}
+
+/// Upload response body: JSON, or `<upload>` XML for clients that ask for it
+fn upload_body(
+    mut response: actix_web::HttpResponseBuilder,
+    xml: bool,
+    body: impl Serialize,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !xml {
+        return Ok(response.json(body));
+    }
+    let body = xml::to_xml("upload", &body).map_err(|e| {
+        log::error!("Failed to serialize upload response: {}", e);
+        actix_web::error::ErrorInternalServerError("Failed to serialize response")
+    })?;
+    Ok(response
+        .content_type("application/xml; charset=utf-8")
+        .body(body))
+}
+
+#[derive(Deserialize)]
+pub struct PreflightRequest {
+    pub filename: String,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct PreflightResponse {
+    pub ok: bool,
+    pub issues: Vec<String>,
+    /// Name the file would be stored under, when it could be determined
+    pub final_filename: Option<String>,
+}
+
+/// Checks whether an upload of the described file would be accepted, reporting every
+/// rule it breaks. Nothing is stored or reserved, so a later upload may still lose a
+/// race for the name; checks that need the content (type sniffing) happen on upload.
+pub async fn upload_preflight(
+    body: web::Json<PreflightRequest>,
+    user: Option<AuthenticatedUser>,
+    filename_rules: web::Data<FilenameRules>,
+    reservations: web::Data<NameReservations>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let user = user.map_or_else(|| ANONYMOUS_USER.to_string(), |user| user.sub);
+    let mut issues = Vec::new();
+
+    if let Some(limit) = max_upload_bytes() {
+        if body.size.is_some_and(|size| size > limit) {
+            issues.push(format!(
+                "Upload exceeds the maximum size of {} bytes",
+                limit
+            ));
+        }
+    }
+    if let Some(declared) = &body.content_type {
+        if declared.parse::<actix_web::mime::Mime>().is_err() {
+            issues.push(format!("Invalid content type {}", declared));
+        }
+    }
+
+    let Some(filename) = sanitize_filename(&body.filename) else {
+        issues.push("Invalid filename".to_string());
+        return Ok(HttpResponse::Ok().json(PreflightResponse {
+            ok: false,
+            issues,
+            final_filename: None,
+        }));
+    };
+    if let Err(e) = filename_rules.validate(&filename) {
+        issues.push(e);
+    }
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let owned: Vec<&UploadMetadata> = current_files(&entries)
+        .into_iter()
+        .filter(|entry| entry.user == user)
+        .collect();
+    let owned_files: HashSet<&str> = owned.iter().map(|entry| entry.filename.as_str()).collect();
+    if filename_unique_per_user()
+        && owned
+            .iter()
+            .any(|entry| entry.client_filename() == filename)
+    {
+        issues.push(format!("You already have a file named {}", filename));
+    }
+
+    // Generated names (uuid, timestamped, hashed) can't be predicted or collide
+    let strategy = &filename_rules.name_strategy;
+    let keeps_name =
+        !strategy.uses_checksum() && strategy.generate(&filename, &user, "") == filename;
+    let final_filename = if !keeps_name {
+        None
+    } else {
+        match reservations.preview_target(
+            &scope.uploads_dir,
+            &filename,
+            CollisionPolicy::from_env(),
+        ) {
+            Ok(name) => Some(name),
+            Err(e) => {
+                issues.push(e.to_string());
+                None
+            }
+        }
+    };
+    if let (Some(limit), Some(name)) = (max_files_per_user(), &final_filename) {
+        if !owned_files.contains(name.as_str()) && owned_files.len() >= limit {
+            issues.push(format!(
+                "File limit reached: at most {} files per user",
+                limit
+            ));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(PreflightResponse {
+        ok: issues.is_empty(),
+        issues,
+        final_filename,
+    }))
+}
+
+/// 422 error when `filename`'s extension contradicts the type sniffed from `head`;
+/// checked against the client's name, since the stored one may be generated
+fn type_mismatch(filename: &str, head: &[u8]) -> Option<actix_web::Error> {
+    let reason = content_type::check_extension_match(filename, head).err()?;
+    log::warn!("Rejected upload {}: {}", filename, reason);
+    Some(actix_web::error::ErrorUnprocessableEntity(reason))
+}
+
+/// How long a single disk write or flush may take, from `DISK_WRITE_TIMEOUT_SECS`;
+/// unset or 0 waits indefinitely
+fn disk_write_timeout() -> Option<Duration> {
+    env::var("DISK_WRITE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+        .map(Duration::from_secs)
+}
+
+/// Runs a disk operation, failing with 503 if it outlives `timeout` so a stalled
+/// volume doesn't hold the request forever
+async fn with_disk_timeout(
+    operation: impl std::future::Future<Output = std::io::Result<()>>,
+    timeout: Option<Duration>,
+    action: &str,
+) -> Result<(), actix_web::Error> {
+    let result = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, operation).await {
+            Ok(result) => result,
+            Err(_) => {
+                log::error!("Timed out after {:?} trying to {}", timeout, action);
+                return Err(actix_web::error::ErrorServiceUnavailable(format!(
+                    "Timed out trying to {}",
+                    action
+                )));
+            }
+        },
+        None => operation.await,
+    };
+    result.map_err(|e| {
+        log::error!("Failed to {}: {}", action, e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to {}: {}", action, e))
+    })
+}
+
+async fn write_chunk(
+    file: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    timeout: Option<Duration>,
+) -> Result<(), actix_web::Error> {
+    with_disk_timeout(file.write_all(data), timeout, "write file").await
+}
+
+async fn flush_file(
+    file: &mut (impl AsyncWrite + Unpin),
+    timeout: Option<Duration>,
+) -> Result<(), actix_web::Error> {
+    with_disk_timeout(file.flush(), timeout, "flush file").await
+}
+
+fn pipe_rejected(filename: &str, reason: &str) -> actix_web::Error {
+    log::warn!("Pipe command rejected {}: {}", filename, reason);
+    actix_web::error::ErrorUnprocessableEntity(format!(
+        "Upload rejected by processing command: {}",
+        reason
+    ))
+}
+
+/// A write to the pipe command usually fails because the command exited, so its exit
+/// status explains the failure better than the broken pipe
+async fn pipe_write_error(
+    output: Option<PipeOutput>,
+    filename: &str,
+    error: actix_web::Error,
+) -> actix_web::Error {
+    match output {
+        Some(output) => match output.finish().await {
+            Err(reason) => pipe_rejected(filename, &reason),
+            Ok(_) => error,
+        },
+        None => error,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Exact MIME type or wildcard such as `image/*`
+    pub content_type: Option<String>,
+    /// Page size; defaults to `LIST_DEFAULT_PER_PAGE`, and without either every
+    /// matching file is returned
+    pub limit: Option<usize>,
+    /// `next_cursor` from the previous page
+    pub cursor: Option<String>,
+}
+
+/// Page sizes for `GET /api/files`, loaded once at startup
+#[derive(Debug, Clone, Copy)]
+pub struct ListPaging {
+    /// Page size when the request has no `limit`, from `LIST_DEFAULT_PER_PAGE`
+    pub default_per_page: Option<usize>,
+    /// Largest page returned, from `LIST_MAX_PER_PAGE`; larger requests are clamped
+    pub max_per_page: usize,
+}
+
+impl Default for ListPaging {
+    fn default() -> Self {
+        Self {
+            default_per_page: None,
+            max_per_page: 1000,
+        }
+    }
+}
+
+impl ListPaging {
+    /// Reads `LIST_DEFAULT_PER_PAGE` and `LIST_MAX_PER_PAGE`, failing on values that
+    /// aren't positive integers or a default above the maximum
+    pub fn from_env() -> Result<Self, String> {
+        let read = |var: &str| -> Result<Option<usize>, String> {
+            match env::var(var) {
+                Ok(value) if !value.trim().is_empty() => value
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&v| v > 0)
+                    .map(Some)
+                    .ok_or_else(|| {
+                        format!("Invalid {} '{}', expected a positive integer", var, value)
+                    }),
+                _ => Ok(None),
+            }
+        };
+        let paging = Self {
+            default_per_page: read("LIST_DEFAULT_PER_PAGE")?,
+            max_per_page: read("LIST_MAX_PER_PAGE")?.unwrap_or(Self::default().max_per_page),
+        };
+        if let Some(default) = paging.default_per_page.filter(|&d| d > paging.max_per_page) {
+            return Err(format!(
+                "LIST_DEFAULT_PER_PAGE {} exceeds LIST_MAX_PER_PAGE {}",
+                default, paging.max_per_page
+            ));
+        }
+        Ok(paging)
+    }
+}
+
+/// Set on listings whose requested `limit` was reduced to `LIST_MAX_PER_PAGE`
+const PAGE_CLAMPED_HEADER: &str = "X-Page-Size-Clamped";
+
+/// Position in the newest-first listing, encoded opaquely as hex of `timestamp\nfilename`
+struct ListCursor {
+    timestamp: String,
+    filename: String,
+}
+
+impl ListCursor {
+    fn after(entry: &UploadMetadata) -> Self {
+        Self {
+            timestamp: entry.timestamp.clone(),
+            filename: entry.filename.clone(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        hex::encode(format!("{}\n{}", self.timestamp, self.filename))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, actix_web::Error> {
+        let invalid = || actix_web::error::ErrorBadRequest("Invalid cursor");
+        let decoded = hex::decode(cursor).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (timestamp, filename) = decoded.split_once('\n').ok_or_else(invalid)?;
+        Ok(Self {
+            timestamp: timestamp.to_string(),
+            filename: filename.to_string(),
+        })
+    }
+
+    /// Whether `entry` sorts after this position: older, or as old with a later name
+    fn precedes(&self, entry: &UploadMetadata) -> bool {
+        (entry.timestamp.as_str(), self.filename.as_str())
+            < (self.timestamp.as_str(), entry.filename.as_str())
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileSummary {
+    pub filename: String,
+    pub user: String,
+    pub size_bytes: u64,
+    pub timestamp: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub download_count: u64,
+}
+
+impl FileSummary {
+    fn new(entry: &UploadMetadata, format: TimestampFormat) -> Self {
+        Self {
+            filename: entry.filename.clone(),
+            user: entry.user.clone(),
+            size_bytes: entry.size_bytes,
+            timestamp: format.render(&entry.timestamp),
+            checksum: entry.checksum.clone(),
+            content_type: entry.content_type.clone(),
+            download_count: entry.download_count,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileListResponse {
+    pub files: Vec<FileSummary>,
+    /// Cursor for the next page, present when `limit` cut the listing short
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Lists the caller's current files, newest first, or every file when listing is public.
+///
+/// With `limit`, returns one page and a `next_cursor`; new uploads sort before the
+/// cursor, so paging forward never repeats or skips a file. A `limit` above
+/// `LIST_MAX_PER_PAGE` is clamped and flagged with `X-Page-Size-Clamped`.
+pub async fn list_files(
+    query: web::Query<ListQuery>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+    paging: web::Data<ListPaging>,
+    timestamps: web::Data<TimestampConfig>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(ListCursor::decode)
+        .transpose()?;
+    let entries = read_metadata(&scope.metadata_file)?;
+    let mut files: Vec<&UploadMetadata> = current_files(&entries)
+        .into_iter()
+        .filter(|entry| user.as_ref().is_none_or(|user| entry.user == user.sub))
+        .filter(|entry| match &query.content_type {
+            // Entries recorded before content types were stored never match a filter
+            Some(filter) => entry
+                .content_type
+                .as_deref()
+                .is_some_and(|content_type| content_type::matches_filter(content_type, filter)),
+            None => true,
+        })
+        .filter(|entry| cursor.as_ref().is_none_or(|cursor| cursor.precedes(entry)))
+        .collect();
+    // Ties on timestamp are broken by name so pages are stable
+    files.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| a.filename.cmp(&b.filename))
+    });
+
+    let mut response = HttpResponse::Ok();
+    let mut next_cursor = None;
+    if let Some(requested) = query.limit.or(paging.default_per_page) {
+        let limit = requested.clamp(1, paging.max_per_page);
+        if requested > paging.max_per_page {
+            response.insert_header((PAGE_CLAMPED_HEADER, limit.to_string()));
+        }
+        if files.len() > limit {
+            files.truncate(limit);
+            next_cursor = files.last().map(|entry| ListCursor::after(entry).encode());
+        }
+    }
+
+    Ok(response.json(FileListResponse {
+        files: files
+            .into_iter()
+            .map(|entry| FileSummary::new(entry, timestamps.format))
+            .collect(),
+        next_cursor,
+    }))
+}
+
+/// Streams a stored file to its owner, or to anyone when downloads are public,
+/// honoring `Range` and conditional requests
+pub async fn download_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filename = path.into_inner();
+    validate_stored_name(&filename)?;
+    let entries = read_metadata(&scope.metadata_file)?;
+    let entry = find_owned_entry(&entries, &filename, user.as_ref())?;
+    let query = web::Query::<DownloadQuery>::from_query(req.query_string())
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid download parameters"))?;
+    if let Some(recipient) = &query.encrypt_to {
+        if query.transform.is_some() {
+            return Err(actix_web::error::ErrorBadRequest(
+                "encrypt_to can't be combined with transform",
+            ));
+        }
+        let response = serve_encrypted(&scope, entry, recipient).await?;
+        record_download(&scope, entry);
+        return Ok(response);
+    }
+    if let Some(transforms) = &query.transform {
+        let response = serve_transformed(&scope, entry, transforms).await?;
+        record_download(&scope, entry);
+        return Ok(response);
+    }
+    let content_type = corrected_content_type(&scope, entry).await?;
+
+    match &user {
+        Some(user) => log::info!("Serving {} to {}", filename, user.sub),
+        None => log::info!("Serving public download {}", filename),
+    }
+    let response = serve_stored_file(&req, &scope, &filename, content_type, user.is_none()).await?;
+    record_download(&scope, entry);
+    Ok(response)
+}
+
+/// Serves a stored file by its SHA-256, from any current file the caller owns
+pub async fn download_by_checksum(
+    req: HttpRequest,
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let digest = path.into_inner().to_lowercase();
+    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Expected a hex-encoded SHA-256 digest",
+        ));
+    }
+
+    // Only files whose latest upload has this digest still hold that content on disk
+    let entries = read_metadata(&scope.metadata_file)?;
+    let matching: Vec<&UploadMetadata> = current_files(&entries)
+        .into_iter()
+        .filter(|entry| entry.checksum.as_deref() == Some(digest.as_str()))
+        .collect();
+    let entry = match &user {
+        Some(user) => matching.into_iter().find(|entry| entry.user == user.sub),
+        None => matching.into_iter().next(),
+    }
+    .ok_or_else(|| actix_web::error::ErrorNotFound("No file with that checksum"))?;
+
+    let content_type = corrected_content_type(&scope, entry).await?;
+
+    log::info!("Serving {} by checksum {}", entry.filename, digest);
+    let response =
+        serve_stored_file(&req, &scope, &entry.filename, content_type, user.is_none()).await?;
+    record_download(&scope, entry);
+    Ok(response)
+}
+
+/// Whether serving a file increments its `download_count`, from `TRACK_DOWNLOADS`
+fn track_downloads() -> bool {
+    env::var("TRACK_DOWNLOADS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Counts a download of `entry`; a failed metadata write is logged, not returned,
+/// since the file is already being served
+fn record_download(scope: &StorageScope, entry: &UploadMetadata) {
+    if !track_downloads() {
+        return;
+    }
+    let counted =
+        update_metadata(&scope.metadata_file, |entries| {
+            if let Some(stored) = entries.iter_mut().rev().find(|stored| {
+                stored.filename == entry.filename && stored.timestamp == entry.timestamp
+            }) {
+                stored.download_count += 1;
+            }
+        });
+    if let Err(e) = counted {
+        log::warn!("Failed to count download of {}: {}", entry.filename, e);
+    }
+}
+
+/// Type sniffed from a stored file whose recorded type is missing or generic, when
+/// `CORRECT_CONTENT_TYPE_ON_DOWNLOAD` is on. The result is backfilled into metadata,
+/// so each file is only sniffed once.
+async fn corrected_content_type(
+    scope: &StorageScope,
+    entry: &UploadMetadata,
+) -> Result<Option<String>, actix_web::Error> {
+    if !content_type::correct_on_download()
+        || !content_type::needs_correction(entry.content_type.as_deref())
+    {
+        return Ok(None);
+    }
+
+    let filepath = scope.uploads_dir.join(&entry.filename);
+    let mut file = tokio::fs::File::open(&filepath).await.map_err(|e| {
+        log::error!("Failed to open {}: {}", filepath.display(), e);
+        actix_web::error::ErrorNotFound("File not found on disk")
+    })?;
+    let mut head = Vec::new();
+    (&mut file)
+        .take(content_type::sniff_bytes() as u64)
+        .read_to_end(&mut head)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to read {}: {}", filepath.display(), e);
+            actix_web::error::ErrorInternalServerError(format!("Failed to read file: {}", e))
+        })?;
+    let Some(detected) = content_type::detect(&head, None) else {
+        return Ok(None);
+    };
 
     log::info!(
-        "Upload process completed successfully for file: {}",
-        filename
+        "Corrected content type of {} to {}",
+        entry.filename,
+        detected
     );
+    update_metadata(&scope.metadata_file, |entries| {
+        if let Some(stored) = entries
+            .iter_mut()
+            .rev()
+            .find(|stored| stored.filename == entry.filename && stored.timestamp == entry.timestamp)
+        {
+            stored.content_type = Some(detected.clone());
+        }
+    })?;
+    Ok(Some(detected))
+}
 
-    // Return success response with file details
-    let response = create_upload_response(filename, user, total_bytes);
-    Ok(HttpResponse::Ok().json(response))
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    /// Forces `Content-Disposition: inline` (`true`) or `attachment` (`false`)
+    pub inline: Option<bool>,
+    /// Comma-separated image transforms applied before serving, when
+    /// `DOWNLOAD_TRANSFORMS_ENABLED` is on
+    pub transform: Option<String>,
+    /// age recipient (`age1…`) the file is encrypted to as it streams out
+    pub encrypt_to: Option<String>,
+}
+
+/// Streams a stored file encrypted to an age `recipient`, as `{name}.age`; 400 for an
+/// invalid recipient
+async fn serve_encrypted(
+    scope: &StorageScope,
+    entry: &UploadMetadata,
+    recipient: &str,
+) -> Result<HttpResponse, actix_web::Error> {
+    let recipient =
+        encrypt::AgeRecipient::parse(recipient).map_err(actix_web::error::ErrorBadRequest)?;
+    let filepath = scope.uploads_dir.join(&entry.filename);
+    let file = tokio::fs::File::open(&filepath).await.map_err(|e| {
+        log::error!("Failed to open {}: {}", filepath.display(), e);
+        actix_web::error::ErrorNotFound("File not found on disk")
+    })?;
+    let body = encrypt::encrypt_stream(file, &recipient).map_err(|e| {
+        log::error!("Failed to start encrypting {}: {}", entry.filename, e);
+        actix_web::error::ErrorInternalServerError("Failed to encrypt file")
+    })?;
+
+    log::info!("Serving {} encrypted to an age recipient", entry.filename);
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(header::ContentDisposition::attachment(format!(
+            "{}.age",
+            entry.filename
+        )))
+        .streaming(body))
+}
+
+/// Serves a stored image through the `?transform=` pipeline, caching the result; 400
+/// for unknown transforms or files they can't be applied to
+async fn serve_transformed(
+    scope: &StorageScope,
+    entry: &UploadMetadata,
+    value: &str,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !images::transforms_enabled() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Download transforms are not enabled",
+        ));
+    }
+    let transforms = images::parse_transforms(value).map_err(actix_web::error::ErrorBadRequest)?;
+
+    let source = scope.uploads_dir.join(&entry.filename);
+    let version = cache_version(entry, &source)?;
+    let probe = source.clone();
+    let format = web::block(move || images::image_format(&probe))
+        .await?
+        .map_err(|e| {
+            log::error!("Failed to read {}: {}", entry.filename, e);
+            actix_web::error::ErrorNotFound("File not found on disk")
+        })?
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Transforms only apply to image files"))?;
+    let format = images::output_format(&transforms, format);
+    let cached = images::cache_dir().join("transform").join(format!(
+        "{}.{}.{}.{}",
+        entry.filename,
+        version,
+        images::transforms_key(&transforms),
+        format.extensions_str().first().copied().unwrap_or("bin")
+    ));
+
+    let cache_status = if cached.exists() {
+        "HIT"
+    } else {
+        let dest = cached.clone();
+        let transformed =
+            web::block(move || images::transform_image(&source, &transforms, format, &dest))
+                .await?
+                .map_err(|e| {
+                    log::error!("Transforming {} failed: {}", entry.filename, e);
+                    actix_web::error::ErrorInternalServerError(e)
+                })?;
+        if !transformed {
+            return Err(actix_web::error::ErrorBadRequest(
+                "Transforms cannot be applied to this file",
+            ));
+        }
+        "MISS"
+    };
+
+    let body = tokio::fs::read(&cached).await.map_err(|e| {
+        log::error!("Failed to read cached image {}: {}", cached.display(), e);
+        actix_web::error::ErrorInternalServerError("Failed to read transformed image")
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type(format.to_mime_type())
+        .insert_header(("X-Cache", cache_status))
+        .body(body))
+}
+
+/// Streams `filename` from the scope's uploads directory with download cache headers;
+/// `content_type` overrides the type guessed from the extension.
+///
+/// The disposition follows `?inline=`, else `INLINE_CONTENT_TYPES`, else the default
+/// of serving images, text, audio and video inline.
+async fn serve_stored_file(
+    req: &HttpRequest,
+    scope: &StorageScope,
+    filename: &str,
+    content_type: Option<String>,
+    public: bool,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filepath = scope.uploads_dir.join(filename);
+    let mut file = NamedFile::open_async(&filepath).await.map_err(|e| {
+        log::error!("Failed to open {}: {}", filepath.display(), e);
+        // Deleted from disk, so any cached copy is stale
+        if let Some(cache) = req.app_data::<web::Data<DownloadCache>>() {
+            cache.invalidate(&filepath);
+        }
+        actix_web::error::ErrorNotFound("File not found on disk")
+    })?;
+    if let Some(mime) = content_type.and_then(|v| v.parse::<actix_web::mime::Mime>().ok()) {
+        file = file.set_content_type(mime);
+    }
+    let query = web::Query::<DownloadQuery>::from_query(req.query_string())
+        .map_err(|_| actix_web::error::ErrorBadRequest("Invalid inline parameter"))?;
+    let inline = query
+        .inline
+        .or_else(|| content_type::serves_inline(file.content_type().essence_str()));
+    if let Some(inline) = inline {
+        let mut disposition = file.content_disposition().clone();
+        disposition.disposition = if inline {
+            header::DispositionType::Inline
+        } else {
+            header::DispositionType::Attachment
+        };
+        file = file.set_content_disposition(disposition);
+    }
+
+    // Small files are cached whole, so only plain GETs of the full file use the cache;
+    // the headers still come from the open file, so they are the same either way
+    let cache = req
+        .app_data::<web::Data<DownloadCache>>()
+        .filter(|cache| cache.enabled() && req.method() == actix_web::http::Method::GET);
+    let modified = file.metadata().modified().ok();
+    let len = file.metadata().len();
+    let mut response = file.into_response(req);
+    if let Some(cache) = cache.filter(|_| response.status() == actix_web::http::StatusCode::OK) {
+        let cached = match cache.get(&filepath, modified) {
+            Some(bytes) => Some((bytes, "HIT")),
+            None if cache.admits(len) => read_into_cache(cache, &filepath, len, modified)
+                .await
+                .map(|bytes| (bytes, "MISS")),
+            None => None,
+        };
+        if let Some((bytes, status)) = cached {
+            response = response.set_body(actix_web::body::BoxBody::new(bytes));
+            response.headers_mut().insert(
+                header::HeaderName::from_static("x-cache"),
+                header::HeaderValue::from_static(status),
+            );
+        }
+    }
+    let (cache_control, expires) = download_cache_headers(public);
+    let headers = response.headers_mut();
+    if let Ok(value) = header::HeaderValue::from_str(&cache_control) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+    if let Some(value) = expires.and_then(|v| header::HeaderValue::from_str(&v).ok()) {
+        headers.insert(header::EXPIRES, value);
+    }
+    Ok(response)
+}
+
+/// Reads a stored file whole and caches it; `None` when it can't be read or changed
+/// since it was opened, so it is served from the file as usual
+async fn read_into_cache(
+    cache: &DownloadCache,
+    filepath: &Path,
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+) -> Option<web::Bytes> {
+    let bytes = match tokio::fs::read(filepath).await {
+        Ok(bytes) => web::Bytes::from(bytes),
+        Err(e) => {
+            log::warn!("Failed to read {} for caching: {}", filepath.display(), e);
+            return None;
+        }
+    };
+    if bytes.len() as u64 != len {
+        return None;
+    }
+    cache.insert(filepath, bytes.clone(), modified);
+    Some(bytes)
+}
+
+/// Most filenames one metadata batch may ask for
+const MAX_METADATA_BATCH: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct MetadataBatchRequest {
+    pub filenames: Vec<String>,
+}
+
+/// Outcome for one requested filename
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum MetadataBatchResult {
+    Found {
+        metadata: Box<UploadMetadata>,
+    },
+    NotFound,
+    /// The file exists but belongs to another user
+    Forbidden,
+}
+
+#[derive(Serialize)]
+pub struct MetadataBatchResponse {
+    pub files: BTreeMap<String, MetadataBatchResult>,
+}
+
+/// Looks up the latest metadata for several files at once, applying the same
+/// ownership rules as a download of each
+pub async fn metadata_batch(
+    body: web::Json<MetadataBatchRequest>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    if body.filenames.len() > MAX_METADATA_BATCH {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "At most {} filenames per batch",
+            MAX_METADATA_BATCH
+        )));
+    }
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let files = body
+        .filenames
+        .iter()
+        .map(|filename| {
+            let latest = entries
+                .iter()
+                .rev()
+                .find(|entry| entry.filename == *filename);
+            let result = match latest {
+                None => MetadataBatchResult::NotFound,
+                Some(entry) if user.as_ref().is_some_and(|user| entry.user != user.sub) => {
+                    MetadataBatchResult::Forbidden
+                }
+                Some(entry) => MetadataBatchResult::Found {
+                    metadata: Box::new(entry.clone()),
+                },
+            };
+            (filename.clone(), result)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(MetadataBatchResponse { files }))
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveManifestRequest {
+    pub filenames: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveManifestEntry {
+    pub filename: String,
+    pub size_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveManifestResponse {
+    pub files: Vec<ArchiveManifestEntry>,
+    /// Requested names that don't exist or that the caller may not read
+    pub missing: Vec<String>,
+    pub total_bytes: u64,
+}
+
+/// Lists what an archive of the requested files would contain, without building it.
+/// Each name appears once, in request order.
+pub async fn archive_manifest(
+    body: web::Json<ArchiveManifestRequest>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    if body.filenames.len() > MAX_METADATA_BATCH {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "At most {} filenames per archive",
+            MAX_METADATA_BATCH
+        )));
+    }
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+    for filename in &body.filenames {
+        if !seen.insert(filename.as_str()) {
+            continue;
+        }
+        match find_owned_entry(&entries, filename, user.as_ref()) {
+            Ok(entry) => files.push(ArchiveManifestEntry {
+                filename: entry.filename.clone(),
+                size_bytes: entry.size_bytes,
+                checksum: entry.checksum.clone(),
+            }),
+            Err(_) => missing.push(filename.clone()),
+        }
+    }
+    let total_bytes = files.iter().map(|file| file.size_bytes).sum();
+
+    Ok(HttpResponse::Ok().json(ArchiveManifestResponse {
+        files,
+        missing,
+        total_bytes,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct LineRangeQuery {
+    /// First line to return, 1-based; defaults to the first line
+    pub start: Option<u64>,
+    /// Last line to return, inclusive; defaults to the end of the file
+    pub end: Option<u64>,
+}
+
+/// Streams lines `start..=end` of a stored text file, reading no further than `end`
+pub async fn file_lines(
+    path: web::Path<String>,
+    query: web::Query<LineRangeQuery>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filename = path.into_inner();
+    validate_stored_name(&filename)?;
+    let start = query.start.unwrap_or(1);
+    if start == 0 || query.end.is_some_and(|end| end < start) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Invalid line range: start must be at least 1 and end not before start",
+        ));
+    }
+    let end = query.end.unwrap_or(u64::MAX);
+
+    let entries = read_metadata(&scope.metadata_file)?;
+    let entry = find_owned_entry(&entries, &filename, user.as_ref())?;
+
+    let filepath = scope.uploads_dir.join(&filename);
+    let file = tokio::fs::File::open(&filepath).await.map_err(|e| {
+        log::error!("Failed to open {}: {}", filepath.display(), e);
+        actix_web::error::ErrorNotFound("File not found on disk")
+    })?;
+    let mut reader = tokio::io::BufReader::new(file);
+    let head = reader.fill_buf().await.map_err(|e| {
+        log::error!("Failed to read {}: {}", filepath.display(), e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to read file: {}", e))
+    })?;
+    if !content_type::is_text(entry.content_type.as_deref(), head) {
+        return Err(actix_web::error::ErrorBadRequest("File is not a text file"));
+    }
+
+    log::info!("Serving lines {}..={} of {}", start, end, filename);
+    // Lines keep their terminators, so the selection is byte-for-byte the original
+    let lines = futures::stream::unfold((reader, 1u64), move |(mut reader, line)| async move {
+        let mut skipped = Vec::new();
+        let mut line = line;
+        while line < start {
+            skipped.clear();
+            match reader.read_until(b'\n', &mut skipped).await {
+                Ok(0) => return None,
+                Ok(_) => line += 1,
+                Err(e) => return Some((Err(e), (reader, u64::MAX))),
+            }
+        }
+        if line > end {
+            return None;
+        }
+        let mut buffer = Vec::new();
+        match reader.read_until(b'\n', &mut buffer).await {
+            Ok(0) => None,
+            Ok(_) => Some((Ok(web::Bytes::from(buffer)), (reader, line + 1))),
+            Err(e) => Some((Err(e), (reader, u64::MAX))),
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .streaming(lines))
+}
+
+/// Hook outcome reported for an upload
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum DeliveryState {
+    Recorded {
+        status: DeliveryStatus,
+        at: Timestamp,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// `pending` until the hook has run, `not_configured` without a hook
+    Unrecorded { status: &'static str },
+}
+
+#[derive(Serialize)]
+pub struct DeliveryResponse {
+    pub filename: String,
+    /// When the upload the delivery belongs to was stored
+    pub timestamp: Timestamp,
+    pub delivery: DeliveryState,
+}
+
+/// Whether the post-upload hook for the latest upload of a file was delivered
+pub async fn file_delivery(
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+    timestamps: web::Data<TimestampConfig>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filename = path.into_inner();
+    validate_stored_name(&filename)?;
+    let entries = read_metadata(&scope.metadata_file)?;
+    let entry = find_owned_entry(&entries, &filename, user.as_ref())?;
+
+    let delivery = match &entry.hook_delivery {
+        Some(delivery) => DeliveryState::Recorded {
+            status: delivery.status,
+            at: timestamps.format.render(&delivery.at),
+            error: delivery.error.clone(),
+        },
+        None if hooks::PostUploadHook::from_env().is_some() => {
+            DeliveryState::Unrecorded { status: "pending" }
+        }
+        None => DeliveryState::Unrecorded {
+            status: "not_configured",
+        },
+    };
+    Ok(HttpResponse::Ok().json(DeliveryResponse {
+        filename,
+        timestamp: timestamps.format.render(&entry.timestamp),
+        delivery,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ChecksumQuery {
+    pub algo: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChecksumResponse {
+    pub filename: String,
+    pub algo: String,
+    pub checksum: String,
+}
+
+/// Computes a stored file's digest on demand and backfills it into metadata
+pub async fn file_checksum(
+    path: web::Path<String>,
+    query: web::Query<ChecksumQuery>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    let filename = path.into_inner();
+    validate_stored_name(&filename)?;
+    let algo = query
+        .algo
+        .clone()
+        .unwrap_or_else(|| "sha256".to_string())
+        .to_lowercase();
+    if algo != "sha256" && algo != "md5" {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Unsupported algorithm, use sha256 or md5",
+        ));
+    }
+
+    let metadata_file = &scope.metadata_file;
+    let entries = read_metadata(metadata_file)?;
+    let owner = find_owned_entry(&entries, &filename, user.as_ref())?
+        .user
+        .clone();
+
+    let filepath = scope.uploads_dir.join(&filename);
+    let mut file = tokio::fs::File::open(&filepath).await.map_err(|e| {
+        log::error!("Failed to open {}: {}", filepath.display(), e);
+        actix_web::error::ErrorNotFound("File not found on disk")
+    })?;
+
+    // Stream the file through the digest rather than loading it into memory
+    let mut sha256 = Sha256::new();
+    let mut md5 = Md5::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await.map_err(|e| {
+            log::error!("Failed to read {}: {}", filepath.display(), e);
+            actix_web::error::ErrorInternalServerError(format!("Failed to read file: {}", e))
+        })?;
+        if read == 0 {
+            break;
+        }
+        if algo == "sha256" {
+            sha256.update(&buffer[..read]);
+        } else {
+            md5.update(&buffer[..read]);
+        }
+    }
+    let checksum = if algo == "sha256" {
+        hex::encode(sha256.finalize())
+    } else {
+        hex::encode(md5.finalize())
+    };
+
+    // Backfill the digest on the entry we just verified ownership of
+    update_metadata(metadata_file, |entries| {
+        if let Some(entry) = entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.filename == filename && entry.user == owner)
+        {
+            if algo == "sha256" {
+                entry.checksum = Some(checksum.clone());
+            } else {
+                entry.checksum_md5 = Some(checksum.clone());
+            }
+        }
+    })?;
+
+    log::info!("Computed {} checksum for {}", algo, filename);
+    Ok(HttpResponse::Ok().json(ChecksumResponse {
+        filename,
+        algo,
+        checksum,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -134,13 +2372,6 @@ pub struct TokenExchangeRequest {
     pub redirect_uri: String,
 }
 
-#[derive(Serialize)]
-pub struct TokenResponse {
-    pub access_token: String,
-    pub token_type: String,
-    pub expires_in: Option<u64>,
-}
-
 #[derive(Deserialize)]
 pub struct RefreshTokenRequest {
     pub refresh_token: String,
@@ -252,3 +2483,71 @@ pub async fn refresh_token(
         }))),
     }
 }
+
+/// Version of a stored file that derived images are cached under: its checksum, else
+/// its modification time. Keying the cache on content means an overwritten file is
+/// never served stale.
+fn cache_version(entry: &UploadMetadata, source: &Path) -> Result<String, actix_web::Error> {
+    match &entry.checksum {
+        Some(checksum) => Ok(checksum.clone()),
+        None => fs::metadata(source)
+            .and_then(|m| m.modified())
+            .map(|t| {
+                let since_epoch = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+                format!("m{}", since_epoch.as_secs())
+            })
+            .map_err(|_| actix_web::error::ErrorNotFound("File not found on disk")),
+    }
+}
+
+/// Serves a stored image converted to WebP, caching the converted file
+pub async fn file_webp(
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    scope: StorageScope,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !images::webp_enabled() {
+        return Err(actix_web::error::ErrorNotFound(
+            "WebP transcoding is not enabled",
+        ));
+    }
+
+    let filename = path.into_inner();
+    validate_stored_name(&filename)?;
+    let entries = read_metadata(&scope.metadata_file)?;
+    let entry = find_owned_entry(&entries, &filename, user.as_ref())?;
+
+    let source = scope.uploads_dir.join(&filename);
+    let version = cache_version(entry, &source)?;
+    let cached = images::cache_dir()
+        .join("webp")
+        .join(format!("{}.{}.webp", filename, version));
+
+    let cache_status = if cached.exists() {
+        "HIT"
+    } else {
+        let dest = cached.clone();
+        let converted = web::block(move || images::transcode_to_webp(&source, &dest))
+            .await?
+            .map_err(|e| {
+                log::error!("WebP transcoding failed for {}: {}", filename, e);
+                actix_web::error::ErrorInternalServerError(e)
+            })?;
+        if !converted {
+            return Err(actix_web::error::ErrorBadRequest("File is not an image"));
+        }
+        "MISS"
+    };
+
+    let body = tokio::fs::read(&cached).await.map_err(|e| {
+        log::error!("Failed to read cached WebP {}: {}", cached.display(), e);
+        actix_web::error::ErrorInternalServerError("Failed to read converted image")
+    })?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/webp")
+        .insert_header(("X-Cache", cache_status))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests;
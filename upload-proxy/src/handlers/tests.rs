@@ -0,0 +1,2043 @@
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+
+use super::*;
+use crate::test_support::{
+    app, serve, Form, MockKeycloak, TestEnv, TEST_ROLES_HEADER, TEST_USER_HEADER,
+};
+
+/// Uploads one file as `user`
+async fn upload_as<S, B>(app: &S, user: &str, filename: &str, content: &[u8]) -> ServiceResponse<B>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let req = Form::new()
+        .file(filename, content)
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, user))
+        .to_request();
+    call_service(app, req).await
+}
+
+/// Value of the response header `name`, or `""` when it is missing
+fn header_of<B>(resp: &ServiceResponse<B>, name: &str) -> String {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// `GET uri` as `user`
+async fn get_as<S, B>(app: &S, user: &str, uri: &str) -> ServiceResponse<B>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let req = TestRequest::get()
+        .uri(uri)
+        .insert_header((TEST_USER_HEADER, user))
+        .to_request();
+    call_service(app, req).await
+}
+
+/// Points `METADATA_FILE` into a directory that doesn't exist, so writes to it fail
+fn break_metadata(env: &mut TestEnv) {
+    let missing = env.path().join("missing").join("uploads.json");
+    env.set("METADATA_FILE", &missing.display().to_string());
+    env.set("METADATA_WRITE_RETRIES", "0");
+}
+
+#[test]
+fn metadata_failure_policy_defaults_to_fail() {
+    let mut env = TestEnv::new();
+    env.remove("ON_METADATA_FAILURE");
+    assert_eq!(
+        MetadataFailurePolicy::from_env(),
+        MetadataFailurePolicy::Fail
+    );
+    env.set("ON_METADATA_FAILURE", "Rollback");
+    assert_eq!(
+        MetadataFailurePolicy::from_env(),
+        MetadataFailurePolicy::Rollback
+    );
+    env.set("ON_METADATA_FAILURE", "keep");
+    assert_eq!(
+        MetadataFailurePolicy::from_env(),
+        MetadataFailurePolicy::Keep
+    );
+    env.set("ON_METADATA_FAILURE", "shrug");
+    assert_eq!(
+        MetadataFailurePolicy::from_env(),
+        MetadataFailurePolicy::Fail
+    );
+}
+
+#[actix_web::test]
+async fn upload_stores_file_and_metadata() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("notes.txt", b"hello")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["filename"], "notes.txt");
+    assert_eq!(body["user"], "alice");
+    assert_eq!(body["size_bytes"], 5);
+
+    assert_eq!(env.stored_files(), ["notes.txt"]);
+    let entries = env.entries();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].user, "alice");
+    assert_eq!(
+        entries[0].checksum.as_deref(),
+        Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+    );
+}
+
+#[actix_web::test]
+async fn metadata_failure_fails_but_keeps_the_file() {
+    let mut env = TestEnv::new().with("ON_METADATA_FAILURE", "fail");
+    break_metadata(&mut env);
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("a.txt", b"data")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(env.stored_files(), ["a.txt"]);
+}
+
+#[actix_web::test]
+async fn metadata_failure_with_keep_returns_a_warning() {
+    let mut env = TestEnv::new().with("ON_METADATA_FAILURE", "keep");
+    break_metadata(&mut env);
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("a.txt", b"data")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert!(body["warning"]
+        .as_str()
+        .is_some_and(|warning| warning.contains("metadata could not be recorded")));
+    assert_eq!(env.stored_files(), ["a.txt"]);
+}
+
+#[actix_web::test]
+async fn metadata_failure_with_rollback_removes_every_file() {
+    let mut env = TestEnv::new().with("ON_METADATA_FAILURE", "rollback");
+    break_metadata(&mut env);
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("a.txt", b"one")
+        .file("b.txt", b"two")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(env.stored_files().is_empty());
+}
+
+#[actix_web::test]
+async fn metadata_failure_with_rollback_keeps_the_replaced_file() {
+    let mut env = TestEnv::new().with("ON_METADATA_FAILURE", "rollback");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"previous").await;
+    break_metadata(&mut env);
+
+    let resp = upload_as(&app, "alice", "a.txt", b"replacement").await;
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(env.stored_files(), ["a.txt"]);
+    let kept = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    assert_eq!(kept, b"previous");
+}
+
+#[actix_web::test]
+async fn filename_regex_rejects_other_names() {
+    let env = TestEnv::new().with("FILENAME_REGEX", r"\.txt$");
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("image.png", b"data")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(env.stored_files().is_empty());
+
+    let req = Form::new()
+        .file("notes.txt", b"data")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn checksum_is_computed_and_backfilled() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+    assert_eq!(
+        upload_as(&app, "alice", "a.txt", b"hello").await.status(),
+        StatusCode::OK
+    );
+
+    let resp = get_as(&app, "alice", "/api/files/a.txt/checksum?algo=MD5").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["algo"], "md5");
+    assert_eq!(body["checksum"], "5d41402abc4b2a76b9719d911017c592");
+    assert_eq!(
+        env.entries()[0].checksum_md5.as_deref(),
+        Some("5d41402abc4b2a76b9719d911017c592")
+    );
+}
+
+#[actix_web::test]
+async fn checksum_is_only_served_to_the_owner() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    let resp = get_as(&app, "bob", "/api/files/a.txt/checksum").await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = get_as(&app, "alice", "/api/files/a.txt/checksum?algo=crc32").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = get_as(&app, "alice", "/api/files/missing.txt/checksum").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn declared_oversize_upload_is_rejected_before_reading() {
+    let env = TestEnv::new().with("MAX_UPLOAD_BYTES", "64");
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("big.bin", &[0u8; 16])
+        .post("/api/upload")
+        .insert_header((header::CONTENT_LENGTH, "100000"))
+        .insert_header((header::EXPECT, "100-continue"))
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(env.stored_files().is_empty());
+}
+
+#[actix_web::test]
+async fn oversize_upload_keeps_the_file_it_would_replace() {
+    let env = TestEnv::new().with("MAX_UPLOAD_BYTES", "1024");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"previous").await;
+
+    let resp = upload_as(&app, "alice", "a.txt", &[0u8; 4096]).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(env.stored_files(), ["a.txt"]);
+    let kept = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    assert_eq!(kept, b"previous");
+    assert_eq!(env.entries().len(), 1);
+}
+
+#[actix_web::test]
+async fn unsupported_expectation_gets_417() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("a.txt", b"data")
+        .post("/api/upload")
+        .insert_header((header::EXPECT, "200-ok"))
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::EXPECTATION_FAILED);
+}
+
+/// A small RGB PNG
+fn png_bytes() -> Vec<u8> {
+    let image = image::RgbImage::from_fn(8, 8, |x, y| image::Rgb([x as u8 * 30, y as u8 * 30, 90]));
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageFormat::Png)
+        .expect("failed to encode test PNG");
+    png.into_inner()
+}
+
+#[actix_web::test]
+async fn webp_endpoint_is_off_by_default() {
+    let mut env = TestEnv::new();
+    env.remove("WEBP_TRANSCODE_ENABLED");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "pic.png", &png_bytes()).await;
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png/webp").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn webp_is_transcoded_once_then_served_from_cache() {
+    let mut env = TestEnv::new().with("WEBP_TRANSCODE_ENABLED", "true");
+    env.set(
+        "TRANSCODE_CACHE_DIR",
+        &env.path().join("cache").display().to_string(),
+    );
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "pic.png", &png_bytes()).await;
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png/webp").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header_of(&resp, "content-type"), "image/webp");
+    assert_eq!(header_of(&resp, "x-cache"), "MISS");
+    let body = read_body(resp).await;
+    assert_eq!(&body[..4], b"RIFF");
+    assert_eq!(&body[8..12], b"WEBP");
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png/webp").await;
+    assert_eq!(header_of(&resp, "x-cache"), "HIT");
+}
+
+#[actix_web::test]
+async fn webp_of_a_non_image_is_a_bad_request() {
+    let mut env = TestEnv::new().with("WEBP_TRANSCODE_ENABLED", "true");
+    env.set(
+        "TRANSCODE_CACHE_DIR",
+        &env.path().join("cache").display().to_string(),
+    );
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "notes.txt", b"plain text").await;
+
+    let resp = get_as(&app, "alice", "/api/files/notes.txt/webp").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn file_limit_counts_distinct_names_per_user() {
+    let env = TestEnv::new().with("MAX_FILES_PER_USER", "2");
+    let app = init_service(app()).await;
+
+    assert_eq!(
+        upload_as(&app, "alice", "a.txt", b"1").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        upload_as(&app, "alice", "b.txt", b"2").await.status(),
+        StatusCode::OK
+    );
+    // Replacing a file doesn't add one
+    assert_eq!(
+        upload_as(&app, "alice", "a.txt", b"3").await.status(),
+        StatusCode::OK
+    );
+
+    let resp = upload_as(&app, "alice", "c.txt", b"4").await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["limit"], "files_per_user");
+    assert_eq!(body["value"], 2);
+    assert_eq!(env.stored_files(), ["a.txt", "b.txt"]);
+
+    // Other users have their own allowance
+    assert_eq!(
+        upload_as(&app, "bob", "c.txt", b"5").await.status(),
+        StatusCode::OK
+    );
+}
+
+#[actix_web::test]
+async fn downloads_use_public_and_private_cache_control() {
+    let _env = TestEnv::new()
+        .with("CACHE_CONTROL_HEADER", "public, max-age=3600")
+        .with("CACHE_CONTROL_PRIVATE_HEADER", "private, no-store");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    let public = call_service(
+        &app,
+        TestRequest::get().uri("/api/files/a.txt").to_request(),
+    )
+    .await;
+    assert_eq!(public.status(), StatusCode::OK);
+    assert_eq!(header_of(&public, "cache-control"), "public, max-age=3600");
+    assert!(!header_of(&public, "expires").is_empty());
+
+    let private = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(private.status(), StatusCode::OK);
+    assert_eq!(header_of(&private, "cache-control"), "private, no-store");
+    assert_eq!(header_of(&private, "expires"), "");
+}
+
+#[test]
+fn download_cache_headers_default_to_a_longer_public_ttl() {
+    let mut env = TestEnv::new();
+    env.remove("CACHE_CONTROL_HEADER");
+    env.remove("CACHE_CONTROL_PRIVATE_HEADER");
+    let (public, public_expires) = download_cache_headers(true);
+    let (private, _) = download_cache_headers(false);
+    assert_eq!(public, "public, max-age=86400");
+    assert_eq!(private, "private, max-age=300");
+    assert!(public_expires.is_some());
+}
+
+/// Uploads two fields both named `a.txt` under `DUPLICATE_FILENAME_POLICY=policy`
+async fn upload_duplicate_fields(env: &mut TestEnv, policy: &str) -> StatusCode {
+    env.set("DUPLICATE_FILENAME_POLICY", policy);
+    let app = init_service(app()).await;
+    let req = Form::new()
+        .file("a.txt", b"first")
+        .file("a.txt", b"second")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    call_service(&app, req).await.status()
+}
+
+#[actix_web::test]
+async fn duplicate_fields_are_suffixed_by_default() {
+    let mut env = TestEnv::new();
+    assert_eq!(upload_duplicate_fields(&mut env, "").await, StatusCode::OK);
+    assert_eq!(env.stored_files(), ["a (1).txt", "a.txt"]);
+    let read = |name: &str| std::fs::read(env.uploads_dir().join(name)).unwrap();
+    assert_eq!(read("a.txt"), b"first");
+    assert_eq!(read("a (1).txt"), b"second");
+}
+
+#[actix_web::test]
+async fn duplicate_fields_can_be_rejected() {
+    let mut env = TestEnv::new();
+    let status = upload_duplicate_fields(&mut env, "reject").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(env.stored_files().is_empty());
+    assert!(env.entries().is_empty());
+}
+
+#[actix_web::test]
+async fn duplicate_fields_can_overwrite() {
+    let mut env = TestEnv::new();
+    let status = upload_duplicate_fields(&mut env, "overwrite").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(env.stored_files(), ["a.txt"]);
+    let content = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    assert_eq!(content, b"second");
+    assert_eq!(env.entries().len(), 1);
+}
+
+#[actix_web::test]
+async fn uploads_report_their_local_storage_location() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "a.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    let path = env.uploads_dir().join("a.txt").display().to_string();
+    assert_eq!(body["storage"]["backend"], "local");
+    assert_eq!(body["storage"]["location"], path.as_str());
+
+    let storage = env.entries()[0].storage.clone().unwrap();
+    assert_eq!(storage.backend, "local");
+    assert_eq!(storage.location, path);
+}
+
+#[actix_web::test]
+async fn denied_filenames_are_rejected_after_sanitizing() {
+    let env = TestEnv::new().with("DENY_FILENAME_PATTERNS", "*.php,.htaccess");
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "../www/shell.PHP", b"<?php").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = upload_as(&app, "alice", "site/.htaccess", b"Deny").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = upload_as(&app, "alice", "notes.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(env.stored_files(), ["notes.txt"]);
+}
+
+#[actix_web::test]
+async fn buffered_and_streamed_files_are_stored_alike() {
+    let env = TestEnv::new().with("SMALL_FILE_BUFFER_BYTES", "1024");
+    let app = init_service(app()).await;
+    let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+    for (name, content) in [("tiny.bin", &b"tiny"[..]), ("large.bin", &large[..])] {
+        let resp = upload_as(&app, "alice", name, content).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["size_bytes"], content.len());
+        assert_eq!(body["checksum"], hex::encode(Sha256::digest(content)));
+        let stored = std::fs::read(env.uploads_dir().join(name)).unwrap();
+        assert_eq!(stored, content);
+    }
+}
+
+#[actix_web::test]
+async fn ownership_is_only_checked_for_authenticated_downloads() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    let anonymous = TestRequest::get().uri("/api/files/a.txt").to_request();
+    let resp = call_service(&app, anonymous).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_body(resp).await, "hello");
+    let resp = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = get_as(&app, "bob", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn files_are_served_by_checksum_to_their_owners() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+    let digest = hex::encode(Sha256::digest(b"hello"));
+
+    let resp = get_as(&app, "alice", &format!("/api/content/{}", digest)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_body(resp).await, "hello");
+    let upper = get_as(
+        &app,
+        "alice",
+        &format!("/api/content/{}", digest.to_uppercase()),
+    )
+    .await;
+    assert_eq!(upper.status(), StatusCode::OK);
+
+    // Someone else holding no copy, or a digest nothing has
+    let resp = get_as(&app, "bob", &format!("/api/content/{}", digest)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let unknown = hex::encode(Sha256::digest(b"other"));
+    let resp = get_as(&app, "alice", &format!("/api/content/{}", unknown)).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = get_as(&app, "alice", "/api/content/not-a-digest").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn mismatched_extensions_are_rejected_when_enforced() {
+    let env = TestEnv::new().with("ENFORCE_TYPE_EXTENSION_MATCH", "true");
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "photo.png", &png_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = upload_as(
+        &app,
+        "alice",
+        "invoice.png",
+        b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n",
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(env.stored_files(), ["photo.png"]);
+}
+
+/// Status line and body of `HEAD path` sent to `url` over a real connection, where the
+/// server drops any body a handler produced
+async fn head_over_http(url: &str, path: &str) -> (String, Vec<u8>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    let req = format!(
+        "HEAD {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..end]).to_string();
+    let status = head.lines().next().unwrap_or_default().to_string();
+    (status, response[end + 4..].to_vec())
+}
+
+#[actix_web::test]
+async fn health_endpoints_answer_head_without_a_body() {
+    let keycloak = MockKeycloak::start().await;
+    let mut env = TestEnv::new();
+    keycloak.configure(&mut env);
+    let url = serve();
+
+    for path in ["/health", "/health/live", "/health/ready"] {
+        let (status, body) = head_over_http(&url, path).await;
+        assert_eq!(status, "HTTP/1.1 200 OK", "{}", path);
+        assert!(body.is_empty(), "{}", path);
+    }
+}
+
+#[actix_web::test]
+async fn readiness_reports_unavailable_signing_keys() {
+    let mut env = TestEnv::new();
+    env.remove("KEYCLOAK_URL");
+    let app = init_service(app()).await;
+
+    let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["uploads_dir"], "ok");
+    assert_eq!(body["checks"]["jwks"], "KEYCLOAK_URL is not set");
+}
+
+#[actix_web::test]
+async fn unknown_routes_get_a_json_404() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let resp = call_service(&app, TestRequest::get().uri("/no/such/route").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    assert_eq!(header_of(&resp, "content-type"), "application/json");
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(
+        body,
+        serde_json::json!({ "error": "not_found", "path": "/no/such/route" })
+    );
+
+    let resp = call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+/// A tar header block, whose `ustar` magic sits at offset 257
+fn tar_bytes() -> Vec<u8> {
+    let mut block = vec![0u8; 512];
+    block[..8].copy_from_slice(b"file.txt");
+    block[257..263].copy_from_slice(b"ustar\0");
+    block
+}
+
+#[actix_web::test]
+async fn sniff_depth_decides_whether_late_magic_is_seen() {
+    for (sniff_bytes, expected) in [
+        ("1024", "application/x-tar"),
+        ("16", "application/octet-stream"),
+    ] {
+        let env = TestEnv::new().with("MIME_SNIFF_BYTES", sniff_bytes);
+        let app = init_service(app()).await;
+        let resp = upload_as(&app, "alice", "archive.tar", &tar_bytes()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let content_type = env.entries()[0].content_type.clone();
+        assert_eq!(content_type.as_deref(), Some(expected), "{}", sniff_bytes);
+    }
+}
+
+#[actix_web::test]
+async fn racing_same_name_uploads_get_distinct_names() {
+    let env = TestEnv::new().with("COLLISION_POLICY", "suffix");
+    let url = serve();
+    let client = reqwest::Client::new();
+    let uploads = (0..4).map(|i| {
+        let (content_type, body) = Form::new()
+            .file("a.txt", format!("upload {}", i).as_bytes())
+            .finish();
+        client
+            .post(format!("{}/api/upload", url))
+            .header("Content-Type", content_type)
+            .header(TEST_USER_HEADER, "alice")
+            .body(body)
+            .send()
+    });
+
+    let mut names = Vec::new();
+    for resp in futures::future::join_all(uploads).await {
+        let resp = resp.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        names.push(body["filename"].as_str().unwrap().to_string());
+    }
+    names.sort();
+    let expected = ["a (1).txt", "a (2).txt", "a (3).txt", "a.txt"];
+    assert_eq!(names, expected);
+    assert_eq!(env.stored_files(), expected);
+    assert_eq!(env.entries().len(), 4);
+}
+
+/// Names on one page of alice's listing, in listing order, and its `next_cursor`
+async fn list_page<S, B>(app: &S, uri: &str) -> (Vec<String>, Option<String>)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let resp = get_as(app, "alice", uri).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    let names = body["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["filename"].as_str().unwrap().to_string())
+        .collect();
+    (names, body["next_cursor"].as_str().map(str::to_string))
+}
+
+#[actix_web::test]
+async fn listing_filters_by_content_type() {
+    let env = TestEnv::new();
+    let typed = |name: &str, content_type: Option<&str>| {
+        let mut entry = UploadMetadata::new(name.into(), "alice".into(), 1);
+        entry.content_type = content_type.map(str::to_string);
+        entry
+    };
+    env.seed(&[
+        typed("photo.png", Some("image/png")),
+        typed("pic.jpg", Some("image/jpeg")),
+        typed("clip.mp4", Some("video/mp4")),
+        typed("doc.pdf", Some("application/pdf")),
+        typed("legacy.png", None),
+    ]);
+    let app = init_service(app()).await;
+
+    let (mut images, _) = list_page(&app, "/api/files?content_type=image/*").await;
+    images.sort();
+    assert_eq!(images, ["photo.png", "pic.jpg"]);
+    let (png, _) = list_page(&app, "/api/files?content_type=image/png").await;
+    assert_eq!(png, ["photo.png"]);
+    let (all, _) = list_page(&app, "/api/files").await;
+    assert_eq!(all.len(), 5);
+}
+
+#[actix_web::test]
+async fn cursor_pages_are_disjoint_and_stable() {
+    let env = TestEnv::new();
+    let at = |name: &str, second: u32| {
+        let mut entry = UploadMetadata::new(name.into(), "alice".into(), 1);
+        entry.timestamp = format!("2026-01-01T00:00:{:02}+00:00", second);
+        entry
+    };
+    // b and c share a timestamp, so their order comes from the name
+    env.seed(&[
+        at("a", 1),
+        at("c", 2),
+        at("b", 2),
+        at("d", 3),
+        at("e", 4),
+        at("f", 5),
+        at("g", 6),
+    ]);
+    let app = init_service(app()).await;
+
+    let (first, cursor) = list_page(&app, "/api/files?limit=3").await;
+    assert_eq!(first, ["g", "f", "e"]);
+    // A new upload sorts before the cursor, so later pages neither repeat nor skip
+    upload_as(&app, "alice", "new.txt", b"hello").await;
+    let cursor = cursor.unwrap();
+    let (second, cursor) = list_page(&app, &format!("/api/files?limit=3&cursor={}", cursor)).await;
+    assert_eq!(second, ["d", "b", "c"]);
+    let cursor = cursor.unwrap();
+    let (third, cursor) = list_page(&app, &format!("/api/files?limit=3&cursor={}", cursor)).await;
+    assert_eq!(third, ["a"]);
+    assert!(cursor.is_none());
+
+    let (fresh, _) = list_page(&app, "/api/files?limit=2").await;
+    assert_eq!(fresh, ["new.txt", "g"]);
+    let resp = get_as(&app, "alice", "/api/files?cursor=zz").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Stores a PNG as `image.bin` for alice, with no content type recorded
+fn seed_untyped_png(env: &TestEnv) {
+    std::fs::create_dir_all(env.uploads_dir()).unwrap();
+    std::fs::write(env.uploads_dir().join("image.bin"), png_bytes()).unwrap();
+    env.seed(&[UploadMetadata::new("image.bin".into(), "alice".into(), 1)]);
+}
+
+#[actix_web::test]
+async fn oversized_pages_are_clamped() {
+    let env = TestEnv::new().with("LIST_MAX_PER_PAGE", "2");
+    env.seed(&[
+        UploadMetadata::new("a".into(), "alice".into(), 1),
+        UploadMetadata::new("b".into(), "alice".into(), 1),
+        UploadMetadata::new("c".into(), "alice".into(), 1),
+    ]);
+    let app = init_service(app()).await;
+
+    let resp = get_as(&app, "alice", "/api/files?limit=50").await;
+    assert_eq!(header_of(&resp, "x-page-size-clamped"), "2");
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["files"].as_array().unwrap().len(), 2);
+    assert!(body["next_cursor"].is_string());
+}
+
+#[actix_web::test]
+async fn missing_content_types_are_sniffed_on_download_when_enabled() {
+    let env = TestEnv::new().with("CORRECT_CONTENT_TYPE_ON_DOWNLOAD", "true");
+    seed_untyped_png(&env);
+    let app = init_service(app()).await;
+
+    let resp = get_as(&app, "alice", "/api/files/image.bin").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header_of(&resp, "content-type"), "image/png");
+    // Recorded, so later downloads don't sniff again
+    assert_eq!(env.entries()[0].content_type.as_deref(), Some("image/png"));
+}
+
+#[actix_web::test]
+async fn content_types_are_not_corrected_by_default() {
+    let mut env = TestEnv::new();
+    env.remove("CORRECT_CONTENT_TYPE_ON_DOWNLOAD");
+    seed_untyped_png(&env);
+    let app = init_service(app()).await;
+
+    let resp = get_as(&app, "alice", "/api/files/image.bin").await;
+    assert_eq!(header_of(&resp, "content-type"), "application/octet-stream");
+    assert!(env.entries()[0].content_type.is_none());
+}
+
+#[actix_web::test]
+async fn line_ranges_select_lines_of_text_files() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    let text: String = (1..=10).map(|n| format!("line {}\n", n)).collect();
+    upload_as(&app, "alice", "log.txt", text.as_bytes()).await;
+    upload_as(&app, "alice", "image.png", &png_bytes()).await;
+
+    let lines = |uri: &'static str| get_as(&app, "alice", uri);
+    let resp = lines("/api/files/log.txt/lines?start=4&end=6").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(read_body(resp).await, "line 4\nline 5\nline 6\n");
+    let resp = lines("/api/files/log.txt/lines?start=9").await;
+    assert_eq!(read_body(resp).await, "line 9\nline 10\n");
+    let resp = lines("/api/files/log.txt/lines?start=20").await;
+    assert_eq!(read_body(resp).await, "");
+
+    let resp = lines("/api/files/log.txt/lines?start=6&end=4").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = lines("/api/files/log.txt/lines?start=0").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = lines("/api/files/image.png/lines?start=1&end=2").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn unique_per_user_rejects_a_users_repeated_name() {
+    for strategy in ["original", "uuid"] {
+        let env = TestEnv::new()
+            .with("FILENAME_UNIQUE_PER_USER", "true")
+            .with("NAME_STRATEGY", strategy);
+        let app = init_service(app()).await;
+
+        let resp = upload_as(&app, "alice", "a.txt", b"first").await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", strategy);
+        let resp = upload_as(&app, "alice", "a.txt", b"second").await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT, "{}", strategy);
+        let resp = upload_as(&app, "bob", "a.txt", b"theirs").await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", strategy);
+        assert_eq!(env.entries().len(), 2, "{}", strategy);
+    }
+}
+
+#[actix_web::test]
+async fn empty_multipart_bodies_have_their_own_error() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "No file part found");
+    assert!(env.entries().is_empty());
+}
+
+#[actix_web::test]
+async fn uploads_report_quota_usage_and_warn_near_the_limit() {
+    let _env = TestEnv::new()
+        .with("MAX_FILES_PER_USER", "4")
+        .with("QUOTA_WARN_PERCENT", "75");
+    let app = init_service(app()).await;
+
+    let mut seen = Vec::new();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        let resp = upload_as(&app, "alice", name, b"hello").await;
+        seen.push((
+            header_of(&resp, "x-quota-used"),
+            header_of(&resp, "x-quota-limit"),
+            header_of(&resp, "x-quota-warning"),
+        ));
+    }
+    let expected =
+        |used: &str, warning: &str| (used.to_string(), "4".to_string(), warning.to_string());
+    assert_eq!(
+        seen,
+        [expected("1", ""), expected("2", ""), expected("3", "true")]
+    );
+}
+
+#[actix_web::test]
+async fn quota_headers_need_a_file_limit() {
+    let mut env = TestEnv::new();
+    env.remove("MAX_FILES_PER_USER");
+    let app = init_service(app()).await;
+    let resp = upload_as(&app, "alice", "a.txt", b"hello").await;
+    assert_eq!(header_of(&resp, "x-quota-used"), "");
+    assert_eq!(header_of(&resp, "x-quota-limit"), "");
+}
+
+#[actix_web::test]
+async fn metadata_batches_report_each_file() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "mine.txt", b"hello").await;
+    upload_as(&app, "bob", "theirs.txt", b"hello").await;
+
+    let req = TestRequest::post()
+        .uri("/api/files/metadata/batch")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .set_json(serde_json::json!({
+            "filenames": ["mine.txt", "theirs.txt", "missing.txt"]
+        }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    let files = &body["files"];
+    assert_eq!(files["mine.txt"]["status"], "found");
+    assert_eq!(files["mine.txt"]["metadata"]["user"], "alice");
+    assert_eq!(files["mine.txt"]["metadata"]["filename"], "mine.txt");
+    assert_eq!(
+        files["theirs.txt"],
+        serde_json::json!({ "status": "forbidden" })
+    );
+    assert_eq!(
+        files["missing.txt"],
+        serde_json::json!({ "status": "not_found" })
+    );
+}
+
+#[actix_web::test]
+async fn oversized_metadata_batches_are_rejected() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    let filenames: Vec<String> = (0..=MAX_METADATA_BATCH)
+        .map(|i| format!("{}.txt", i))
+        .collect();
+    let req = TestRequest::post()
+        .uri("/api/files/metadata/batch")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .set_json(serde_json::json!({ "filenames": filenames }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Uploads `content` as alice's `a.txt` with `If-Unmodified-Since: since`
+async fn upload_unless_modified<S, B>(app: &S, since: &str, content: &[u8]) -> ServiceResponse<B>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let req = Form::new()
+        .file("a.txt", content)
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("If-Unmodified-Since", since))
+        .to_request();
+    call_service(app, req).await
+}
+
+#[actix_web::test]
+async fn stale_conditional_uploads_are_rejected() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"first").await;
+
+    let stale = "Sun, 06 Nov 1994 08:49:37 GMT";
+    let resp = upload_unless_modified(&app, stale, b"second").await;
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(env.entries().len(), 1);
+    let stored = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    assert_eq!(stored, b"first");
+}
+
+#[actix_web::test]
+async fn current_conditional_uploads_overwrite() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"first").await;
+
+    let since = header::HttpDate::from(std::time::SystemTime::now()).to_string();
+    let resp = upload_unless_modified(&app, &since, b"second").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let stored = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    assert_eq!(stored, b"second");
+
+    // Unparsable dates are ignored rather than failing the upload
+    let resp = upload_unless_modified(&app, "not a date", b"third").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+/// `download_count` of alice's `a.txt` in her listing
+async fn listed_download_count<S, B>(app: &S) -> u64
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let resp = get_as(app, "alice", "/api/files").await;
+    let body: serde_json::Value = read_body_json(resp).await;
+    body["files"][0]["download_count"].as_u64().unwrap()
+}
+
+#[actix_web::test]
+async fn downloads_are_counted_when_tracked() {
+    let env = TestEnv::new().with("TRACK_DOWNLOADS", "true");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+    assert_eq!(listed_download_count(&app).await, 0);
+
+    for _ in 0..2 {
+        let resp = get_as(&app, "alice", "/api/files/a.txt").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let digest = hex::encode(Sha256::digest(b"hello"));
+    let resp = get_as(&app, "alice", &format!("/api/content/{}", digest)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    assert_eq!(listed_download_count(&app).await, 3);
+    assert_eq!(env.entries()[0].download_count, 3);
+}
+
+#[actix_web::test]
+async fn downloads_are_not_counted_by_default() {
+    let mut env = TestEnv::new();
+    env.remove("TRACK_DOWNLOADS");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+    get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(listed_download_count(&app).await, 0);
+}
+
+/// Preflight response for `proposal` as alice
+async fn preflight<S, B>(app: &S, proposal: serde_json::Value) -> serde_json::Value
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let req = TestRequest::post()
+        .uri("/api/upload/preflight")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .set_json(proposal)
+        .to_request();
+    let resp = call_service(app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    read_body_json(resp).await
+}
+
+#[actix_web::test]
+async fn preflight_accepts_a_valid_proposal_without_storing() {
+    let env = TestEnv::new().with("COLLISION_POLICY", "suffix");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "report.txt", b"hello").await;
+
+    let body = preflight(
+        &app,
+        serde_json::json!({
+            "filename": "report.txt",
+            "content_type": "text/plain",
+            "size": 5
+        }),
+    )
+    .await;
+    assert_eq!(body["ok"], true);
+    assert_eq!(body["issues"], serde_json::json!([]));
+    assert_eq!(body["final_filename"], "report (1).txt");
+    assert_eq!(env.entries().len(), 1);
+    assert_eq!(env.stored_files(), ["report.txt"]);
+}
+
+#[actix_web::test]
+async fn preflight_lists_every_broken_rule() {
+    let _env = TestEnv::new()
+        .with("MAX_UPLOAD_BYTES", "1024")
+        .with("DENY_FILENAME_PATTERNS", "*.php")
+        .with("MAX_FILES_PER_USER", "1");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    let body = preflight(
+        &app,
+        serde_json::json!({
+            "filename": "shell.php",
+            "content_type": "not a type",
+            "size": 4096
+        }),
+    )
+    .await;
+    assert_eq!(body["ok"], false);
+    let issues: Vec<&str> = body["issues"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|issue| issue.as_str().unwrap())
+        .collect();
+    assert_eq!(issues.len(), 4, "{:?}", issues);
+    assert!(issues[0].contains("maximum size"));
+    assert!(issues[1].contains("Invalid content type"));
+    assert!(issues[2].contains("shell.php"));
+    assert!(issues[3].contains("File limit reached"));
+
+    let body = preflight(&app, serde_json::json!({ "filename": "../.." })).await;
+    assert_eq!(body["ok"], false);
+    assert_eq!(body["issues"], serde_json::json!(["Invalid filename"]));
+    assert!(body["final_filename"].is_null());
+}
+
+#[actix_web::test]
+async fn archive_manifests_total_owned_files_and_list_the_rest() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+    upload_as(&app, "alice", "b.txt", b"hello world").await;
+    upload_as(&app, "bob", "theirs.txt", b"secret").await;
+
+    let req = TestRequest::post()
+        .uri("/api/files/archive/manifest")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .set_json(serde_json::json!({
+            "filenames": ["b.txt", "missing.txt", "a.txt", "theirs.txt", "b.txt"]
+        }))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+
+    let checksum = |name: &str| {
+        env.entries()
+            .into_iter()
+            .find(|entry| entry.filename == name)
+            .and_then(|entry| entry.checksum)
+            .unwrap()
+    };
+    assert_eq!(
+        body["files"],
+        serde_json::json!([
+            { "filename": "b.txt", "size_bytes": 11, "checksum": checksum("b.txt") },
+            { "filename": "a.txt", "size_bytes": 5, "checksum": checksum("a.txt") },
+        ])
+    );
+    assert_eq!(
+        body["missing"],
+        serde_json::json!(["missing.txt", "theirs.txt"])
+    );
+    assert_eq!(body["total_bytes"], 16);
+}
+
+#[actix_web::test]
+async fn stalled_disk_writes_time_out() {
+    // Nobody reads the other end, so writes stall once its buffer is full
+    let (mut writer, _reader) = tokio::io::duplex(16);
+    let stalled = writer.write_all(&[0; 64]);
+    let err = with_disk_timeout(stalled, Some(Duration::from_millis(20)), "write file")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.as_response_error().status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let done = with_disk_timeout(async { Ok(()) }, Some(Duration::from_millis(20)), "flush");
+    assert!(done.await.is_ok());
+}
+
+#[actix_web::test]
+async fn listings_default_to_the_configured_page_size() {
+    let env = TestEnv::new()
+        .with("LIST_DEFAULT_PER_PAGE", "2")
+        .with("LIST_MAX_PER_PAGE", "3");
+    env.seed(&[
+        UploadMetadata::new("a".into(), "alice".into(), 1),
+        UploadMetadata::new("b".into(), "alice".into(), 1),
+        UploadMetadata::new("c".into(), "alice".into(), 1),
+        UploadMetadata::new("d".into(), "alice".into(), 1),
+    ]);
+    let app = init_service(app()).await;
+
+    let resp = get_as(&app, "alice", "/api/files").await;
+    assert_eq!(header_of(&resp, "x-page-size-clamped"), "");
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["files"].as_array().unwrap().len(), 2);
+    let (page, _) = list_page(&app, "/api/files?limit=3").await;
+    assert_eq!(page.len(), 3);
+}
+
+#[test]
+fn list_paging_is_validated() {
+    let mut env = TestEnv::new();
+    env.remove("LIST_DEFAULT_PER_PAGE");
+    env.remove("LIST_MAX_PER_PAGE");
+    let paging = ListPaging::from_env().unwrap();
+    assert_eq!(paging.default_per_page, None);
+    assert_eq!(paging.max_per_page, 1000);
+
+    env.set("LIST_MAX_PER_PAGE", "0");
+    assert!(ListPaging::from_env().is_err());
+    env.set("LIST_MAX_PER_PAGE", "10");
+    env.set("LIST_DEFAULT_PER_PAGE", "many");
+    assert!(ListPaging::from_env().is_err());
+    env.set("LIST_DEFAULT_PER_PAGE", "20");
+    assert!(ListPaging::from_env().is_err());
+    env.set("LIST_DEFAULT_PER_PAGE", "10");
+    assert_eq!(ListPaging::from_env().unwrap().default_per_page, Some(10));
+}
+
+#[actix_web::test]
+async fn dispositions_follow_inline_content_types() {
+    let _env = TestEnv::new().with("INLINE_CONTENT_TYPES", "image/*,application/pdf");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "doc.pdf", b"%PDF-1.7\n").await;
+    upload_as(&app, "alice", "bundle.zip", b"PK\x05\x06").await;
+
+    let disposition = |resp: &ServiceResponse<_>| {
+        let value = header_of(resp, "content-disposition");
+        value.split(';').next().unwrap().to_string()
+    };
+    let resp = get_as(&app, "alice", "/api/files/doc.pdf").await;
+    assert_eq!(disposition(&resp), "inline");
+    let resp = get_as(&app, "alice", "/api/files/bundle.zip").await;
+    assert_eq!(disposition(&resp), "attachment");
+
+    // The query wins over the configuration either way
+    let resp = get_as(&app, "alice", "/api/files/doc.pdf?inline=false").await;
+    assert_eq!(disposition(&resp), "attachment");
+    let resp = get_as(&app, "alice", "/api/files/bundle.zip?inline=true").await;
+    assert_eq!(disposition(&resp), "inline");
+    let resp = get_as(&app, "alice", "/api/files/doc.pdf?inline=maybe").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn misnamed_uploads_get_their_extension_corrected() {
+    let env = TestEnv::new().with("CORRECT_EXTENSION", "true");
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "photo.txt", &png_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = upload_as(&app, "alice", "logo.png", &png_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    assert_eq!(env.stored_files(), ["logo.png", "photo.png"]);
+    let entries = env.entries();
+    let names: Vec<(&str, Option<&str>)> = entries
+        .iter()
+        .map(|entry| (entry.filename.as_str(), entry.original_filename.as_deref()))
+        .collect();
+    assert_eq!(
+        names,
+        [("photo.png", Some("photo.txt")), ("logo.png", None)]
+    );
+}
+
+#[actix_web::test]
+async fn piped_uploads_store_the_command_output() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let env = TestEnv::new().with("UPLOAD_PIPE_COMMAND", "gzip -c");
+    let app = init_service(app()).await;
+    let content = b"hello hello hello hello".repeat(100);
+    let resp = upload_as(&app, "alice", "a.txt", &content).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let stored = std::fs::read(env.uploads_dir().join("a.txt")).unwrap();
+    let mut unzipped = Vec::new();
+    GzDecoder::new(stored.as_slice())
+        .read_to_end(&mut unzipped)
+        .unwrap();
+    assert_eq!(unzipped, content);
+    let entry = &env.entries()[0];
+    assert_eq!(entry.size_bytes, stored.len() as u64);
+    assert_eq!(
+        entry.checksum.as_deref(),
+        Some(hex::encode(Sha256::digest(&stored)).as_str())
+    );
+}
+
+#[actix_web::test]
+async fn failing_pipe_commands_reject_the_upload() {
+    let env = TestEnv::new().with("UPLOAD_PIPE_COMMAND", "false");
+    let app = init_service(app()).await;
+    let resp = upload_as(&app, "alice", "a.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(env.stored_files().is_empty());
+    assert!(env.entries().is_empty());
+}
+
+#[actix_web::test]
+async fn uploads_stalled_on_disk_are_removed() {
+    // A pipe command that never reads its input stands in for a hung volume
+    let env = TestEnv::new()
+        .with("DISK_WRITE_TIMEOUT_SECS", "1")
+        .with("UPLOAD_PIPE_COMMAND", "sleep 2");
+    let target = env.uploads_dir().join("a.txt");
+    std::fs::create_dir_all(env.uploads_dir()).unwrap();
+    std::fs::write(&target, "previous").unwrap();
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "a.txt", &vec![b'x'; 1 << 20]).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(env.stored_files(), vec!["a.txt"]);
+    assert_eq!(std::fs::read_to_string(&target).unwrap(), "previous");
+    assert!(env.entries().is_empty());
+}
+
+#[actix_web::test]
+async fn uploads_pause_below_the_disk_soft_limit() {
+    let mut env = TestEnv::new();
+    std::fs::create_dir_all(env.uploads_dir()).unwrap();
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    // No real disk has this much free, so the limit is always crossed
+    env.set("DISK_SOFT_LIMIT_BYTES", &u64::MAX.to_string());
+    let resp = upload_as(&app, "alice", "b.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+    let resp = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+    let body: serde_json::Value = read_body_json(resp).await;
+    let disk = body["checks"]["disk"].as_str().unwrap();
+    assert!(disk.starts_with("uploads paused: "), "{}", disk);
+
+    // Once space is back above the limit uploads resume
+    env.set("DISK_SOFT_LIMIT_BYTES", "1");
+    let resp = upload_as(&app, "alice", "b.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["checks"]["disk"], "ok");
+    assert_eq!(env.stored_files(), ["a.txt", "b.txt"]);
+}
+
+/// Full response to an upload to `url` that sends half its body and then stalls
+async fn stalled_upload(url: &str, extra_headers: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (content_type, body) = Form::new().file("a.txt", &[b'x'; 4096]).finish();
+    let mut stream = tokio::net::TcpStream::connect(url.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    let head = format!(
+        "POST /api/upload HTTP/1.1\r\nHost: localhost\r\n{}: alice\r\n{}\
+         Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        TEST_USER_HEADER,
+        extra_headers,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&body[..body.len() / 2]).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[actix_web::test]
+async fn uploads_cut_off_at_the_deadline_are_removed() {
+    let env = TestEnv::new().with("REQUEST_DEADLINE_SECS", "1");
+    let url = serve();
+
+    let response = stalled_upload(&url, "").await;
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "{}",
+        response.lines().next().unwrap_or_default()
+    );
+    assert!(env.stored_files().is_empty());
+    assert!(env.entries().is_empty());
+
+    // A streamed response has already started, so the deadline ends up in the summary
+    let response = stalled_upload(&url, "Accept: application/x-ndjson\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("\"status_code\":503"), "{}", response);
+    assert!(env.stored_files().is_empty());
+}
+
+/// Status, content type and body of an empty upload sent with `accept`
+async fn empty_upload_error<S, B>(app: &S, accept: &str) -> (StatusCode, String, String)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let req = Form::new()
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("Accept", accept))
+        .to_request();
+    let resp = call_service(app, req).await;
+    let status = resp.status();
+    let content_type = header_of(&resp, "content-type");
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    (status, content_type, body)
+}
+
+#[actix_web::test]
+async fn errors_are_negotiated_by_accept() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let (status, content_type, body) = empty_upload_error(&app, "application/json").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "application/json");
+    assert_eq!(body, r#"{"error":"No file part found"}"#);
+
+    let (status, content_type, body) = empty_upload_error(
+        &app,
+        "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    assert!(body.contains("<title>400 Bad Request</title>"), "{}", body);
+    assert!(body.contains("<p>No file part found</p>"), "{}", body);
+}
+
+#[actix_web::test]
+async fn uploads_outside_the_window_are_refused_while_reads_continue() {
+    let mut env = TestEnv::new();
+    // A window that opened an hour ago and closes in an hour, then its opposite
+    let now = Utc::now();
+    let hhmm = |offset_hours: i64| (now + chrono::Duration::hours(offset_hours)).format("%H:%M");
+
+    env.set("UPLOAD_WINDOW", &format!("{}-{}", hhmm(-1), hhmm(1)));
+    let open = init_service(app()).await;
+    let resp = upload_as(&open, "alice", "a.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    env.set("UPLOAD_WINDOW", &format!("{}-{}", hhmm(1), hhmm(-1)));
+    let closed = init_service(app()).await;
+    let resp = upload_as(&closed, "alice", "b.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let retry_after: u32 = header_of(&resp, "retry-after").parse().unwrap();
+    assert!((3500..=3600).contains(&retry_after), "{}", retry_after);
+    let resp = get_as(&closed, "alice", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(env.stored_files(), ["a.txt"]);
+}
+
+#[actix_web::test]
+async fn the_global_upload_budget_is_shared_by_every_user() {
+    let mut env = TestEnv::new().with("GLOBAL_UPLOADS_PER_MINUTE", "2");
+    std::fs::create_dir_all(env.uploads_dir()).unwrap();
+    let app = init_service(app()).await;
+
+    // Uploads refused before the limit is consulted don't spend the budget
+    env.set("DISK_SOFT_LIMIT_BYTES", &u64::MAX.to_string());
+    let resp = upload_as(&app, "alice", "a.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::INSUFFICIENT_STORAGE);
+    env.remove("DISK_SOFT_LIMIT_BYTES");
+
+    for (user, name) in [("alice", "a.txt"), ("bob", "b.txt")] {
+        let resp = upload_as(&app, user, name, b"hello").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    let resp = upload_as(&app, "carol", "c.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = header_of(&resp, "retry-after").parse().unwrap();
+    assert!((1..=30).contains(&retry_after), "{}", retry_after);
+    assert_eq!(env.stored_files(), ["a.txt", "b.txt"]);
+}
+
+#[actix_web::test]
+async fn hashed_names_are_derived_from_the_content() {
+    let env = TestEnv::new().with("NAME_STRATEGY", "hashed");
+    let app = init_service(app()).await;
+    let resp = upload_as(&app, "alice", "greeting.txt", b"hello").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let stored = format!("{}.txt", hex::encode(Sha256::digest(b"hello")));
+    assert_eq!(env.stored_files(), [stored.as_str()]);
+    assert_eq!(env.entries()[0].filename, stored);
+}
+
+#[actix_web::test]
+async fn generated_names_are_type_checked_by_the_client_name() {
+    let env = TestEnv::new()
+        .with("NAME_STRATEGY", "uuid")
+        .with("ENFORCE_TYPE_EXTENSION_MATCH", "true");
+    let app = init_service(app()).await;
+
+    let resp = upload_as(
+        &app,
+        "alice",
+        "invoice.png",
+        b"%PDF-1.7\n%\xe2\xe3\xcf\xd3\n",
+    )
+    .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp = upload_as(&app, "alice", "photo.png", &png_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let stored = env.stored_files();
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].ends_with(".png"), "{:?}", stored);
+}
+
+/// Uploads `a.txt` as alice, declaring `content_type` for the file part
+async fn upload_declaring<S, B>(app: &S, content_type: Option<&str>) -> ServiceResponse<B>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let req = Form::new()
+        .part(Some("a.txt"), content_type, b"hello")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    call_service(app, req).await
+}
+
+#[actix_web::test]
+async fn parts_without_a_content_type_are_rejected_when_required() {
+    let mut env = TestEnv::new().with("REQUIRE_CONTENT_TYPE", "true");
+    let app = init_service(app()).await;
+
+    let resp = upload_declaring(&app, None).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "File part is missing a Content-Type");
+    assert!(env.stored_files().is_empty());
+
+    let resp = upload_declaring(&app, Some("text/plain")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    env.remove("REQUIRE_CONTENT_TYPE");
+    let resp = upload_declaring(&app, None).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+/// Leaf elements of an XML document, as name and text
+fn xml_fields(xml: &str) -> BTreeMap<String, String> {
+    let element = regex::Regex::new(r"<(\w+)>([^<]*)</(\w+)>").unwrap();
+    element
+        .captures_iter(xml)
+        .filter(|captures| captures[1] == captures[3])
+        .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+        .collect()
+}
+
+/// Leaf values of a JSON document by field name, rendered as [`xml_fields`] sees them
+fn json_fields(value: &serde_json::Value, fields: &mut BTreeMap<String, String>) {
+    for (name, value) in value.as_object().unwrap() {
+        match value {
+            serde_json::Value::Object(_) => json_fields(value, fields),
+            serde_json::Value::String(text) => {
+                fields.insert(name.clone(), text.clone());
+            }
+            _ => {
+                fields.insert(name.clone(), value.to_string());
+            }
+        }
+    }
+}
+
+#[actix_web::test]
+async fn uploads_answer_in_xml_when_preferred() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+    let json: serde_json::Value =
+        read_body_json(upload_as(&app, "alice", "a.txt", b"hello").await).await;
+
+    let req = Form::new()
+        .file("a.txt", b"hello")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("Accept", "application/xml"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        header_of(&resp, "content-type"),
+        "application/xml; charset=utf-8"
+    );
+    let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert!(body.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<upload>"));
+    assert!(body.ends_with("</upload>\n"));
+
+    let mut fields = xml_fields(&body);
+    let mut expected = BTreeMap::new();
+    json_fields(&json, &mut expected);
+    // The two uploads were stored at different times
+    fields.remove("timestamp");
+    expected.remove("timestamp");
+    assert_eq!(fields, expected);
+}
+
+/// Uploads `a.txt` containing `hello` as alice with `X-Tree-Hash: tree_hash`
+async fn upload_with_tree_hash<S, B>(app: &S, tree_hash: &str) -> ServiceResponse<B>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let req = Form::new()
+        .file("a.txt", b"hello")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("X-Tree-Hash", tree_hash))
+        .to_request();
+    call_service(app, req).await
+}
+
+#[actix_web::test]
+async fn tree_hashes_are_verified_and_stored() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+    // Under one chunk the tree hash is the content's SHA-256
+    let digest = hex::encode(Sha256::digest(b"hello"));
+
+    let resp = upload_with_tree_hash(&app, &hex::encode(Sha256::digest(b"other"))).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(env.stored_files().is_empty());
+    let resp = upload_with_tree_hash(&app, "not-a-hash").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = upload_with_tree_hash(&app, &digest.to_uppercase()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(env.entries()[0].tree_hash.as_deref(), Some(digest.as_str()));
+}
+
+#[actix_web::test]
+async fn role_default_tags_join_the_client_tags() {
+    let env = TestEnv::new().with("ROLE_DEFAULT_TAGS", "finance:department=finance,q3");
+    let app = init_service(app()).await;
+    let upload = |user: &str, roles: &str, name: &str| {
+        Form::new()
+            .file(name, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, user))
+            .insert_header((TEST_ROLES_HEADER, roles))
+            .insert_header(("X-Upload-Tags", "q3, invoices"))
+            .to_request()
+    };
+
+    let resp = call_service(&app, upload("alice", "user,finance", "a.txt")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = call_service(&app, upload("bob", "user", "b.txt")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let tags: Vec<Vec<String>> = env.entries().into_iter().map(|entry| entry.tags).collect();
+    assert_eq!(
+        tags,
+        [
+            vec!["q3", "invoices", "department=finance"],
+            vec!["q3", "invoices"],
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn grayscale_downloads_are_transformed_once_then_cached() {
+    let mut env = TestEnv::new().with("DOWNLOAD_TRANSFORMS_ENABLED", "true");
+    env.set(
+        "TRANSCODE_CACHE_DIR",
+        &env.path().join("cache").display().to_string(),
+    );
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "pic.png", &png_bytes()).await;
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png?transform=grayscale").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header_of(&resp, "content-type"), "image/png");
+    assert_eq!(header_of(&resp, "x-cache"), "MISS");
+    let gray = read_body(resp).await;
+    assert_ne!(gray, png_bytes());
+    let decoded = image::load_from_memory(&gray).unwrap().to_rgb8();
+    assert!(decoded.pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png?transform=grayscale").await;
+    assert_eq!(header_of(&resp, "x-cache"), "HIT");
+    assert_eq!(read_body(resp).await, gray);
+
+    let resp = get_as(&app, "alice", "/api/files/pic.png?transform=grayscale,webp").await;
+    assert_eq!(header_of(&resp, "content-type"), "image/webp");
+    assert_eq!(header_of(&resp, "x-cache"), "MISS");
+}
+
+#[actix_web::test]
+async fn unsupported_transforms_are_bad_requests() {
+    let mut env = TestEnv::new().with("DOWNLOAD_TRANSFORMS_ENABLED", "true");
+    env.set(
+        "TRANSCODE_CACHE_DIR",
+        &env.path().join("cache").display().to_string(),
+    );
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "pic.png", &png_bytes()).await;
+    upload_as(&app, "alice", "notes.txt", b"plain text").await;
+
+    for uri in [
+        "/api/files/pic.png?transform=watermark",
+        "/api/files/notes.txt?transform=grayscale",
+    ] {
+        let resp = get_as(&app, "alice", uri).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+    env.remove("DOWNLOAD_TRANSFORMS_ENABLED");
+    let resp = get_as(&app, "alice", "/api/files/pic.png?transform=grayscale").await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+/// The lines of a multi-file upload streamed as NDJSON
+async fn ndjson_upload<S, B>(app: &S, form: Form) -> Vec<serde_json::Value>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody,
+{
+    let req = form
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("Accept", "application/x-ndjson"))
+        .to_request();
+    let resp = call_service(app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header_of(&resp, "content-type"), "application/x-ndjson");
+    let body = read_body(resp).await;
+    body.split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect()
+}
+
+#[actix_web::test]
+async fn ndjson_uploads_stream_a_line_per_file_then_a_summary() {
+    let env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let form = Form::new()
+        .file("one.txt", b"1")
+        .file("two.txt", b"22")
+        .file("three.txt", b"333");
+    let lines = ndjson_upload(&app, form).await;
+    assert_eq!(lines.len(), 4);
+    let files: Vec<(&str, u64)> = lines[..3]
+        .iter()
+        .map(|line| {
+            assert_eq!(line["type"], "file");
+            (
+                line["filename"].as_str().unwrap(),
+                line["size_bytes"].as_u64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(files, [("one.txt", 1), ("two.txt", 2), ("three.txt", 3)]);
+    assert_eq!(lines[3]["type"], "summary");
+    assert_eq!(lines[3]["status"], "success");
+    assert_eq!(lines[3]["status_code"], 200);
+    assert_eq!(lines[3]["files"], 3);
+    assert_eq!(env.stored_files().len(), 3);
+}
+
+#[actix_web::test]
+async fn ndjson_upload_failures_are_reported_in_the_summary() {
+    let env = TestEnv::new().with("FILENAME_REGEX", r"\.txt$");
+    let app = init_service(app()).await;
+
+    let form = Form::new().file("ok.txt", b"ok").file("image.png", b"png");
+    let lines = ndjson_upload(&app, form).await;
+    let summary = lines.last().unwrap();
+    assert_eq!(summary["type"], "summary");
+    assert_eq!(summary["status"], "error");
+    assert_eq!(summary["status_code"], 400);
+    assert_eq!(summary["files"], 0);
+    assert!(env.stored_files().is_empty());
+}
+
+#[actix_web::test]
+async fn uploads_answer_with_json_unless_ndjson_is_preferred() {
+    let _env = TestEnv::new();
+    let app = init_service(app()).await;
+
+    let req = Form::new()
+        .file("a.txt", b"a")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("Accept", "application/x-ndjson;q=0.5, application/json"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(header_of(&resp, "content-type"), "application/json");
+}
+
+#[actix_web::test]
+async fn client_tags_are_limited_in_number_and_length() {
+    let env = TestEnv::new()
+        .with("MAX_TAGS_PER_FILE", "2")
+        .with("MAX_TAG_LEN", "8");
+    let app = init_service(app()).await;
+    let upload = |name: &str, tags: &str| {
+        Form::new()
+            .file(name, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .insert_header(("X-Upload-Tags", tags))
+            .to_request()
+    };
+
+    let resp = call_service(&app, upload("a.txt", "q3,invoices,extra")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = call_service(&app, upload("b.txt", "q3,overlong-tag")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(env.entries().is_empty());
+
+    let resp = call_service(&app, upload("c.txt", " q3 , invoices ")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(env.entries()[0].tags, ["q3", "invoices"]);
+}
+
+/// `Content-Encoding` of a file listing of `files` entries fetched with gzip accepted
+async fn listing_encoding(files: usize) -> String {
+    let env = TestEnv::new()
+        .with("COMPRESS_RESPONSES", "true")
+        .with("COMPRESSION_MIN_BYTES", "2048");
+    let entries: Vec<UploadMetadata> = (0..files)
+        .map(|i| UploadMetadata::new(format!("file-{}.txt", i), "alice".into(), 10))
+        .collect();
+    env.seed(&entries);
+    let app = init_service(app()).await;
+
+    let req = TestRequest::get()
+        .uri("/api/files")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("Accept-Encoding", "gzip"))
+        .to_request();
+    let resp = call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    header_of(&resp, "content-encoding")
+}
+
+#[actix_web::test]
+async fn only_responses_above_the_threshold_are_compressed() {
+    assert_ne!(listing_encoding(1).await, "gzip");
+    assert_eq!(listing_encoding(50).await, "gzip");
+}
+
+#[actix_web::test]
+async fn polyglot_images_are_rejected_when_configured() {
+    let mut env = TestEnv::new();
+    env.remove("REJECT_POLYGLOTS");
+    let app = init_service(app()).await;
+    let polyglot: &[u8] = b"GIF89a/*\x01\x00\x01\x00\x00\x00\x00;*/=1;alert(document.domain);";
+
+    let resp = upload_as(&app, "alice", "a.gif", polyglot).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    env.set("REJECT_POLYGLOTS", "true");
+    let resp = upload_as(&app, "alice", "b.gif", polyglot).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(env.stored_files(), ["a.gif"]);
+
+    let resp = upload_as(&app, "alice", "c.png", &png_bytes()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn payload_too_large_names_the_limit_that_was_hit() {
+    let mut env = TestEnv::new().with("MAX_UPLOAD_BYTES", "1024");
+    env.remove("MAX_FILES_PER_USER");
+    let app = init_service(app()).await;
+
+    let resp = upload_as(&app, "alice", "big.bin", &[0u8; 4096]).await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["limit"], "request_bytes");
+    assert_eq!(body["value"], 1024);
+    assert_eq!(
+        body["error"],
+        "Upload exceeds the maximum size of 1024 bytes"
+    );
+
+    env.set("MAX_FILES_PER_USER", "1");
+    assert_eq!(
+        upload_as(&app, "alice", "a.txt", b"1").await.status(),
+        StatusCode::OK
+    );
+    let resp = upload_as(&app, "alice", "b.txt", b"2").await;
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["limit"], "files_per_user");
+    assert_eq!(body["value"], 1);
+}
+
+#[actix_web::test]
+async fn downloads_beyond_the_limit_get_503_until_a_permit_frees() {
+    let _env = TestEnv::new().with("MAX_CONCURRENT_DOWNLOADS", "1");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"hello").await;
+
+    // The permit is held until the body has been sent
+    let streaming = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(streaming.status(), StatusCode::OK);
+
+    let resp = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(header_of(&resp, "retry-after"), "1");
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["error"], "too_many_downloads");
+
+    assert_eq!(read_body(streaming).await.as_ref(), b"hello");
+    let resp = get_as(&app, "alice", "/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn case_differing_names_share_one_lowercased_file() {
+    let mut env = TestEnv::new().with("LOWERCASE_FILENAMES", "true");
+    env.remove("COLLISION_POLICY");
+    let app = init_service(app()).await;
+
+    upload_as(&app, "alice", "Photo.JPG", b"first").await;
+    let resp = upload_as(&app, "alice", "photo.jpg", b"second").await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["filename"], "photo.jpg");
+    assert_eq!(env.stored_files(), ["photo.jpg"]);
+    assert_eq!(
+        std::fs::read(env.uploads_dir().join("photo.jpg")).unwrap(),
+        b"second"
+    );
+
+    env.set("COLLISION_POLICY", "reject");
+    let resp = upload_as(&app, "alice", "PHOTO.jpg", b"third").await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn plaintext_uploads_need_an_upgrade_when_https_is_required() {
+    let env = TestEnv::new().with("REQUIRE_HTTPS", "true");
+    let app = init_service(app()).await;
+    let upload = |name: &str, proto: &str| {
+        Form::new()
+            .file(name, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .insert_header(("X-Forwarded-Proto", proto))
+            .to_request()
+    };
+
+    let resp = call_service(&app, upload("a.txt", "http")).await;
+    assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+    assert_eq!(header_of(&resp, "upgrade"), "TLS/1.2, HTTP/1.1");
+    assert!(env.entries().is_empty());
+
+    let resp = call_service(&app, upload("b.txt", "https")).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Reads stay available over plaintext
+    let req = TestRequest::get()
+        .uri("/api/files/b.txt")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .insert_header(("X-Forwarded-Proto", "http"))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+/// A three-file upload whose middle file breaks `FILENAME_REGEX=\.txt$`
+fn batch_with_one_invalid_file() -> TestRequest {
+    Form::new()
+        .file("one.txt", b"1")
+        .file("bad.png", b"png")
+        .file("two.txt", b"2")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+}
+
+#[actix_web::test]
+async fn all_or_nothing_uploads_roll_back_on_any_invalid_file() {
+    let mut env = TestEnv::new().with("FILENAME_REGEX", r"\.txt$");
+    env.remove("MULTI_UPLOAD_POLICY");
+    let app = init_service(app()).await;
+
+    let resp = call_service(&app, batch_with_one_invalid_file().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert!(env.stored_files().is_empty());
+    assert!(env.entries().is_empty());
+}
+
+#[actix_web::test]
+async fn best_effort_uploads_keep_the_valid_files() {
+    let env = TestEnv::new()
+        .with("FILENAME_REGEX", r"\.txt$")
+        .with("MULTI_UPLOAD_POLICY", "best-effort");
+    let app = init_service(app()).await;
+
+    let resp = call_service(&app, batch_with_one_invalid_file().to_request()).await;
+    assert_eq!(resp.status(), StatusCode::MULTI_STATUS);
+    let body: serde_json::Value = read_body_json(resp).await;
+    assert_eq!(body["status"], "partial");
+    assert_eq!(body["message"], "2 files uploaded, 1 rejected");
+    assert_eq!(body["files"][0]["filename"], "one.txt");
+    assert_eq!(body["files"][1]["filename"], "two.txt");
+    assert_eq!(body["failed"][0]["filename"], "bad.png");
+    assert_eq!(body["failed"][0]["status_code"], 400);
+    assert_eq!(env.stored_files(), ["one.txt", "two.txt"]);
+    assert_eq!(env.entries().len(), 2);
+
+    // With nothing valid the request fails as a single bad file would
+    let req = Form::new()
+        .file("bad.png", b"png")
+        .post("/api/upload")
+        .insert_header((TEST_USER_HEADER, "alice"))
+        .to_request();
+    assert_eq!(
+        call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[test]
+fn multi_upload_policy_defaults_to_all_or_nothing() {
+    let mut env = TestEnv::new();
+    env.remove("MULTI_UPLOAD_POLICY");
+    assert_eq!(
+        MultiUploadPolicy::from_env(),
+        MultiUploadPolicy::AllOrNothing
+    );
+    env.set("MULTI_UPLOAD_POLICY", "Best-Effort");
+    assert_eq!(MultiUploadPolicy::from_env(), MultiUploadPolicy::BestEffort);
+    env.set("MULTI_UPLOAD_POLICY", "sometimes");
+    assert_eq!(
+        MultiUploadPolicy::from_env(),
+        MultiUploadPolicy::AllOrNothing
+    );
+}
+
+#[actix_web::test]
+async fn small_downloads_are_served_from_memory_until_changed() {
+    let env = TestEnv::new()
+        .with("DOWNLOAD_CACHE_BYTES", "4096")
+        .with("DOWNLOAD_CACHE_MAX_FILE_BYTES", "64");
+    let app = init_service(app()).await;
+    upload_as(&app, "alice", "a.txt", b"first").await;
+    upload_as(&app, "alice", "big.txt", &[b'x'; 100]).await;
+
+    let download = |name: &'static str| get_as(&app, "alice", name);
+    let resp = download("/api/files/a.txt").await;
+    assert_eq!(header_of(&resp, "x-cache"), "MISS");
+    let resp = download("/api/files/a.txt").await;
+    assert_eq!(header_of(&resp, "x-cache"), "HIT");
+    assert_eq!(read_body(resp).await.as_ref(), b"first");
+    let resp = download("/api/files/big.txt").await;
+    assert_eq!(header_of(&resp, "x-cache"), "");
+
+    // Overwriting replaces the cached copy
+    upload_as(&app, "alice", "a.txt", b"second").await;
+    let resp = download("/api/files/a.txt").await;
+    assert_eq!(header_of(&resp, "x-cache"), "MISS");
+    assert_eq!(read_body(resp).await.as_ref(), b"second");
+
+    std::fs::remove_file(env.uploads_dir().join("a.txt")).unwrap();
+    let resp = download("/api/files/a.txt").await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,344 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::metadata::{update_metadata, UploadMetadata};
+
+/// Where upload notifications go, from `POST_UPLOAD_HOOK`
+#[derive(Debug, Clone)]
+pub enum PostUploadHook {
+    /// JSON `POST` of the metadata to an `http(s)://` URL
+    Url(String),
+    /// Program and arguments, run without a shell; the metadata is passed as JSON
+    /// on stdin and as `UPLOAD_*` environment variables
+    Command(Vec<String>),
+}
+
+impl PostUploadHook {
+    /// Reads `POST_UPLOAD_HOOK`; unset or empty disables the hook
+    pub fn from_env() -> Option<Self> {
+        let hook = env::var("POST_UPLOAD_HOOK").ok()?;
+        let hook = hook.trim();
+        if hook.is_empty() {
+            return None;
+        }
+        if hook.starts_with("http://") || hook.starts_with("https://") {
+            return Some(Self::Url(hook.to_string()));
+        }
+        Some(Self::Command(
+            hook.split_whitespace().map(str::to_string).collect(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Delivered,
+    Failed,
+    TimedOut,
+}
+
+/// Outcome of the post-upload hook for one upload, recorded on its metadata entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookDelivery {
+    pub status: DeliveryStatus,
+    /// When the hook finished, failed or was abandoned
+    #[serde(deserialize_with = "crate::timestamps::deserialize")]
+    pub at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How long a hook may run before it is abandoned, from `POST_UPLOAD_HOOK_TIMEOUT_SECS`
+fn hook_timeout() -> Duration {
+    Duration::from_secs(
+        env::var("POST_UPLOAD_HOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30),
+    )
+}
+
+/// Strips control characters so values are safe to hand to scripts
+fn env_value(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Runs the configured hook for a stored upload in the background, then records the
+/// outcome on the upload's entry in `metadata_file`; failures are otherwise only logged
+pub fn spawn_post_upload_hook(metadata: &UploadMetadata, metadata_file: &str) {
+    let Some(hook) = PostUploadHook::from_env() else {
+        return;
+    };
+    let metadata = metadata.clone();
+    let metadata_file = metadata_file.to_string();
+    actix_web::rt::spawn(async move {
+        let filename = metadata.filename.clone();
+        let (status, error) =
+            match tokio::time::timeout(hook_timeout(), run_hook(&hook, &metadata)).await {
+                Ok(Ok(())) => {
+                    log::info!("Post-upload hook succeeded for {}", filename);
+                    (DeliveryStatus::Delivered, None)
+                }
+                Ok(Err(e)) => {
+                    log::warn!("Post-upload hook failed for {}: {}", filename, e);
+                    (DeliveryStatus::Failed, Some(e))
+                }
+                Err(_) => {
+                    log::warn!("Post-upload hook timed out for {}", filename);
+                    (DeliveryStatus::TimedOut, None)
+                }
+            };
+        let delivery = HookDelivery {
+            status,
+            at: Utc::now().to_rfc3339(),
+            error,
+        };
+        let recorded = update_metadata(&metadata_file, |entries| {
+            if let Some(stored) = entries.iter_mut().rev().find(|stored| {
+                stored.filename == metadata.filename && stored.timestamp == metadata.timestamp
+            }) {
+                stored.hook_delivery = Some(delivery);
+            }
+        });
+        if let Err(e) = recorded {
+            log::warn!("Failed to record hook delivery for {}: {}", filename, e);
+        }
+    });
+}
+
+async fn run_hook(hook: &PostUploadHook, metadata: &UploadMetadata) -> Result<(), String> {
+    let body = serde_json::to_vec(metadata).map_err(|e| e.to_string())?;
+    match hook {
+        PostUploadHook::Url(url) => {
+            let response = reqwest::Client::new()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("hook returned {}", response.status()));
+            }
+            Ok(())
+        }
+        PostUploadHook::Command(argv) => {
+            let (program, args) = argv.split_first().ok_or("empty hook command")?;
+            let mut child = Command::new(program)
+                .args(args)
+                .env("UPLOAD_FILENAME", env_value(&metadata.filename))
+                .env("UPLOAD_USER", env_value(&metadata.user))
+                .env("UPLOAD_SIZE_BYTES", metadata.size_bytes.to_string())
+                .env("UPLOAD_TIMESTAMP", env_value(&metadata.timestamp))
+                .env(
+                    "UPLOAD_CHECKSUM",
+                    env_value(metadata.checksum.as_deref().unwrap_or_default()),
+                )
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| format!("failed to start {}: {}", program, e))?;
+            if let Some(mut stdin) = child.stdin.take() {
+                // A hook that ignores stdin may exit before reading it
+                if let Err(e) = stdin.write_all(&body).await {
+                    if e.kind() != std::io::ErrorKind::BrokenPipe {
+                        return Err(e.to_string());
+                    }
+                }
+            }
+            let status = child.wait().await.map_err(|e| e.to_string())?;
+            if !status.success() {
+                return Err(format!("hook exited with {}", status));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use sha2::{Digest, Sha256};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    /// Installs a hook script that saves its stdin and `UPLOAD_*` variables in `dir`
+    fn recording_hook(dir: &Path) -> String {
+        let script = dir.join("hook.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncat > \"$1/stdin.json\"\n\
+             printf '%s|%s|%s|%s' \"$UPLOAD_FILENAME\" \"$UPLOAD_USER\" \
+             \"$UPLOAD_SIZE_BYTES\" \"$UPLOAD_CHECKSUM\" > \"$1/env.txt\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        format!("{} {}", script.display(), dir.display())
+    }
+
+    /// Waits for the background hook to write `path`
+    async fn read_when_written(path: &Path) -> String {
+        for _ in 0..250 {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                if !content.is_empty() {
+                    return content;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} was never written", path.display());
+    }
+
+    #[test]
+    fn hook_is_a_url_or_a_command() {
+        let mut env = TestEnv::new();
+        env.remove("POST_UPLOAD_HOOK");
+        assert!(PostUploadHook::from_env().is_none());
+        env.set("POST_UPLOAD_HOOK", "  ");
+        assert!(PostUploadHook::from_env().is_none());
+        env.set("POST_UPLOAD_HOOK", "https://hooks.example/upload");
+        assert!(matches!(
+            PostUploadHook::from_env(),
+            Some(PostUploadHook::Url(_))
+        ));
+        env.set("POST_UPLOAD_HOOK", "/usr/bin/notify --quiet");
+        match PostUploadHook::from_env() {
+            Some(PostUploadHook::Command(argv)) => assert_eq!(argv, ["/usr/bin/notify", "--quiet"]),
+            other => panic!("unexpected hook {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn command_hook_receives_the_upload_metadata() {
+        let mut env = TestEnv::new();
+        let out = env.path();
+        env.set("POST_UPLOAD_HOOK", &recording_hook(&out));
+        let app = init_service(app()).await;
+
+        // Passed without a shell, so the name reaches the script verbatim
+        let filename = "a$(touch pwned).txt";
+        let req = Form::new()
+            .file(filename, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        assert!(call_service(&app, req).await.status().is_success());
+
+        let checksum = hex::encode(Sha256::digest(b"hello"));
+        let vars = read_when_written(&out.join("env.txt")).await;
+        assert_eq!(vars, format!("{}|alice|5|{}", filename, checksum));
+        let stdin: serde_json::Value =
+            serde_json::from_str(&read_when_written(&out.join("stdin.json")).await).unwrap();
+        assert_eq!(stdin["filename"], filename);
+        assert_eq!(stdin["user"], "alice");
+        assert_eq!(stdin["size_bytes"], 5);
+        assert_eq!(stdin["checksum"], checksum.as_str());
+        assert!(!out.join("pwned").exists());
+    }
+
+    #[test]
+    fn control_characters_are_stripped_from_hook_values() {
+        assert_eq!(env_value("a\nb\r\x1b[31mc"), "ab[31mc");
+    }
+
+    /// A webhook receiver answering `/ok` with 204 and `/broken` with 500
+    fn webhook_receiver() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/ok", web::post().to(HttpResponse::NoContent))
+                .route("/broken", web::post().to(HttpResponse::InternalServerError))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    /// Uploads `filename` and polls its delivery until the hook has been recorded
+    async fn delivery_after_upload<S, B>(app: &S, filename: &str) -> serde_json::Value
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: actix_web::body::MessageBody,
+    {
+        let req = Form::new()
+            .file(filename, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        assert_eq!(call_service(app, req).await.status(), StatusCode::OK);
+        for _ in 0..250 {
+            let req = TestRequest::get()
+                .uri(&format!("/api/files/{}/delivery", filename))
+                .insert_header((TEST_USER_HEADER, "alice"))
+                .to_request();
+            let resp = call_service(app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = read_body_json(resp).await;
+            if body["delivery"]["status"] != "pending" {
+                return body;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("delivery of {} was never recorded", filename);
+    }
+
+    #[actix_web::test]
+    async fn webhook_deliveries_are_recorded_per_upload() {
+        let receiver = webhook_receiver();
+        let mut env = TestEnv::new();
+        env.set("POST_UPLOAD_HOOK", &format!("{}/ok", receiver));
+        let app = init_service(app()).await;
+
+        let body = delivery_after_upload(&app, "a.txt").await;
+        assert_eq!(body["filename"], "a.txt");
+        assert_eq!(body["delivery"]["status"], "delivered");
+        assert!(body["delivery"]["at"].is_string());
+        assert!(body["delivery"].get("error").is_none());
+        let recorded = env.entries()[0].hook_delivery.clone().unwrap();
+        assert_eq!(recorded.status, DeliveryStatus::Delivered);
+
+        env.set("POST_UPLOAD_HOOK", &format!("{}/broken", receiver));
+        let body = delivery_after_upload(&app, "b.txt").await;
+        assert_eq!(body["delivery"]["status"], "failed");
+        assert_eq!(
+            body["delivery"]["error"],
+            "hook returned 500 Internal Server Error"
+        );
+    }
+
+    #[actix_web::test]
+    async fn delivery_is_reported_as_not_configured_without_a_hook() {
+        let mut env = TestEnv::new();
+        env.remove("POST_UPLOAD_HOOK");
+        let app = init_service(app()).await;
+
+        let body = delivery_after_upload(&app, "a.txt").await;
+        assert_eq!(body["delivery"]["status"], "not_configured");
+        assert!(env.entries()[0].hook_delivery.is_none());
+    }
+
+    #[actix_web::test]
+    async fn slow_hooks_are_recorded_as_timed_out() {
+        let mut env = TestEnv::new().with("POST_UPLOAD_HOOK_TIMEOUT_SECS", "0");
+        env.set("POST_UPLOAD_HOOK", "sleep 5");
+        let app = init_service(app()).await;
+
+        let body = delivery_after_upload(&app, "a.txt").await;
+        assert_eq!(body["delivery"]["status"], "timed_out");
+    }
+}
@@ -0,0 +1,167 @@
+use image::codecs::webp::WebPEncoder;
+use image::{ImageFormat, ImageReader};
+use std::env;
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Directory derived images are cached in, from `TRANSCODE_CACHE_DIR`
+pub fn cache_dir() -> PathBuf {
+    PathBuf::from(env::var("TRANSCODE_CACHE_DIR").unwrap_or_else(|_| "./cache".to_string()))
+}
+
+/// Whether `GET /api/files/{filename}/webp` is enabled, from `WEBP_TRANSCODE_ENABLED`
+pub fn webp_enabled() -> bool {
+    env::var("WEBP_TRANSCODE_ENABLED")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Whether downloads honor `?transform=`, from `DOWNLOAD_TRANSFORMS_ENABLED`
+pub fn transforms_enabled() -> bool {
+    env::var("DOWNLOAD_TRANSFORMS_ENABLED")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// One step of a `?transform=` pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Grayscale,
+    /// Re-encodes without changing pixels, which drops EXIF and other embedded metadata
+    StripMetadata,
+    /// Re-encodes as WebP instead of the stored format
+    Webp,
+}
+
+impl Transform {
+    fn name(self) -> &'static str {
+        match self {
+            Transform::Grayscale => "grayscale",
+            Transform::StripMetadata => "strip-metadata",
+            Transform::Webp => "webp",
+        }
+    }
+}
+
+/// Parses `?transform=` as comma-separated transforms, applied in order
+pub fn parse_transforms(value: &str) -> Result<Vec<Transform>, String> {
+    let transforms = value
+        .split(',')
+        .map(|name| match name.trim() {
+            "grayscale" => Ok(Transform::Grayscale),
+            "strip-metadata" => Ok(Transform::StripMetadata),
+            "webp" => Ok(Transform::Webp),
+            other => Err(format!(
+                "Unknown transform '{}', expected grayscale, strip-metadata or webp",
+                other
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(transforms)
+}
+
+/// Identifies a pipeline in cache file names, e.g. `grayscale+webp`
+pub fn transforms_key(transforms: &[Transform]) -> String {
+    transforms
+        .iter()
+        .map(|transform| transform.name())
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// Format the pipeline's output is encoded in: WebP when requested, else the source's
+pub fn output_format(transforms: &[Transform], source: ImageFormat) -> ImageFormat {
+    if transforms.contains(&Transform::Webp) {
+        ImageFormat::WebP
+    } else {
+        source
+    }
+}
+
+/// Format `source` is stored in, guessed from its content; `None` when it isn't an image
+pub fn image_format(source: &Path) -> Result<Option<ImageFormat>, String> {
+    ImageReader::open(source)
+        .and_then(|reader| reader.with_guessed_format())
+        .map(|reader| reader.format())
+        .map_err(|e| format!("Failed to open {}: {}", source.display(), e))
+}
+
+/// Decodes `source`, applies `transforms` and writes the result to `dest` in `format`.
+///
+/// Returns `Ok(false)` when `source` can't be decoded or `format` can't be encoded.
+pub fn transform_image(
+    source: &Path,
+    transforms: &[Transform],
+    format: ImageFormat,
+    dest: &Path,
+) -> Result<bool, String> {
+    let mut image = match ImageReader::open(source)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(image::ImageError::IoError)
+        .and_then(|reader| reader.decode())
+    {
+        Ok(image) => image,
+        Err(e) => {
+            log::warn!("Failed to decode {} as an image: {}", source.display(), e);
+            return Ok(false);
+        }
+    };
+    for transform in transforms {
+        if *transform == Transform::Grayscale {
+            image = image.grayscale();
+        }
+    }
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create cache dir: {}", e))?;
+    }
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".partial");
+    let file =
+        fs::File::create(&partial).map_err(|e| format!("Failed to create cache file: {}", e))?;
+    if let Err(e) = image.write_to(BufWriter::new(file), format) {
+        let _ = fs::remove_file(&partial);
+        if let image::ImageError::Unsupported(_) = e {
+            log::warn!("Cannot encode {} as {:?}: {}", source.display(), format, e);
+            return Ok(false);
+        }
+        return Err(format!("Failed to encode {:?}: {}", format, e));
+    }
+    fs::rename(&partial, dest).map_err(|e| format!("Failed to store transformed image: {}", e))?;
+    Ok(true)
+}
+
+/// Decodes `source` and writes it to `dest` as lossless WebP.
+///
+/// Returns `Ok(false)` when `source` isn't a decodable image.
+pub fn transcode_to_webp(source: &Path, dest: &Path) -> Result<bool, String> {
+    let reader = ImageReader::open(source)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Failed to open {}: {}", source.display(), e))?;
+    if reader.format().is_none() {
+        return Ok(false);
+    }
+    let image = match reader.decode() {
+        Ok(image) => image,
+        Err(e) => {
+            log::warn!("Failed to decode {} as an image: {}", source.display(), e);
+            return Ok(false);
+        }
+    };
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create cache dir: {}", e))?;
+    }
+    // Write to a temporary name first so concurrent readers never see a partial file
+    let partial = dest.with_extension("webp.partial");
+    let file =
+        fs::File::create(&partial).map_err(|e| format!("Failed to create cache file: {}", e))?;
+    // The WebP encoder only supports 8-bit RGB(A)
+    let image = image::DynamicImage::ImageRgba8(image.to_rgba8());
+    image
+        .write_with_encoder(WebPEncoder::new_lossless(BufWriter::new(file)))
+        .map_err(|e| format!("Failed to encode WebP: {}", e))?;
+    fs::rename(&partial, dest).map_err(|e| format!("Failed to store cached WebP: {}", e))?;
+    Ok(true)
+}
@@ -0,0 +1,440 @@
+use actix_web::web;
+use reqwest::header::{HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// Whether workers share one JWKS cache, from `JWKS_CACHE_SCOPE`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JwksCacheScope {
+    /// One cache for the process: keys are fetched once for all workers
+    Shared,
+    /// Every worker fetches and caches the keys on its own
+    PerWorker,
+}
+
+impl JwksCacheScope {
+    /// Reads `JWKS_CACHE_SCOPE`, defaulting to `shared`
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("JWKS_CACHE_SCOPE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "shared" => Ok(Self::Shared),
+            "per-worker" => Ok(Self::PerWorker),
+            other => Err(format!(
+                "Invalid JWKS_CACHE_SCOPE '{}', expected shared or per-worker",
+                other
+            )),
+        }
+    }
+
+    /// The cache a worker registers as app data: `shared`, built once at startup, or a
+    /// new one per worker. Call from the `HttpServer` app factory, which runs per worker.
+    pub fn worker_cache(self, shared: &web::Data<JwksCache>) -> web::Data<JwksCache> {
+        match self {
+            Self::Shared => shared.clone(),
+            Self::PerWorker => web::Data::new(JwksCache::new()),
+        }
+    }
+}
+
+/// How long a key dropped from the JWKS still validates tokens, from `OLD_KEY_GRACE_SECS`
+/// (default 0, dropped keys are forgotten on the next fetch)
+fn old_key_grace() -> Duration {
+    Duration::from_secs(
+        env::var("OLD_KEY_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+    )
+}
+
+/// A fetched key set, indexed by `kid` so lookups don't scan every key
+pub struct Jwks {
+    keys: Vec<Value>,
+    by_kid: HashMap<String, usize>,
+    /// Keys carried over from an earlier fetch, with when a fetch first found them gone
+    retired_at: HashMap<String, Instant>,
+}
+
+impl Jwks {
+    fn from_value(jwks: Value) -> Result<Self, actix_web::Error> {
+        let keys = match jwks {
+            Value::Object(mut jwks) => match jwks.remove("keys") {
+                Some(Value::Array(keys)) => keys,
+                _ => return Err(actix_web::error::ErrorUnauthorized("Invalid JWKS format")),
+            },
+            _ => return Err(actix_web::error::ErrorUnauthorized("Invalid JWKS format")),
+        };
+        let mut by_kid = HashMap::with_capacity(keys.len());
+        for (index, key) in keys.iter().enumerate() {
+            if let Some(kid) = key["kid"].as_str() {
+                // First key wins, matching a linear search
+                by_kid.entry(kid.to_string()).or_insert(index);
+            }
+        }
+        Ok(Self {
+            keys,
+            by_kid,
+            retired_at: HashMap::new(),
+        })
+    }
+
+    /// Keeps keys from `previous` that this fetch no longer publishes, until `grace` has
+    /// passed since they were first found missing, so tokens signed just before a key
+    /// rotation still validate
+    fn retain_retired(mut self, previous: &Jwks, grace: Duration) -> Self {
+        let now = Instant::now();
+        for (kid, &index) in &previous.by_kid {
+            if self.by_kid.contains_key(kid) {
+                continue;
+            }
+            let retired_at = previous.retired_at.get(kid).copied().unwrap_or(now);
+            if retired_at.elapsed() < grace {
+                log::info!(
+                    "Key {} was dropped from the JWKS, keeping it for {:?}",
+                    kid,
+                    grace
+                );
+                self.by_kid.insert(kid.clone(), self.keys.len());
+                self.keys.push(previous.keys[index].clone());
+                self.retired_at.insert(kid.clone(), retired_at);
+            }
+        }
+        self
+    }
+
+    /// Looks up the JWK with the given key ID; retired keys only within their grace window
+    pub fn find(&self, kid: &str) -> Option<&Value> {
+        let &index = self.by_kid.get(kid)?;
+        if let Some(retired_at) = self.retired_at.get(kid) {
+            if retired_at.elapsed() >= old_key_grace() {
+                return None;
+            }
+        }
+        Some(&self.keys[index])
+    }
+}
+
+#[derive(Clone)]
+struct CachedJwks {
+    keys: Arc<Jwks>,
+    fetched_at: Instant,
+    /// Validator for conditional refetches, when Keycloak sent one
+    etag: Option<HeaderValue>,
+}
+
+/// Caches the Keycloak JWKS and deduplicates concurrent refreshes, so a burst of
+/// requests against a cold or expired cache results in a single fetch
+pub struct JwksCache {
+    cached: RwLock<Option<CachedJwks>>,
+    fetch_lock: Mutex<()>,
+    client: reqwest::Client,
+}
+
+impl Default for JwksCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JwksCache {
+    pub fn new() -> Self {
+        Self {
+            cached: RwLock::new(None),
+            fetch_lock: Mutex::new(()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// How long fetched keys are reused, from `JWKS_CACHE_TTL_SECS`
+    fn ttl() -> Duration {
+        Duration::from_secs(
+            env::var("JWKS_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+        )
+    }
+
+    /// Minimum age before a forced refresh refetches, from `JWKS_MIN_REFRESH_SECS`;
+    /// stops tokens with made-up key IDs from triggering a fetch on every request
+    fn min_refresh_interval() -> Duration {
+        Duration::from_secs(
+            env::var("JWKS_MIN_REFRESH_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+        )
+    }
+
+    /// Returns the cached JWKS, fetching it if the cache is cold or expired.
+    ///
+    /// With `force_refresh` the keys are refetched unless another caller already
+    /// refreshed them after this call started (e.g. on an unknown `kid`).
+    pub async fn get(
+        &self,
+        jwks_url: &str,
+        force_refresh: bool,
+    ) -> Result<Arc<Jwks>, actix_web::Error> {
+        let requested_at = Instant::now();
+        let ttl = Self::ttl();
+
+        if !force_refresh {
+            if let Some(cached) = self.cached.read().await.as_ref() {
+                if cached.fetched_at.elapsed() < ttl {
+                    return Ok(cached.keys.clone());
+                }
+            }
+        }
+
+        // Single flight: only one fetch runs at a time, waiters reuse its result
+        let _guard = self.fetch_lock.lock().await;
+        if let Some(cached) = self.cached.read().await.as_ref() {
+            let refreshed_meanwhile = cached.fetched_at >= requested_at;
+            let max_age = if force_refresh {
+                Self::min_refresh_interval()
+            } else {
+                ttl
+            };
+            if refreshed_meanwhile || cached.fetched_at.elapsed() < max_age {
+                return Ok(cached.keys.clone());
+            }
+        }
+
+        log::info!("Fetching JWKS from: {}", jwks_url);
+        let previous = self.cached.read().await.clone();
+        let mut request = self.client.get(jwks_url);
+        if let Some(etag) = previous.as_ref().and_then(|cached| cached.etag.clone()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await.map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Failed to fetch JWKS: {}", e))
+        })?;
+
+        // Unchanged keys are kept as parsed, only their age is reset
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(mut cached) = previous {
+                log::info!("JWKS not modified, keeping cached keys");
+                cached.fetched_at = Instant::now();
+                let keys = cached.keys.clone();
+                *self.cached.write().await = Some(cached);
+                return Ok(keys);
+            }
+        }
+        let etag = response.headers().get(ETAG).cloned();
+        let keys: Value = response.json().await.map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Failed to parse JWKS: {}", e))
+        })?;
+
+        let mut keys = Jwks::from_value(keys)?;
+        let grace = old_key_grace();
+        if !grace.is_zero() {
+            if let Some(previous) = &previous {
+                keys = keys.retain_retired(&previous.keys, grace);
+            }
+        }
+        let keys = Arc::new(keys);
+        *self.cached.write().await = Some(CachedJwks {
+            keys: keys.clone(),
+            fetched_at: Instant::now(),
+            etag,
+        });
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::validate_token;
+    use crate::test_support::{MockKeycloak, TestEnv};
+
+    #[test]
+    fn cache_scope_parses() {
+        let mut env = TestEnv::new();
+        env.remove("JWKS_CACHE_SCOPE");
+        assert_eq!(JwksCacheScope::from_env(), Ok(JwksCacheScope::Shared));
+        env.set("JWKS_CACHE_SCOPE", "Per-Worker");
+        assert_eq!(JwksCacheScope::from_env(), Ok(JwksCacheScope::PerWorker));
+        env.set("JWKS_CACHE_SCOPE", "global");
+        assert!(JwksCacheScope::from_env().is_err());
+    }
+
+    #[actix_web::test]
+    async fn per_worker_caches_fetch_their_own_keys() {
+        let _env = TestEnv::new();
+        let keycloak = MockKeycloak::start().await;
+        let url = keycloak.jwks_url();
+        let shared = web::Data::new(JwksCache::new());
+
+        for _ in 0..3 {
+            let cache = JwksCacheScope::Shared.worker_cache(&shared);
+            assert!(Arc::ptr_eq(
+                &cache.clone().into_inner(),
+                &shared.clone().into_inner()
+            ));
+            cache.get(&url, false).await.unwrap();
+        }
+        assert_eq!(keycloak.fetches(), 1);
+
+        for _ in 0..3 {
+            let cache = JwksCacheScope::PerWorker.worker_cache(&shared);
+            cache.get(&url, false).await.unwrap();
+        }
+        assert_eq!(keycloak.fetches(), 4);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_requests_on_a_cold_cache_fetch_once() {
+        let _env = TestEnv::new();
+        let keycloak = MockKeycloak::start().await;
+        keycloak.set_delay(Duration::from_millis(100));
+        let cache = JwksCache::new();
+        let url = keycloak.jwks_url();
+
+        let results = futures::future::join_all((0..20).map(|_| cache.get(&url, false))).await;
+        assert!(results.iter().all(|keys| keys.is_ok()));
+        assert_eq!(keycloak.fetches(), 1);
+    }
+
+    #[actix_web::test]
+    async fn keys_are_reused_until_the_ttl_expires() {
+        let mut env = TestEnv::new();
+        let keycloak = MockKeycloak::start().await;
+        let cache = JwksCache::new();
+        let url = keycloak.jwks_url();
+
+        cache.get(&url, false).await.unwrap();
+        cache.get(&url, false).await.unwrap();
+        assert_eq!(keycloak.fetches(), 1);
+
+        env.set("JWKS_CACHE_TTL_SECS", "0");
+        cache.get(&url, false).await.unwrap();
+        assert_eq!(keycloak.fetches(), 2);
+    }
+
+    #[actix_web::test]
+    async fn forced_refreshes_are_rate_limited() {
+        let mut env = TestEnv::new().with("JWKS_MIN_REFRESH_SECS", "60");
+        let keycloak = MockKeycloak::start().await;
+        let cache = JwksCache::new();
+        let url = keycloak.jwks_url();
+
+        cache.get(&url, false).await.unwrap();
+        cache.get(&url, true).await.unwrap();
+        assert_eq!(keycloak.fetches(), 1);
+
+        env.set("JWKS_MIN_REFRESH_SECS", "0");
+        cache.get(&url, true).await.unwrap();
+        assert_eq!(keycloak.fetches(), 2);
+    }
+
+    #[actix_web::test]
+    async fn fetch_failures_are_reported() {
+        let _env = TestEnv::new();
+        let cache = JwksCache::new();
+        let error = cache
+            .get("http://127.0.0.1:1/realms/upload-realm/certs", false)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("Failed to fetch JWKS"));
+    }
+
+    #[test]
+    fn large_key_sets_are_indexed_by_kid() {
+        let mut keys: Vec<Value> = (0..5000)
+            .map(|i| serde_json::json!({ "kid": format!("k{}", i), "n": i }))
+            .collect();
+        keys.push(serde_json::json!({ "kid": "k42", "n": "duplicate" }));
+        keys.push(serde_json::json!({ "n": "no kid" }));
+        let jwks = Jwks::from_value(serde_json::json!({ "keys": keys })).unwrap();
+
+        assert_eq!(jwks.by_kid.len(), 5000);
+        assert_eq!(jwks.by_kid["k4321"], 4321);
+        assert_eq!(jwks.find("k4321").unwrap()["n"], 4321);
+        assert_eq!(jwks.find("k42").unwrap()["n"], 42);
+        assert!(jwks.find("k5000").is_none());
+    }
+
+    #[test]
+    fn malformed_key_sets_are_rejected() {
+        assert!(Jwks::from_value(serde_json::json!({ "keys": {} })).is_err());
+        assert!(Jwks::from_value(serde_json::json!([])).is_err());
+    }
+
+    /// Fetches a key set where `test-key-1` was rotated out for `test-key-2`
+    async fn rotated(keycloak: &MockKeycloak, cache: &JwksCache) -> Arc<Jwks> {
+        let url = keycloak.jwks_url();
+        let before = cache.get(&url, false).await.unwrap();
+        assert!(before.find("test-key-1").is_some());
+        keycloak.publish(&["test-key-2"]);
+        cache.get(&url, true).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn dropped_keys_are_forgotten_without_a_grace_period() {
+        let _env = TestEnv::new()
+            .with("OLD_KEY_GRACE_SECS", "0")
+            .with("JWKS_MIN_REFRESH_SECS", "0");
+        let keycloak = MockKeycloak::start().await;
+
+        let keys = rotated(&keycloak, &JwksCache::new()).await;
+        assert!(keys.find("test-key-1").is_none());
+        assert!(keys.find("test-key-2").is_some());
+    }
+
+    #[actix_web::test]
+    async fn dropped_keys_validate_within_the_grace_period() {
+        let mut env = TestEnv::new()
+            .with("OLD_KEY_GRACE_SECS", "60")
+            .with("JWKS_MIN_REFRESH_SECS", "0");
+        let keycloak = MockKeycloak::start().await;
+        keycloak.configure(&mut env);
+        let cache = JwksCache::new();
+        let token = keycloak.token("alice");
+
+        let keys = rotated(&keycloak, &cache).await;
+        assert!(keys.find("test-key-1").is_some());
+        assert!(keys.find("test-key-2").is_some());
+        let user = validate_token(&token, &cache).await.unwrap();
+        assert_eq!(user.sub, "alice");
+
+        // Later fetches keep the key until the period runs out
+        let keys = cache.get(&keycloak.jwks_url(), true).await.unwrap();
+        assert!(keys.find("test-key-1").is_some());
+        env.set("OLD_KEY_GRACE_SECS", "0");
+        assert!(keys.find("test-key-1").is_none());
+        assert!(validate_token(&token, &cache).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn unchanged_keys_are_revalidated_and_kept() {
+        let _env = TestEnv::new().with("JWKS_MIN_REFRESH_SECS", "0");
+        let keycloak = MockKeycloak::start().await;
+        let cache = JwksCache::new();
+        let url = keycloak.jwks_url();
+
+        let first = cache.get(&url, false).await.unwrap();
+        let etag = cache.cached.read().await.as_ref().unwrap().etag.clone();
+        assert_eq!(etag.unwrap(), "\"test-key-1\"");
+
+        // The mock answers the If-None-Match with a 304, so the parsed set is reused
+        let revalidated = cache.get(&url, true).await.unwrap();
+        assert_eq!(keycloak.fetches(), 2);
+        assert!(Arc::ptr_eq(&first, &revalidated));
+
+        keycloak.publish(&["test-key-1", "test-key-2"]);
+        let changed = cache.get(&url, true).await.unwrap();
+        assert_eq!(keycloak.fetches(), 3);
+        assert!(!Arc::ptr_eq(&first, &changed));
+        assert!(changed.find("test-key-2").is_some());
+    }
+}
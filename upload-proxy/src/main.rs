@@ -1,15 +1,57 @@
 use actix_cors::Cors;
 use actix_web::{middleware, web, App, HttpServer};
-use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenv::dotenv;
 use std::env;
 
+mod admin;
 mod auth;
+mod cleanup;
+mod compression;
+mod content_type;
+mod dav;
+mod disk;
+mod downloads;
+mod encrypt;
+mod errors;
+mod events;
+mod filename;
 mod handlers;
+mod hooks;
+mod images;
+mod jwks;
 mod metadata;
+mod metrics;
+mod namespace;
+mod naming;
+mod ndjson;
+mod pipe;
+mod progress;
+mod ratelimit;
+mod receipts;
+mod routes;
+mod routing;
+mod tags;
+mod timestamps;
+mod treehash;
+mod window;
+mod xml;
+#[cfg(test)]
+mod test_support;
 
-use auth::validator;
-use handlers::{exchange_token, health_check, upload_file, refresh_token};
+use cleanup::{cleanup_temp_files, temp_cleanup_age};
+use compression::compression_min_bytes;
+use downloads::{DownloadCache, DownloadLimiter};
+use events::EventBus;
+use filename::{FilenameRules, NameReservations};
+use handlers::ListPaging;
+use jwks::{JwksCache, JwksCacheScope};
+use metadata::{metadata_backup_interval, run_metadata_backups};
+use progress::ProgressTracker;
+use ratelimit::RateLimiter;
+use routes::{configure, wrap_middleware, MiddlewareSettings};
+use routing::{route_prefix, AuthRequirements};
+use timestamps::TimestampConfig;
+use window::UploadWindow;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -27,6 +69,116 @@ async fn main() -> std::io::Result<()> {
         .map(|s| s.trim().to_string())
         .collect();
 
+    let filename_rules = FilenameRules::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let filename_rules = web::Data::new(filename_rules);
+
+    let list_paging = ListPaging::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    log::info!("File listing pagination: {:?}", list_paging);
+    let list_paging = web::Data::new(list_paging);
+
+    let events = web::Data::new(EventBus::new(256));
+    let progress = web::Data::new(ProgressTracker::default());
+    let reservations = web::Data::new(NameReservations::default());
+
+    let settings = MiddlewareSettings::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    log::info!("Trailing slash handling: {:?}", settings.trailing_slash);
+
+    let rate_limiter = RateLimiter::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    if rate_limiter.enabled() {
+        log::info!("Rate limiting keyed by {:?}", rate_limiter.key());
+    }
+    if let Some(limit) = rate_limiter.uploads_per_minute() {
+        log::info!("Accepting at most {} uploads per minute in total", limit);
+    }
+    let rate_limiter = web::Data::new(rate_limiter);
+
+    let jwks_scope = JwksCacheScope::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    log::info!("JWKS cache scope: {:?}", jwks_scope);
+    let shared_jwks = web::Data::new(JwksCache::new());
+
+    let timestamp_config = TimestampConfig::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    log::info!("Timestamp format: {:?}", timestamp_config);
+    let timestamp_config = web::Data::new(timestamp_config);
+
+    let upload_window = UploadWindow::from_env().map_err(|e| {
+        log::error!("{}", e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    if let Some(window) = &upload_window {
+        log::info!("Accepting uploads only within {:?}", window);
+    }
+    let upload_window = web::Data::new(upload_window);
+
+    let download_limiter = DownloadLimiter::from_env();
+    if let Some(limit) = download_limiter.limit() {
+        log::info!("Serving at most {} downloads at once", limit);
+    }
+    let download_limiter = web::Data::new(download_limiter);
+
+    let download_cache = DownloadCache::from_env();
+    if download_cache.enabled() {
+        log::info!(
+            "Caching small downloads in up to {} bytes of memory",
+            download_cache.capacity()
+        );
+    }
+    let download_cache = web::Data::new(download_cache);
+
+    if let Some(limit) = settings.header_limit {
+        log::info!("Request headers limited to {} bytes", limit);
+    }
+    if settings.user_agent_required {
+        log::info!("Rejecting requests without a User-Agent");
+    }
+    if let Some(deadline) = settings.deadline {
+        log::info!("Requests abort after {:?}", deadline);
+    }
+
+    if let Some(max_age) = temp_cleanup_age() {
+        let removed = cleanup_temp_files(max_age);
+        log::info!(
+            "Removed {} orphaned temporary files older than {:?}",
+            removed,
+            max_age
+        );
+    }
+
+    if settings.compress {
+        log::info!(
+            "Compressing responses of at least {} bytes",
+            compression_min_bytes()
+        );
+    }
+
+    if let Some(interval) = metadata_backup_interval() {
+        log::info!("Backing up metadata every {:?}", interval);
+        actix_web::rt::spawn(run_metadata_backups(interval));
+    }
+
+    let auth = AuthRequirements::from_env();
+    log::info!("Authentication required: {:?}", auth);
+
+    let route_prefix = route_prefix();
+    if !route_prefix.is_empty() {
+        log::info!("Mounting routes under {}", route_prefix);
+    }
+
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()       // For dev, consider specifying origins in production
@@ -37,17 +189,20 @@ async fn main() -> std::io::Result<()> {
 
         log::info!("CORS configured for origins: {:?}", origins);
 
-        App::new()
-            .wrap(middleware::Logger::default())
+        wrap_middleware(App::new().wrap(middleware::Logger::default()), settings)
             .wrap(cors)
-            .route("/health", web::get().to(health_check))
-            .route("/token", web::post().to(exchange_token))
-            .route("/refresh", web::post().to(refresh_token))
-            .service(
-                web::scope("/api")
-                    .wrap(HttpAuthentication::bearer(validator))
-                    .route("/upload", web::post().to(upload_file))
-            )
+            .app_data(filename_rules.clone())
+            .app_data(jwks_scope.worker_cache(&shared_jwks))
+            .app_data(download_cache.clone())
+            .app_data(download_limiter.clone())
+            .app_data(events.clone())
+            .app_data(list_paging.clone())
+            .app_data(progress.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(reservations.clone())
+            .app_data(timestamp_config.clone())
+            .app_data(upload_window.clone())
+            .configure(|cfg| configure(cfg, auth))
     })
     .bind(format!("0.0.0.0:{}", backend_port))?
     .run()
@@ -1,14 +1,101 @@
 use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, OpenOptions};
-use std::path::Path;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::hooks::HookDelivery;
+use crate::metrics;
+use crate::timestamps::{Timestamp, TimestampConfig, TimestampFormat};
+
+/// Serializes read-modify-write cycles on the metadata file
+static METADATA_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where a stored file lives
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StorageLocation {
+    /// Storage backend holding the file, e.g. `local`
+    pub backend: String,
+    /// Backend-specific location: a filesystem path for `local`
+    pub location: String,
+}
+
+impl StorageLocation {
+    pub fn local(path: &Path) -> Self {
+        Self {
+            backend: "local".to_string(),
+            location: path.display().to_string(),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct UploadMetadata {
     pub filename: String,
     pub user: String,
+    #[serde(deserialize_with = "crate::timestamps::deserialize")]
     pub timestamp: String,
     pub size_bytes: u64,
+    /// Hex-encoded SHA-256 of the stored file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Hex-encoded MD5 of the stored file, only recorded on demand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum_md5: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageLocation>,
+    /// MIME type sniffed from the content, else the one the client declared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Name the client sent, when the file was stored under another (by `NAME_STRATEGY`,
+    /// `CORRECT_EXTENSION` or a collision suffix)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    /// SHA-256 tree hash over 1 MiB chunks, verified against the client's `X-Tree-Hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_hash: Option<String>,
+    /// Client tags from `X-Upload-Tags` merged with the uploader's `ROLE_DEFAULT_TAGS`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Times the file was served, counted while `TRACK_DOWNLOADS` is on
+    #[serde(default)]
+    pub download_count: u64,
+    /// Outcome of `POST_UPLOAD_HOOK` for this upload, once it has run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook_delivery: Option<HookDelivery>,
+}
+
+impl UploadMetadata {
+    /// Creates a metadata entry timestamped now
+    pub fn new(filename: String, user: String, size_bytes: u64) -> Self {
+        Self {
+            filename,
+            user,
+            timestamp: Utc::now().to_rfc3339(),
+            size_bytes,
+            checksum: None,
+            checksum_md5: None,
+            storage: None,
+            content_type: None,
+            original_filename: None,
+            tree_hash: None,
+            tags: Vec::new(),
+            download_count: 0,
+            hook_delivery: None,
+        }
+    }
+
+    /// Name the client sent the file with, whatever it was stored under
+    pub fn client_filename(&self) -> &str {
+        self.original_filename.as_deref().unwrap_or(&self.filename)
+    }
 }
 
 #[derive(Serialize)]
@@ -18,77 +105,457 @@ pub struct UploadResponse {
     pub filename: String,
     pub user: String,
     pub size_bytes: u64,
-    pub timestamp: String,
+    pub timestamp: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageLocation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+    /// Signed JWT attesting the upload, when `RECEIPT_SIGNING_KEY` is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
 }
 
-/// Logs upload metadata to uploads.json file
-pub fn log_upload_metadata(
-    filename: String,
-    user: String,
-    size_bytes: u64,
+/// Path of the metadata file, from `METADATA_FILE`
+pub fn metadata_file_path() -> String {
+    env::var("METADATA_FILE").unwrap_or_else(|_| "./uploads.json".to_string())
+}
+
+/// Reads all metadata entries, returning an empty list when the file is missing. A file
+/// that doesn't parse is an error rather than an empty history, so it is never overwritten.
+pub fn read_metadata(metadata_file_path: &str) -> Result<Vec<UploadMetadata>, actix_web::Error> {
+    if !Path::new(metadata_file_path).exists() {
+        return Ok(vec![]);
+    }
+
+    let content = fs::read_to_string(metadata_file_path).map_err(|e| {
+        log::error!("Failed to read {}: {}", metadata_file_path, e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to read metadata: {}", e))
+    })?;
+    metrics::METADATA_FILE_BYTES.store(content.len() as u64, Ordering::Relaxed);
+    let started = Instant::now();
+    let entries = serde_json::from_str::<Vec<UploadMetadata>>(&content).map_err(|e| {
+        log::error!("Failed to parse {}: {}", metadata_file_path, e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to parse metadata: {}", e))
+    })?;
+    metrics::METADATA_PARSE_SECONDS.observe(started.elapsed());
+    Ok(entries)
+}
+
+/// Latest entry per stored filename; re-uploads replace the file on disk
+pub fn current_files(entries: &[UploadMetadata]) -> Vec<&UploadMetadata> {
+    let mut latest: HashMap<&str, &UploadMetadata> = HashMap::new();
+    for entry in entries {
+        latest.insert(entry.filename.as_str(), entry);
+    }
+    latest.into_values().collect()
+}
+
+/// Writes the entries to a temporary file beside the metadata file and renames it into
+/// place, so a failed write leaves the previous contents intact
+fn write_metadata(
     metadata_file_path: &str,
+    uploads: &[UploadMetadata],
 ) -> Result<(), actix_web::Error> {
-    log::info!("Logging upload metadata for file: {}", filename);
-
-    let metadata = UploadMetadata {
-        filename: filename.clone(),
-        user: user.clone(),
-        timestamp: Utc::now().to_rfc3339(),
-        size_bytes,
-    };
+    let partial = format!("{}.partial", metadata_file_path);
+    let mut metadata_file = File::create(&partial).map_err(|e| {
+        log::error!("Failed to open {} for writing: {}", partial, e);
+        actix_web::error::ErrorInternalServerError(format!("Failed to open metadata file: {}", e))
+    })?;
 
-    // Read existing metadata or create new vector
-    let mut uploads = if Path::new(metadata_file_path).exists() {
-        let content = fs::read_to_string(metadata_file_path).map_err(|e| {
-            log::error!("Failed to read {}: {}", metadata_file_path, e);
-            actix_web::error::ErrorInternalServerError(format!("Failed to read metadata: {}", e))
-        })?;
-        serde_json::from_str::<Vec<UploadMetadata>>(&content).unwrap_or_else(|e| {
-            log::warn!(
-                "Failed to parse {}, creating new: {}",
-                metadata_file_path,
+    let format = TimestampConfig::from_env()
+        .unwrap_or_default()
+        .storage_format();
+    let written = stored_json(uploads, format)
+        .map_err(io::Error::from)
+        .and_then(|json| {
+            metadata_file.write_all(&json)?;
+            metadata_file.sync_all()?;
+            Ok(json.len() as u64)
+        })
+        .and_then(|len| fs::rename(&partial, metadata_file_path).map(|()| len));
+    match written {
+        Ok(len) => {
+            metrics::METADATA_FILE_BYTES.store(len, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to write metadata: {}", e);
+            let _ = fs::remove_file(&partial);
+            Err(actix_web::error::ErrorInternalServerError(format!(
+                "Failed to write metadata: {}",
                 e
-            );
-            vec![]
+            )))
+        }
+    }
+}
+
+/// The metadata file's contents, with timestamps in `format`
+fn stored_json(uploads: &[UploadMetadata], format: TimestampFormat) -> serde_json::Result<Vec<u8>> {
+    let mut entries = serde_json::to_value(uploads)?;
+    if format != TimestampFormat::Rfc3339 {
+        for entry in entries.as_array_mut().into_iter().flatten() {
+            if let Some(timestamp) = entry.get_mut("timestamp") {
+                format.rewrite(timestamp);
+            }
+            if let Some(at) = entry.pointer_mut("/hook_delivery/at") {
+                format.rewrite(at);
+            }
+        }
+    }
+    serde_json::to_vec_pretty(&entries)
+}
+
+/// Applies `update` to the stored entries and writes them back while holding the metadata lock
+pub fn update_metadata<T>(
+    metadata_file_path: &str,
+    update: impl FnOnce(&mut Vec<UploadMetadata>) -> T,
+) -> Result<T, actix_web::Error> {
+    let _guard = METADATA_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut uploads = read_metadata(metadata_file_path)?;
+    let result = update(&mut uploads);
+    write_metadata(metadata_file_path, &uploads)?;
+    Ok(result)
+}
+
+/// Number of times a failed metadata write is retried, from `METADATA_WRITE_RETRIES`
+fn metadata_write_retries() -> u32 {
+    env::var("METADATA_WRITE_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2)
+}
+
+/// Logs upload metadata to uploads.json file
+///
+/// Transient failures are retried with exponential backoff (50ms, 100ms, ...)
+/// before the error is returned.
+pub async fn log_upload_metadata(
+    entries: &[UploadMetadata],
+    metadata_file_path: &str,
+) -> Result<(), actix_web::Error> {
+    for entry in entries {
+        log::info!("Logging upload metadata for file: {}", entry.filename);
+    }
+
+    // Append new metadata entries in a single write
+    let started = Instant::now();
+    retry_metadata_write(|| {
+        update_metadata(metadata_file_path, |uploads| {
+            uploads.extend(entries.iter().cloned())
         })
-    } else {
-        vec![]
+    })
+    .await?;
+
+    metrics::METADATA_APPEND_SECONDS.observe(started.elapsed());
+    log::info!("Successfully logged metadata for {} file(s)", entries.len());
+    Ok(())
+}
+
+/// Runs `write`, retrying failures `METADATA_WRITE_RETRIES` times
+async fn retry_metadata_write(
+    mut write: impl FnMut() -> Result<(), actix_web::Error>,
+) -> Result<(), actix_web::Error> {
+    let retries = metadata_write_retries();
+    let mut attempt = 0;
+    loop {
+        match write() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                let backoff = Duration::from_millis(50 * 2u64.pow(attempt.min(6)));
+                attempt += 1;
+                log::warn!(
+                    "Metadata write failed (attempt {}/{}), retrying in {:?}: {}",
+                    attempt,
+                    retries + 1,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// How often the metadata file is backed up, from `METADATA_BACKUP_INTERVAL_SECS`;
+/// unset or 0 disables backups
+pub fn metadata_backup_interval() -> Option<Duration> {
+    env::var("METADATA_BACKUP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+        .map(Duration::from_secs)
+}
+
+/// Number of backups kept, from `METADATA_BACKUP_KEEP`
+fn metadata_backup_keep() -> usize {
+    env::var("METADATA_BACKUP_KEEP")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(7)
+}
+
+/// Path prefix backups are written under, from `METADATA_SNAPSHOT_KEY`; defaults to the
+/// metadata file itself, so backups sit beside it. Pointing it at another volume keeps
+/// backups when the live file's disk is lost.
+fn metadata_backup_prefix(metadata_file_path: &str) -> String {
+    env::var("METADATA_SNAPSHOT_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+        .unwrap_or_else(|| metadata_file_path.to_string())
+}
+
+/// Writes a gzip copy of the metadata file to `<prefix>.<timestamp>.gz` and prunes the
+/// oldest backups beyond `METADATA_BACKUP_KEEP`. Returns `None` when there is no
+/// metadata file yet.
+pub fn backup_metadata(metadata_file_path: &str) -> io::Result<Option<PathBuf>> {
+    // Copy under the lock so the backup never captures a half-written file
+    let content = {
+        let _guard = METADATA_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        match fs::read(metadata_file_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
     };
 
-    // Append new metadata entry
-    uploads.push(metadata);
-
-    // Write updated metadata back to file
-    let metadata_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(metadata_file_path)
-        .map_err(|e| {
-            log::error!("Failed to open {} for writing: {}", metadata_file_path, e);
-            actix_web::error::ErrorInternalServerError(format!(
-                "Failed to open metadata file: {}",
-                e
-            ))
-        })?;
+    let prefix = metadata_backup_prefix(metadata_file_path);
+    let backup = PathBuf::from(format!(
+        "{}.{}.gz",
+        prefix,
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    ));
+    if let Some(dir) = backup.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut encoder = GzEncoder::new(fs::File::create(&backup)?, Compression::default());
+    encoder.write_all(&content)?;
+    encoder.finish()?.sync_all()?;
 
-    serde_json::to_writer_pretty(metadata_file, &uploads).map_err(|e| {
-        log::error!("Failed to write metadata: {}", e);
-        actix_web::error::ErrorInternalServerError(format!("Failed to write metadata: {}", e))
-    })?;
+    prune_metadata_backups(&prefix, metadata_backup_keep())?;
+    Ok(Some(backup))
+}
 
-    log::info!("Successfully logged metadata for file: {}", filename);
+/// Deletes all but the newest `keep` backups written under `prefix`
+fn prune_metadata_backups(prefix: &str, keep: usize) -> io::Result<()> {
+    let path = Path::new(prefix);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    // Timestamps sort lexically, so name order is age order
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|backup| {
+            backup
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".gz"))
+        })
+        .collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep);
+    for old in &backups[..excess] {
+        log::info!("Removing old metadata backup {}", old.display());
+        fs::remove_file(old)?;
+    }
     Ok(())
 }
 
-/// Creates a successful upload response
-pub fn create_upload_response(filename: String, user: String, size_bytes: u64) -> UploadResponse {
+/// Backs up the metadata file every `interval`, for the life of the process
+pub async fn run_metadata_backups(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; start with a full interval instead
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let metadata_file = metadata_file_path();
+        let result = {
+            let metadata_file = metadata_file.clone();
+            actix_web::rt::task::spawn_blocking(move || backup_metadata(&metadata_file)).await
+        };
+        match result {
+            Ok(Ok(Some(backup))) => log::info!("Backed up metadata to {}", backup.display()),
+            Ok(Ok(None)) => log::debug!("No metadata file to back up at {}", metadata_file),
+            Ok(Err(e)) => log::error!("Failed to back up {}: {}", metadata_file, e),
+            Err(e) => log::error!("Metadata backup task failed: {}", e),
+        }
+    }
+}
+
+/// Creates a successful upload response, with its timestamp in `format`
+pub fn create_upload_response(
+    metadata: &UploadMetadata,
+    format: TimestampFormat,
+) -> UploadResponse {
     UploadResponse {
         status: "success".to_string(),
         message: "File uploaded successfully".to_string(),
-        filename,
-        user,
-        size_bytes,
-        timestamp: Utc::now().to_rfc3339(),
+        filename: metadata.filename.clone(),
+        user: metadata.user.clone(),
+        size_bytes: metadata.size_bytes,
+        timestamp: format.render(&metadata.timestamp),
+        checksum: metadata.checksum.clone(),
+        storage: metadata.storage.clone(),
+        warning: None,
+        receipt: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+    use std::time::Instant;
+
+    #[actix_web::test]
+    async fn failed_metadata_write_is_retried_until_it_succeeds() {
+        let _env = TestEnv::new().with("METADATA_WRITE_RETRIES", "3");
+        let mut attempts = 0;
+        let result = retry_metadata_write(|| {
+            attempts += 1;
+            match attempts {
+                1 => Err(actix_web::error::ErrorInternalServerError("disk full")),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn failed_metadata_write_keeps_the_previous_contents() {
+        let env = TestEnv::new();
+        let metadata_file = env.metadata_file();
+        let entry = UploadMetadata::new("a.txt".into(), "alice".into(), 5);
+        write_metadata(&metadata_file, std::slice::from_ref(&entry)).unwrap();
+        let before = fs::read(&metadata_file).unwrap();
+
+        // The temporary file can't be created, so the write fails before replacing anything
+        fs::create_dir(format!("{}.partial", metadata_file)).unwrap();
+        assert!(write_metadata(&metadata_file, &[entry.clone(), entry]).is_err());
+        assert_eq!(fs::read(&metadata_file).unwrap(), before);
+    }
+
+    #[actix_web::test]
+    async fn unparsable_metadata_is_an_error_and_left_alone() {
+        let env = TestEnv::new().with("METADATA_WRITE_RETRIES", "0");
+        let metadata_file = env.metadata_file();
+        fs::write(&metadata_file, "[{\"filename\": \"a.t").unwrap();
+
+        assert!(read_metadata(&metadata_file).is_err());
+        let entry = UploadMetadata::new("b.txt".into(), "alice".into(), 5);
+        assert!(log_upload_metadata(&[entry], &metadata_file).await.is_err());
+        assert_eq!(
+            fs::read_to_string(&metadata_file).unwrap(),
+            "[{\"filename\": \"a.t"
+        );
+    }
+
+    #[actix_web::test]
+    async fn metadata_write_fails_once_retries_are_exhausted() {
+        let env = TestEnv::new().with("METADATA_WRITE_RETRIES", "1");
+        let metadata_file = env.path().join("missing").join("uploads.json");
+        let entry = UploadMetadata::new("a.txt".into(), "alice".into(), 5);
+        let started = Instant::now();
+        let result = log_upload_metadata(&[entry], &metadata_file.display().to_string()).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn concurrent_metadata_appends_lose_no_entries() {
+        let env = TestEnv::new();
+        let metadata_file = env.metadata_file();
+        let writers: Vec<_> = (0..16)
+            .map(|i| {
+                let metadata_file = metadata_file.clone();
+                std::thread::spawn(move || {
+                    let entry = UploadMetadata::new(format!("{}.txt", i), "alice".into(), i);
+                    futures::executor::block_on(log_upload_metadata(&[entry], &metadata_file))
+                        .is_ok()
+                })
+            })
+            .collect();
+        for writer in writers {
+            assert!(writer.join().unwrap());
+        }
+
+        let mut names: Vec<String> = env.entries().into_iter().map(|e| e.filename).collect();
+        names.sort();
+        let mut expected: Vec<String> = (0..16).map(|i| format!("{}.txt", i)).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    /// Backups of the metadata file sitting beside it, oldest first
+    fn backups(dir: &Path) -> Vec<PathBuf> {
+        let mut backups: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gz"))
+            .collect();
+        backups.sort();
+        backups
+    }
+
+    #[test]
+    fn metadata_backups_are_gzip_copies_pruned_to_the_keep_count() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let mut env = TestEnv::new().with("METADATA_BACKUP_KEEP", "2");
+        env.remove("METADATA_SNAPSHOT_KEY");
+        let metadata_file = env.metadata_file();
+        assert!(backup_metadata(&metadata_file).unwrap().is_none());
+
+        let mut written = Vec::new();
+        for i in 0..4 {
+            env.seed(&[UploadMetadata::new(format!("{}.txt", i), "alice".into(), i)]);
+            written.push(backup_metadata(&metadata_file).unwrap().unwrap());
+            // Backup names carry millisecond timestamps
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(backups(&env.path()), written[2..]);
+
+        let mut restored = Vec::new();
+        GzDecoder::new(fs::File::open(&written[3]).unwrap())
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, fs::read(&metadata_file).unwrap());
+    }
+
+    #[test]
+    fn snapshots_are_written_and_rotated_under_the_snapshot_key() {
+        let mut env = TestEnv::new().with("METADATA_BACKUP_KEEP", "2");
+        let snapshots = env.path().join("snapshots");
+        env.set(
+            "METADATA_SNAPSHOT_KEY",
+            &snapshots.join("uploads").display().to_string(),
+        );
+        let metadata_file = env.metadata_file();
+
+        let mut written = Vec::new();
+        for i in 0..3 {
+            env.seed(&[UploadMetadata::new(format!("{}.txt", i), "alice".into(), i)]);
+            written.push(backup_metadata(&metadata_file).unwrap().unwrap());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(written[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("uploads."));
+        assert_eq!(backups(&snapshots), written[1..]);
+        assert!(backups(&env.path()).is_empty());
     }
 }
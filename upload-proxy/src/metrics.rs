@@ -0,0 +1,156 @@
+use actix_web::HttpResponse;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+const BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+/// Latency histogram with fixed buckets, rendered in the Prometheus text format
+pub struct Histogram {
+    /// Observations at or below each bucket's bound (cumulative when rendered)
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Time to append an upload's entries to the metadata file, retries included
+pub static METADATA_APPEND_SECONDS: Histogram = Histogram::new();
+/// Time to parse the metadata file, on every read (listings, lookups and updates)
+pub static METADATA_PARSE_SECONDS: Histogram = Histogram::new();
+/// Size of the metadata file when it was last read or written
+pub static METADATA_FILE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Prometheus metrics for the JSON metadata store, to tell when it becomes a bottleneck
+pub async fn metrics() -> HttpResponse {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP upload_proxy_metadata_file_bytes Size of the metadata file when last read or written"
+    );
+    let _ = writeln!(out, "# TYPE upload_proxy_metadata_file_bytes gauge");
+    let _ = writeln!(
+        out,
+        "upload_proxy_metadata_file_bytes {}",
+        METADATA_FILE_BYTES.load(Ordering::Relaxed)
+    );
+    METADATA_APPEND_SECONDS.render(
+        "upload_proxy_metadata_append_seconds",
+        "Time to append upload entries to the metadata file",
+        &mut out,
+    );
+    METADATA_PARSE_SECONDS.render(
+        "upload_proxy_metadata_parse_seconds",
+        "Time to parse the metadata file",
+        &mut out,
+    );
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    #[test]
+    fn histograms_render_cumulative_buckets() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        histogram.render("latency_seconds", "Latency", &mut out);
+        for line in [
+            "# TYPE latency_seconds histogram",
+            "latency_seconds_bucket{le=\"0.001\"} 1",
+            "latency_seconds_bucket{le=\"0.01\"} 1",
+            "latency_seconds_bucket{le=\"0.025\"} 2",
+            "latency_seconds_bucket{le=\"1\"} 2",
+            "latency_seconds_bucket{le=\"+Inf\"} 3",
+            "latency_seconds_sum 2.0205",
+            "latency_seconds_count 3",
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                out
+            );
+        }
+    }
+
+    /// Value of the sample `name` in the `/metrics` output
+    fn sample(metrics: &str, name: &str) -> u64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn uploads_are_recorded_in_the_append_histogram() {
+        let _env = TestEnv::new();
+        let app = init_service(app()).await;
+        let scrape = || async {
+            let resp = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+            String::from_utf8(read_body(resp).await.to_vec()).unwrap()
+        };
+
+        let before = sample(
+            &scrape().await,
+            "upload_proxy_metadata_append_seconds_count",
+        );
+        let req = Form::new()
+            .file("a.txt", b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        call_service(&app, req).await;
+        let after = scrape().await;
+
+        // Other tests upload concurrently, so only growth is certain
+        assert!(sample(&after, "upload_proxy_metadata_append_seconds_count") > before);
+        assert!(sample(&after, "upload_proxy_metadata_file_bytes") > 0);
+    }
+}
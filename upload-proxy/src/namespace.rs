@@ -0,0 +1,169 @@
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use std::env;
+use std::future::{ready, Ready};
+use std::path::PathBuf;
+
+use crate::handlers::uploads_dir;
+use crate::metadata::metadata_file_path;
+
+/// Trusted header selecting an isolated storage namespace
+pub const NAMESPACE_HEADER: &str = "X-Metadata-Namespace";
+
+/// Whether `X-Metadata-Namespace` is honored, from `ALLOW_METADATA_NAMESPACE`
+pub fn namespaces_enabled() -> bool {
+    env::var("ALLOW_METADATA_NAMESPACE")
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Directory holding one subdirectory per namespace, from `NAMESPACES_DIR`
+pub fn namespaces_dir() -> PathBuf {
+    PathBuf::from(env::var("NAMESPACES_DIR").unwrap_or_else(|_| "./namespaces".to_string()))
+}
+
+/// Accepts 1-64 ASCII letters, digits, `-` and `_`, so a namespace can't name a parent
+/// directory or contain a separator
+fn validate_namespace(namespace: &str) -> Result<(), actix_web::Error> {
+    if namespace.is_empty()
+        || namespace.len() > 64
+        || !namespace
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Invalid metadata namespace",
+        ));
+    }
+    Ok(())
+}
+
+/// Where a request's files and metadata live.
+///
+/// Without a namespace this is `UPLOADS_DIR` and `METADATA_FILE`; a namespaced request
+/// uses `NAMESPACES_DIR/<namespace>/uploads` and `NAMESPACES_DIR/<namespace>/uploads.json`.
+#[derive(Clone, Debug)]
+pub struct StorageScope {
+    pub uploads_dir: PathBuf,
+    pub metadata_file: String,
+}
+
+impl StorageScope {
+    /// The shared, un-namespaced storage
+    pub fn shared() -> Self {
+        Self {
+            uploads_dir: uploads_dir(),
+            metadata_file: metadata_file_path(),
+        }
+    }
+
+    fn namespaced(namespace: &str) -> Self {
+        let root = namespaces_dir().join(namespace);
+        Self {
+            uploads_dir: root.join("uploads"),
+            metadata_file: root.join("uploads.json").display().to_string(),
+        }
+    }
+
+    /// Resolves the scope selected by the request's headers
+    pub fn for_request(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let Some(value) = req.headers().get(NAMESPACE_HEADER) else {
+            return Ok(Self::shared());
+        };
+        if !namespaces_enabled() {
+            log::debug!(
+                "Ignoring {} because namespaces are disabled",
+                NAMESPACE_HEADER
+            );
+            return Ok(Self::shared());
+        }
+        let namespace = value
+            .to_str()
+            .map_err(|_| actix_web::error::ErrorBadRequest("Invalid metadata namespace"))?;
+        validate_namespace(namespace)?;
+        Ok(Self::namespaced(namespace))
+    }
+}
+
+impl FromRequest for StorageScope {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::for_request(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::read_metadata;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service};
+
+    /// Status of an upload of `filename` sent with `namespace`, if any
+    async fn upload_in(namespace: Option<&str>, filename: &str) -> StatusCode {
+        let app = init_service(app()).await;
+        let mut req = Form::new()
+            .file(filename, b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"));
+        if let Some(namespace) = namespace {
+            req = req.insert_header((NAMESPACE_HEADER, namespace));
+        }
+        call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn namespaces_get_separate_storage() {
+        let mut env = TestEnv::new().with("ALLOW_METADATA_NAMESPACE", "true");
+        let root = env.path().join("namespaces");
+        env.set("NAMESPACES_DIR", &root.display().to_string());
+        assert_eq!(upload_in(Some("run-a"), "a.txt").await, StatusCode::OK);
+        assert_eq!(upload_in(Some("run_b"), "b.txt").await, StatusCode::OK);
+        assert_eq!(upload_in(None, "shared.txt").await, StatusCode::OK);
+
+        let names = |namespace: &str| -> Vec<String> {
+            let file = root.join(namespace).join("uploads.json");
+            read_metadata(&file.display().to_string())
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.filename)
+                .collect()
+        };
+        assert_eq!(names("run-a"), ["a.txt"]);
+        assert_eq!(names("run_b"), ["b.txt"]);
+        assert!(root.join("run-a/uploads/a.txt").exists());
+        assert!(root.join("run_b/uploads/b.txt").exists());
+        assert_eq!(env.stored_files(), ["shared.txt"]);
+        assert_eq!(env.entries().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn traversing_namespaces_are_rejected() {
+        let mut env = TestEnv::new().with("ALLOW_METADATA_NAMESPACE", "true");
+        env.set(
+            "NAMESPACES_DIR",
+            &env.path().join("namespaces").display().to_string(),
+        );
+        for namespace in ["..", "../escape", "a/b", ""] {
+            let status = upload_in(Some(namespace), "a.txt").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{:?}", namespace);
+        }
+        assert!(!env.path().join("escape").exists());
+    }
+
+    #[actix_web::test]
+    async fn namespace_header_is_ignored_unless_enabled() {
+        let mut env = TestEnv::new();
+        env.remove("ALLOW_METADATA_NAMESPACE");
+        env.set(
+            "NAMESPACES_DIR",
+            &env.path().join("namespaces").display().to_string(),
+        );
+        assert_eq!(upload_in(Some("run-a"), "a.txt").await, StatusCode::OK);
+        assert_eq!(env.stored_files(), ["a.txt"]);
+        assert!(!env.path().join("namespaces").exists());
+    }
+}
@@ -0,0 +1,137 @@
+use chrono::Utc;
+use std::env;
+use uuid::Uuid;
+
+/// How an upload's stored name is derived from the name the client sent
+pub trait NameStrategy: Send + Sync {
+    /// Stored name for an upload of `original` by `user`; `checksum` is the hex SHA-256
+    /// of the content, and empty when `uses_checksum` is false
+    fn generate(&self, original: &str, user: &str, checksum: &str) -> String;
+
+    /// Whether the name depends on the content, so it is only known once the upload
+    /// has been received
+    fn uses_checksum(&self) -> bool {
+        false
+    }
+}
+
+/// `.ext` of `original`, including the dot, or empty
+fn extension(original: &str) -> &str {
+    match original.rfind('.') {
+        Some(dot) if dot > 0 => &original[dot..],
+        _ => "",
+    }
+}
+
+/// Keeps the client's name (subject to the collision policy)
+pub struct OriginalName;
+
+impl NameStrategy for OriginalName {
+    fn generate(&self, original: &str, _user: &str, _checksum: &str) -> String {
+        original.to_string()
+    }
+}
+
+/// A random UUID with the original extension, e.g. `3f2c…e1.pdf`
+pub struct UuidName;
+
+impl NameStrategy for UuidName {
+    fn generate(&self, original: &str, _user: &str, _checksum: &str) -> String {
+        format!("{}{}", Uuid::new_v4(), extension(original))
+    }
+}
+
+/// The original name prefixed with the UTC upload time, e.g. `20240501T120000Z_report.pdf`
+pub struct TimestampedName;
+
+impl NameStrategy for TimestampedName {
+    fn generate(&self, original: &str, _user: &str, _checksum: &str) -> String {
+        format!("{}_{}", Utc::now().format("%Y%m%dT%H%M%SZ"), original)
+    }
+}
+
+/// The content's SHA-256 with the original extension
+pub struct HashedName;
+
+impl NameStrategy for HashedName {
+    fn generate(&self, original: &str, _user: &str, checksum: &str) -> String {
+        format!("{}{}", checksum, extension(original))
+    }
+
+    fn uses_checksum(&self) -> bool {
+        true
+    }
+}
+
+/// Reads `NAME_STRATEGY`: `original` (default), `uuid`, `timestamped` or `hashed`
+pub fn name_strategy_from_env() -> Result<Box<dyn NameStrategy>, String> {
+    match env::var("NAME_STRATEGY")
+        .unwrap_or_default()
+        .to_lowercase()
+        .as_str()
+    {
+        "" | "original" => Ok(Box::new(OriginalName)),
+        "uuid" => Ok(Box::new(UuidName)),
+        "timestamped" => Ok(Box::new(TimestampedName)),
+        "hashed" => Ok(Box::new(HashedName)),
+        other => Err(format!(
+            "Invalid NAME_STRATEGY '{}', expected original, uuid, timestamped or hashed",
+            other
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    const CHECKSUM: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn each_strategy_names_the_same_upload_its_own_way() {
+        let generate =
+            |strategy: &dyn NameStrategy| strategy.generate("report.final.pdf", "alice", CHECKSUM);
+
+        assert_eq!(generate(&OriginalName), "report.final.pdf");
+
+        let uuid = generate(&UuidName);
+        let stem = uuid.strip_suffix(".pdf").unwrap();
+        assert!(Uuid::parse_str(stem).is_ok(), "{}", uuid);
+        assert_ne!(uuid, generate(&UuidName));
+
+        let timestamped = generate(&TimestampedName);
+        let (stamp, rest) = timestamped.split_once('_').unwrap();
+        assert_eq!(rest, "report.final.pdf");
+        assert!(
+            chrono::NaiveDateTime::parse_from_str(stamp, "%Y%m%dT%H%M%SZ").is_ok(),
+            "{}",
+            timestamped
+        );
+
+        assert_eq!(generate(&HashedName), format!("{}.pdf", CHECKSUM));
+        assert!(HashedName.uses_checksum());
+        assert!(!UuidName.uses_checksum());
+    }
+
+    #[test]
+    fn names_without_an_extension_get_none() {
+        assert_eq!(HashedName.generate("README", "alice", CHECKSUM), CHECKSUM);
+        assert_eq!(HashedName.generate(".env", "alice", CHECKSUM), CHECKSUM);
+        assert!(!UuidName.generate("README", "alice", CHECKSUM).contains('.'));
+    }
+
+    #[test]
+    fn strategy_is_chosen_by_name_strategy() {
+        let mut env = TestEnv::new();
+        let generate = || {
+            name_strategy_from_env().map(|strategy| strategy.generate("a.txt", "alice", CHECKSUM))
+        };
+        env.remove("NAME_STRATEGY");
+        assert_eq!(generate().unwrap(), "a.txt");
+        env.set("NAME_STRATEGY", "Hashed");
+        assert_eq!(generate().unwrap(), format!("{}.txt", CHECKSUM));
+        env.set("NAME_STRATEGY", "sequential");
+        assert!(generate().is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::web::Bytes;
+use futures::Stream;
+use serde::Serialize;
+use std::future::Future;
+use tokio::sync::mpsc;
+
+use crate::errors::accept_quality;
+use crate::handlers::FailedUpload;
+use crate::metadata::UploadResponse;
+
+/// Whether the client asked for upload results as newline-delimited JSON
+pub fn prefers_ndjson(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    accept_quality(accept, "application/x-ndjson") > accept_quality(accept, "application/json")
+}
+
+/// One line of a streamed upload response
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResultLine {
+    /// A file was written to disk; it is only kept if the summary reports success
+    File(UploadResponse),
+    /// A file was rejected and skipped under `MULTI_UPLOAD_POLICY=best-effort`
+    Failed(FailedUpload),
+    /// Always the last line
+    Summary {
+        status: &'static str,
+        status_code: u16,
+        files: usize,
+        message: String,
+    },
+}
+
+/// Sender for a streamed upload response, stored in the request's extensions while
+/// the upload is received
+#[derive(Clone)]
+pub struct ResultLines {
+    sender: mpsc::UnboundedSender<Bytes>,
+}
+
+impl ResultLines {
+    /// A sender and the receiving end of the lines it queues
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Bytes>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+
+    /// Response body that runs `upload` as it is polled, forwarding the lines it queues.
+    /// The upload lives and dies with the response, so a client that disconnects drops
+    /// it; the stream ends once `upload` has finished and its lines are drained.
+    pub fn stream(
+        receiver: mpsc::UnboundedReceiver<Bytes>,
+        upload: impl Future<Output = ()> + 'static,
+    ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+        let upload = Some(Box::pin(upload));
+        futures::stream::unfold(
+            (receiver, upload),
+            |(mut receiver, mut upload)| async move {
+                if let Some(running) = upload.as_mut() {
+                    tokio::select! {
+                        biased;
+                        Some(line) = receiver.recv() => return Some((Ok(line), (receiver, upload))),
+                        () = running => {}
+                    }
+                    upload = None;
+                }
+                let line = receiver.try_recv().ok()?;
+                Some((Ok(line), (receiver, upload)))
+            },
+        )
+    }
+
+    /// Queues `line`; a client that went away is ignored
+    pub fn send(&self, line: &ResultLine) {
+        match serde_json::to_vec(line) {
+            Ok(mut json) => {
+                json.push(b'\n');
+                let _ = self.sender.send(Bytes::from(json));
+            }
+            Err(e) => log::error!("Failed to serialize upload result line: {}", e),
+        }
+    }
+}
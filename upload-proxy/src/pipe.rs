@@ -0,0 +1,124 @@
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io;
+use std::process::Stdio;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::task::JoinHandle;
+
+/// Program and arguments uploads are streamed through before being stored, from
+/// `UPLOAD_PIPE_COMMAND`; run without a shell. Unset or empty stores uploads as sent.
+pub fn pipe_command() -> Option<Vec<String>> {
+    let argv: Vec<String> = env::var("UPLOAD_PIPE_COMMAND")
+        .ok()?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    (!argv.is_empty()).then_some(argv)
+}
+
+/// The command's output, as written to disk
+pub struct PipedFile {
+    pub size_bytes: u64,
+    pub checksum: String,
+    /// Leading bytes, for content type detection
+    pub head: Vec<u8>,
+}
+
+/// A running pipe command whose stdout is being copied into the stored file
+pub struct PipeOutput {
+    child: Child,
+    copy: JoinHandle<io::Result<PipedFile>>,
+}
+
+/// Starts `argv` with its output going to `file`; the upload is written to the
+/// returned stdin, and dropping it signals the end of the upload
+pub fn spawn(
+    argv: &[String],
+    file: File,
+    head_bytes: usize,
+) -> io::Result<(ChildStdin, PipeOutput)> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty pipe command"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(io::Error::other("pipe command has no stdio"));
+    };
+    let copy = actix_web::rt::spawn(copy_output(stdout, file, head_bytes));
+    Ok((stdin, PipeOutput { child, copy }))
+}
+
+async fn copy_output(
+    mut stdout: ChildStdout,
+    mut file: File,
+    head_bytes: usize,
+) -> io::Result<PipedFile> {
+    let mut hasher = Sha256::new();
+    let mut size_bytes = 0u64;
+    let mut head = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = stdout.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        let data = &buf[..read];
+        hasher.update(data);
+        size_bytes += read as u64;
+        if head.len() < head_bytes {
+            let wanted = head_bytes - head.len();
+            head.extend_from_slice(&data[..read.min(wanted)]);
+        }
+        file.write_all(data).await?;
+    }
+    file.flush().await?;
+    Ok(PipedFile {
+        size_bytes,
+        checksum: hex::encode(hasher.finalize()),
+        head,
+    })
+}
+
+impl PipeOutput {
+    /// Waits for the command to exit and its output to reach disk; a non-zero exit
+    /// fails even if output was produced
+    pub async fn finish(mut self) -> Result<PipedFile, String> {
+        let status = self.child.wait().await.map_err(|e| e.to_string())?;
+        let piped = self
+            .copy
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("failed to store output: {}", e))?;
+        if !status.success() {
+            return Err(format!("command exited with {}", status));
+        }
+        Ok(piped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    #[test]
+    fn pipe_command_is_split_on_whitespace() {
+        let mut env = TestEnv::new();
+        env.remove("UPLOAD_PIPE_COMMAND");
+        assert_eq!(pipe_command(), None);
+        env.set("UPLOAD_PIPE_COMMAND", "   ");
+        assert_eq!(pipe_command(), None);
+        env.set("UPLOAD_PIPE_COMMAND", " gzip  -c -9 ");
+        assert_eq!(
+            pipe_command(),
+            Some(vec!["gzip".to_string(), "-c".to_string(), "-9".to_string()])
+        );
+    }
+}
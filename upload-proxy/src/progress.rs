@@ -0,0 +1,220 @@
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::auth::{AuthenticatedUser, ANONYMOUS_USER};
+
+/// Header carrying the client-chosen id used to poll an upload's progress
+pub const UPLOAD_ID_HEADER: &str = "Upload-Id";
+
+/// Bytes received so far for one in-flight upload
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadProgress {
+    pub upload_id: String,
+    pub bytes_received: u64,
+    /// Request `Content-Length`, including multipart framing, when the client sent one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_bytes: Option<u64>,
+    pub started_at: String,
+}
+
+/// Transient, in-memory progress of uploads that sent an `Upload-Id`, keyed by
+/// `(user, upload_id)` so ids chosen by different users never collide
+#[derive(Default)]
+pub struct ProgressTracker {
+    entries: Mutex<HashMap<(String, String), UploadProgress>>,
+}
+
+impl ProgressTracker {
+    /// Starts tracking `upload_id` for `user`; fails if that user already has an upload
+    /// with that id running
+    pub fn start(
+        tracker: &web::Data<ProgressTracker>,
+        upload_id: &str,
+        user: &str,
+        expected_bytes: Option<u64>,
+    ) -> Result<ProgressGuard, actix_web::Error> {
+        let key = (user.to_string(), upload_id.to_string());
+        let mut entries = tracker.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.contains_key(&key) {
+            return Err(actix_web::error::ErrorConflict(format!(
+                "Upload {} is already in progress",
+                upload_id
+            )));
+        }
+        entries.insert(
+            key.clone(),
+            UploadProgress {
+                upload_id: upload_id.to_string(),
+                bytes_received: 0,
+                expected_bytes,
+                started_at: Utc::now().to_rfc3339(),
+            },
+        );
+        Ok(ProgressGuard {
+            tracker: tracker.clone(),
+            key,
+        })
+    }
+
+    pub fn get(&self, user: &str, upload_id: &str) -> Option<UploadProgress> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(&(user.to_string(), upload_id.to_string()))
+            .cloned()
+    }
+}
+
+/// Keeps an upload's progress entry alive; the entry is removed when the guard drops,
+/// whether the upload finished or failed
+pub struct ProgressGuard {
+    tracker: web::Data<ProgressTracker>,
+    key: (String, String),
+}
+
+impl ProgressGuard {
+    pub fn add_bytes(&self, bytes: u64) {
+        let mut entries = self
+            .tracker
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.get_mut(&self.key) {
+            entry.bytes_received += bytes;
+        }
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        let mut entries = self
+            .tracker
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        entries.remove(&self.key);
+    }
+}
+
+/// Checks a client-provided upload id: 1-128 visible ASCII characters
+pub fn validate_upload_id(upload_id: &str) -> Result<(), actix_web::Error> {
+    if upload_id.is_empty()
+        || upload_id.len() > 128
+        || !upload_id.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(actix_web::error::ErrorBadRequest("Invalid Upload-Id"));
+    }
+    Ok(())
+}
+
+/// Reports bytes received so far for one of the caller's in-flight uploads
+pub async fn upload_progress(
+    path: web::Path<String>,
+    user: Option<AuthenticatedUser>,
+    tracker: web::Data<ProgressTracker>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let upload_id = path.into_inner();
+    let user = user.map_or_else(|| ANONYMOUS_USER.to_string(), |user| user.sub);
+    // Other users' uploads are never visible, so they're reported as unknown
+    match tracker.get(&user, &upload_id) {
+        Some(progress) => Ok(HttpResponse::Ok().json(progress)),
+        None => Err(actix_web::error::ErrorNotFound(
+            "No upload in progress with that id",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve, Form, TestEnv, TEST_USER_HEADER};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    /// `GET /api/upload/{id}/progress` as `user`
+    async fn poll(url: &str, user: &str, upload_id: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}/api/upload/{}/progress", url, upload_id))
+            .header(TEST_USER_HEADER, user)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Polls until more than `after` bytes of `upload_id` have been received
+    async fn received_more_than(url: &str, upload_id: &str, after: u64) -> u64 {
+        for _ in 0..100 {
+            let resp = poll(url, "alice", upload_id).await;
+            if resp.status() == 200 {
+                let progress: serde_json::Value = resp.json().await.unwrap();
+                let received = progress["bytes_received"].as_u64().unwrap();
+                if received > after {
+                    return received;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("no progress past {} bytes", after);
+    }
+
+    #[actix_web::test]
+    async fn progress_is_reported_while_uploading_and_removed_after() {
+        let env = TestEnv::new();
+        let url = serve();
+        let (content_type, body) = Form::new().file("big.bin", &[7u8; 256 * 1024]).finish();
+
+        let stream = TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+        let mut stream = BufReader::new(stream);
+        let head = format!(
+            "POST /api/upload HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\n\
+             Content-Length: {}\r\n{}: up-1\r\n{}: alice\r\n\r\n",
+            content_type,
+            body.len(),
+            UPLOAD_ID_HEADER,
+            TEST_USER_HEADER
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let (first, rest) = body.split_at(body.len() / 3);
+        let (second, third) = rest.split_at(rest.len() / 2);
+
+        stream.write_all(first).await.unwrap();
+        let early = received_more_than(&url, "up-1", 0).await;
+        stream.write_all(second).await.unwrap();
+        let later = received_more_than(&url, "up-1", early).await;
+        assert!(later < 256 * 1024);
+
+        // Ids are per user: others can't see it, and the owner can't reuse it meanwhile
+        assert_eq!(poll(&url, "bob", "up-1").await.status(), 404);
+        let (content_type, duplicate) = Form::new().file("other.bin", b"x").finish();
+        let resp = reqwest::Client::new()
+            .post(format!("{}/api/upload", url))
+            .header("Content-Type", content_type)
+            .header(UPLOAD_ID_HEADER, "up-1")
+            .header(TEST_USER_HEADER, "alice")
+            .body(duplicate)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 409);
+
+        stream.write_all(third).await.unwrap();
+        let mut status = String::new();
+        stream.read_line(&mut status).await.unwrap();
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(poll(&url, "alice", "up-1").await.status(), 404);
+        assert_eq!(env.stored_files(), ["big.bin"]);
+    }
+
+    #[test]
+    fn upload_ids_must_be_short_visible_ascii() {
+        assert!(validate_upload_id("a1-B2_c3").is_ok());
+        assert!(validate_upload_id("").is_err());
+        assert!(validate_upload_id("has space").is_err());
+        assert!(validate_upload_id(&"x".repeat(129)).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::AuthenticatedUser;
+
+/// What requests are counted against, from `RATE_LIMIT_KEY`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitKey {
+    /// The client's IP address
+    Ip,
+    /// The authenticated subject; unauthenticated routes still use the IP
+    User,
+}
+
+impl RateLimitKey {
+    /// Reads `RATE_LIMIT_KEY`, defaulting to `ip`
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("RATE_LIMIT_KEY")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "ip" => Ok(Self::Ip),
+            "user" => Ok(Self::User),
+            other => Err(format!(
+                "Invalid RATE_LIMIT_KEY '{}', expected ip or user",
+                other
+            )),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(per_minute: u32) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refills for the time since the last request, then takes a token or returns the
+    /// seconds until one is available
+    fn take(&mut self, per_minute: u32, now: Instant) -> Result<(), u64> {
+        let capacity = per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / per_sec).ceil() as u64)
+        }
+    }
+}
+
+/// Token-bucket limiter shared by all workers: each key may burst up to
+/// `RATE_LIMIT_PER_MINUTE` requests, refilled evenly over a minute
+pub struct RateLimiter {
+    per_minute: Option<u32>,
+    key: RateLimitKey,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Uploads accepted per minute across all clients, from `GLOBAL_UPLOADS_PER_MINUTE`
+    uploads_per_minute: Option<u32>,
+    uploads: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Reads `RATE_LIMIT_PER_MINUTE`, `RATE_LIMIT_KEY` and `GLOBAL_UPLOADS_PER_MINUTE`;
+    /// an unset or 0 limit is disabled
+    pub fn from_env() -> Result<Self, String> {
+        let limit = |var: &str| {
+            env::var(var)
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .filter(|&v| v > 0)
+        };
+        let uploads_per_minute = limit("GLOBAL_UPLOADS_PER_MINUTE");
+        Ok(Self {
+            per_minute: limit("RATE_LIMIT_PER_MINUTE"),
+            key: RateLimitKey::from_env()?,
+            buckets: Mutex::new(HashMap::new()),
+            uploads_per_minute,
+            uploads: Mutex::new(Bucket::full(uploads_per_minute.unwrap_or_default())),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.per_minute.is_some()
+    }
+
+    pub fn key(&self) -> RateLimitKey {
+        self.key
+    }
+
+    pub fn uploads_per_minute(&self) -> Option<u32> {
+        self.uploads_per_minute
+    }
+
+    /// Takes a token from the budget shared by every upload, whoever sends it, or
+    /// returns the seconds until one is available
+    pub fn acquire_upload(&self) -> Result<(), u64> {
+        let Some(per_minute) = self.uploads_per_minute else {
+            return Ok(());
+        };
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take(per_minute, Instant::now())
+    }
+
+    /// Takes a token for `key`, or returns the seconds until one is available
+    fn acquire(&self, key: &str) -> Result<(), u64> {
+        let Some(per_minute) = self.per_minute else {
+            return Ok(());
+        };
+        let capacity = per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget buckets that have refilled completely
+        if buckets.len() > 10_000 {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec
+                    < capacity
+            });
+        }
+        buckets
+            .entry(key.to_string())
+            .or_insert_with(|| Bucket::full(per_minute))
+            .take(per_minute, now)
+    }
+}
+
+/// 429 telling the client when to retry
+pub fn too_many_requests(retry_after: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .json(serde_json::json!({
+            "error": "rate_limited",
+            "retry_after_secs": retry_after,
+        }))
+}
+
+/// Applies the rate limit; mount it inside the auth middleware so `user` mode
+/// sees the validated subject
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limiter = match req.app_data::<web::Data<RateLimiter>>() {
+        Some(limiter) if limiter.enabled() => limiter.clone(),
+        _ => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+
+    let user = match limiter.key() {
+        RateLimitKey::User => req
+            .extensions()
+            .get::<AuthenticatedUser>()
+            .map(|user| user.sub.clone()),
+        RateLimitKey::Ip => None,
+    };
+    let key = match user {
+        Some(sub) => format!("user:{}", sub),
+        None => format!(
+            "ip:{}",
+            req.peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default()
+        ),
+    };
+
+    if let Err(retry_after) = limiter.acquire(&key) {
+        log::warn!("Rate limit exceeded for {}", key);
+        let response = too_many_requests(retry_after);
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service};
+
+    /// Statuses of uploads sent by `(user, ip)` in turn, with two requests a minute
+    async fn upload_statuses(key: &str, senders: &[(&str, &str)]) -> Vec<StatusCode> {
+        let _env = TestEnv::new()
+            .with("RATE_LIMIT_PER_MINUTE", "2")
+            .with("RATE_LIMIT_KEY", key);
+        let app = init_service(app()).await;
+        let mut statuses = Vec::new();
+        for (user, ip) in senders {
+            let req = Form::new()
+                .file("a.txt", b"hello")
+                .post("/api/upload")
+                .insert_header((TEST_USER_HEADER, *user))
+                .peer_addr(format!("{}:40000", ip).parse().unwrap())
+                .to_request();
+            statuses.push(call_service(&app, req).await.status());
+        }
+        statuses
+    }
+
+    #[actix_web::test]
+    async fn user_mode_shares_a_bucket_across_addresses() {
+        let statuses = upload_statuses(
+            "user",
+            &[
+                ("alice", "10.0.0.1"),
+                ("alice", "10.0.0.2"),
+                ("alice", "10.0.0.3"),
+                ("bob", "10.0.0.1"),
+            ],
+        )
+        .await;
+        use StatusCode as S;
+        assert_eq!(statuses, [S::OK, S::OK, S::TOO_MANY_REQUESTS, S::OK]);
+    }
+
+    #[actix_web::test]
+    async fn ip_mode_shares_a_bucket_across_users() {
+        let statuses = upload_statuses(
+            "ip",
+            &[
+                ("alice", "10.0.0.1"),
+                ("bob", "10.0.0.1"),
+                ("carol", "10.0.0.1"),
+                ("alice", "10.0.0.2"),
+            ],
+        )
+        .await;
+        use StatusCode as S;
+        assert_eq!(statuses, [S::OK, S::OK, S::TOO_MANY_REQUESTS, S::OK]);
+    }
+
+    #[test]
+    fn rate_limit_key_must_be_ip_or_user() {
+        let mut env = TestEnv::new();
+        env.remove("RATE_LIMIT_KEY");
+        assert_eq!(RateLimitKey::from_env(), Ok(RateLimitKey::Ip));
+        env.set("RATE_LIMIT_KEY", "User");
+        assert_eq!(RateLimitKey::from_env(), Ok(RateLimitKey::User));
+        env.set("RATE_LIMIT_KEY", "session");
+        assert!(RateLimitKey::from_env().is_err());
+    }
+
+    #[test]
+    fn empty_bucket_reports_when_a_token_is_due() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(60);
+        bucket.tokens = 0.0;
+        bucket.updated = start;
+        assert_eq!(bucket.take(60, start), Err(1));
+        assert_eq!(
+            bucket.take(60, start + std::time::Duration::from_secs(1)),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use actix_web::{web, HttpResponse};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::metadata::UploadMetadata;
+
+/// Issuer recorded in receipts, so they can't be confused with Keycloak tokens
+const RECEIPT_ISSUER: &str = "upload-proxy";
+
+/// Claims of a signed upload receipt
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceiptClaims {
+    pub iss: String,
+    /// Uploading user
+    pub sub: String,
+    pub filename: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    pub size_bytes: u64,
+    pub timestamp: String,
+    pub iat: u64,
+}
+
+/// HS256 secret receipts are signed with, from `RECEIPT_SIGNING_KEY`; unset disables receipts
+fn signing_key() -> Option<String> {
+    env::var("RECEIPT_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+}
+
+/// Signs a receipt for a stored upload, or returns `None` when receipts are disabled
+pub fn issue_receipt(metadata: &UploadMetadata) -> Option<String> {
+    let key = signing_key()?;
+    let claims = ReceiptClaims {
+        iss: RECEIPT_ISSUER.to_string(),
+        sub: metadata.user.clone(),
+        filename: metadata.filename.clone(),
+        checksum: metadata.checksum.clone(),
+        size_bytes: metadata.size_bytes,
+        timestamp: metadata.timestamp.clone(),
+        iat: jsonwebtoken::get_current_timestamp(),
+    };
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(key.as_bytes()),
+    )
+    .map_err(|e| log::error!("Failed to sign receipt for {}: {}", metadata.filename, e))
+    .ok()
+}
+
+#[derive(Deserialize)]
+pub struct VerifyQuery {
+    pub receipt: String,
+}
+
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    pub valid: bool,
+    pub receipt: ReceiptClaims,
+}
+
+/// Checks a receipt's signature and returns its claims
+pub async fn verify_receipt(
+    query: web::Query<VerifyQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let key = signing_key()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Upload receipts are not enabled"))?;
+
+    // Receipts don't expire; they record that an upload happened
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    validation.set_issuer(&[RECEIPT_ISSUER]);
+
+    let data = decode::<ReceiptClaims>(
+        &query.receipt,
+        &DecodingKey::from_secret(key.as_bytes()),
+        &validation,
+    )
+    .map_err(|e| {
+        log::warn!("Receipt verification failed: {}", e);
+        actix_web::error::ErrorBadRequest("Invalid receipt")
+    })?;
+
+    Ok(HttpResponse::Ok().json(VerifyResponse {
+        valid: true,
+        receipt: data.claims,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn receipts_verify_and_tampered_ones_fail() {
+        let _env = TestEnv::new().with("RECEIPT_SIGNING_KEY", "receipt-secret");
+        let app = init_service(app()).await;
+        let req = Form::new()
+            .file("a.txt", b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let upload: Value = read_body_json(call_service(&app, req).await).await;
+        let receipt = upload["receipt"].as_str().unwrap().to_string();
+
+        let verify = |receipt: String| {
+            let req = TestRequest::get()
+                .uri(&format!("/api/receipts/verify?receipt={}", receipt))
+                .to_request();
+            call_service(&app, req)
+        };
+        let resp = verify(receipt.clone()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body: Value = read_body_json(resp).await;
+        assert_eq!(body["valid"], true);
+        assert_eq!(body["receipt"]["sub"], "alice");
+        assert_eq!(body["receipt"]["filename"], "a.txt");
+        assert_eq!(body["receipt"]["checksum"], upload["checksum"]);
+
+        // Claim someone else uploaded it, keeping the original signature
+        let parts: Vec<&str> = receipt.split('.').collect();
+        let mut claims = body["receipt"].take();
+        claims["sub"] = "mallory".into();
+        let forged = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"other-secret"),
+        )
+        .unwrap();
+        let forged = forged.split('.').nth(1).unwrap();
+        let tampered = format!("{}.{}.{}", parts[0], forged, parts[2]);
+        assert_eq!(verify(tampered).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn receipts_are_off_without_a_signing_key() {
+        let mut env = TestEnv::new();
+        env.remove("RECEIPT_SIGNING_KEY");
+        let app = init_service(app()).await;
+        let req = Form::new()
+            .file("a.txt", b"hello")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let upload: Value = read_body_json(call_service(&app, req).await).await;
+        assert!(upload.get("receipt").is_none());
+
+        let req = TestRequest::get()
+            .uri("/api/receipts/verify?receipt=x.y.z")
+            .to_request();
+        assert_eq!(
+            call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{middleware, web, App, Resource};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use std::time::Duration;
+
+use crate::admin::{
+    admin_stats, admin_type_stats, backfill_content_types, orphaned_users, rebuild_metadata,
+};
+use crate::auth::{normalize_token_scheme, validator};
+use crate::compression::{compress_responses, skip_small_responses};
+use crate::dav::{dav_options, dav_propfind_collection, dav_propfind_file, propfind_method};
+use crate::downloads::limit_downloads;
+use crate::errors::negotiate_errors;
+use crate::events::events_ws;
+use crate::handlers::{
+    archive_manifest, download_by_checksum, download_file, exchange_token, file_checksum,
+    file_delivery, file_lines, file_webp, health_check, health_live, health_ready, list_files,
+    metadata_batch, not_found, refresh_token, upload_file, upload_preflight,
+};
+use crate::metrics::metrics;
+use crate::progress::upload_progress;
+use crate::ratelimit::rate_limit;
+use crate::receipts::verify_receipt;
+use crate::routing::{
+    enforce_deadline, limit_header_size, max_header_bytes, reject_missing_user_agent,
+    request_deadline, require_trailing_slash, require_user_agent, route_prefix, AuthRequirements,
+    TrailingSlashMode,
+};
+
+/// Which optional request middleware runs, read once at startup
+#[derive(Debug, Clone, Copy)]
+pub struct MiddlewareSettings {
+    pub trailing_slash: TrailingSlashMode,
+    pub user_agent_required: bool,
+    pub header_limit: Option<usize>,
+    pub deadline: Option<Duration>,
+    pub compress: bool,
+}
+
+impl MiddlewareSettings {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            trailing_slash: TrailingSlashMode::from_env()?,
+            user_agent_required: require_user_agent(),
+            header_limit: max_header_bytes(),
+            deadline: request_deadline(),
+            compress: compress_responses(),
+        })
+    }
+}
+
+/// Wraps `app` in the request middleware every route runs behind, innermost first
+pub fn wrap_middleware<T, B>(
+    app: App<T>,
+    settings: MiddlewareSettings,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+>
+where
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let trailing_slash = settings.trailing_slash;
+    app.wrap(middleware::Condition::new(
+        trailing_slash.normalizes(),
+        middleware::NormalizePath::new(middleware::TrailingSlash::Trim),
+    ))
+    .wrap(middleware::Condition::new(
+        trailing_slash == TrailingSlashMode::Require,
+        middleware::from_fn(require_trailing_slash),
+    ))
+    .wrap(middleware::from_fn(normalize_token_scheme))
+    .wrap(middleware::Condition::new(
+        settings.user_agent_required,
+        middleware::from_fn(reject_missing_user_agent),
+    ))
+    .wrap(middleware::Condition::new(
+        settings.header_limit.is_some(),
+        middleware::from_fn(limit_header_size),
+    ))
+    .wrap(middleware::Condition::new(
+        settings.deadline.is_some(),
+        middleware::from_fn(enforce_deadline),
+    ))
+    .wrap(middleware::from_fn(negotiate_errors))
+    .wrap(middleware::Condition::new(
+        settings.compress,
+        middleware::from_fn(skip_small_responses),
+    ))
+    .wrap(middleware::Condition::new(
+        settings.compress,
+        middleware::Compress::default(),
+    ))
+}
+
+/// `resource` behind the rate limiter and, when `required`, bearer authentication. Rate
+/// limits are applied inside auth so `RATE_LIMIT_KEY=user` sees the subject.
+fn guarded<T, B>(
+    resource: Resource<T>,
+    required: bool,
+) -> Resource<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+>
+where
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    resource
+        .wrap(middleware::from_fn(rate_limit))
+        .wrap(middleware::Condition::new(
+            required,
+            HttpAuthentication::bearer(validator),
+        ))
+}
+
+/// Every route, mounted under `ROUTE_PREFIX`, with `auth` deciding which sit behind
+/// bearer authentication
+pub fn configure(cfg: &mut web::ServiceConfig, auth: AuthRequirements) {
+    cfg.default_service(web::to(not_found)).service(
+        web::scope(&route_prefix())
+            .service(
+                web::resource("/health")
+                    .route(web::get().to(health_check))
+                    .route(web::head().to(health_check)),
+            )
+            .service(
+                web::resource("/health/live")
+                    .route(web::get().to(health_live))
+                    .route(web::head().to(health_live)),
+            )
+            .service(
+                web::resource("/health/ready")
+                    .route(web::get().to(health_ready))
+                    .route(web::head().to(health_ready)),
+            )
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(guarded(
+                web::resource("/token").route(web::post().to(exchange_token)),
+                false,
+            ))
+            .service(guarded(
+                web::resource("/refresh").route(web::post().to(refresh_token)),
+                false,
+            ))
+            .service(
+                web::scope("/api")
+                    .service(guarded(
+                        web::resource("/upload").route(web::post().to(upload_file)),
+                        auth.upload,
+                    ))
+                    .service(guarded(
+                        web::resource("/upload/preflight").route(web::post().to(upload_preflight)),
+                        auth.upload,
+                    ))
+                    .service(guarded(
+                        web::resource("/upload/{id}/progress")
+                            .route(web::get().to(upload_progress)),
+                        auth.upload,
+                    ))
+                    .service(guarded(
+                        web::resource("/files").route(web::get().to(list_files)),
+                        auth.list,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/archive/manifest")
+                            .route(web::post().to(archive_manifest)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/metadata/batch")
+                            .route(web::post().to(metadata_batch)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/{filename}")
+                            .wrap(middleware::from_fn(limit_downloads))
+                            .route(web::get().to(download_file)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/{filename}/checksum")
+                            .route(web::get().to(file_checksum)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/{filename}/delivery")
+                            .route(web::get().to(file_delivery)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/{filename}/lines")
+                            .wrap(middleware::from_fn(limit_downloads))
+                            .route(web::get().to(file_lines)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/files/{filename}/webp")
+                            .wrap(middleware::from_fn(limit_downloads))
+                            .route(web::get().to(file_webp)),
+                        auth.download,
+                    ))
+                    .service(guarded(
+                        web::resource("/content/{sha256}")
+                            .wrap(middleware::from_fn(limit_downloads))
+                            .route(web::get().to(download_by_checksum)),
+                        auth.download,
+                    ))
+                    // Everything else under /api always requires a token
+                    .service(
+                        web::scope("")
+                            .wrap(middleware::from_fn(rate_limit))
+                            .wrap(middleware::Condition::new(
+                                auth.api,
+                                HttpAuthentication::bearer(validator),
+                            ))
+                            .route("/ws", web::get().to(events_ws))
+                            .route("/receipts/verify", web::get().to(verify_receipt))
+                            .route("/admin/stats", web::get().to(admin_stats))
+                            .route("/admin/stats/types", web::get().to(admin_type_stats))
+                            .route("/admin/orphaned-users", web::get().to(orphaned_users))
+                            .route("/admin/metadata/rebuild", web::post().to(rebuild_metadata))
+                            .route(
+                                "/admin/backfill/content-types",
+                                web::post().to(backfill_content_types),
+                            ),
+                    ),
+            )
+            // Read-only WebDAV view of the caller's files, for OS file managers
+            .service(
+                web::scope("/dav")
+                    .wrap(middleware::from_fn(rate_limit))
+                    .wrap(middleware::Condition::new(
+                        auth.download,
+                        HttpAuthentication::bearer(validator),
+                    ))
+                    .service(
+                        web::resource(["", "/"])
+                            .route(web::method(Method::OPTIONS).to(dav_options))
+                            .route(web::method(propfind_method()).to(dav_propfind_collection)),
+                    )
+                    .service(
+                        web::resource("/{filename}")
+                            .wrap(middleware::from_fn(limit_downloads))
+                            .route(web::method(Method::OPTIONS).to(dav_options))
+                            .route(web::method(propfind_method()).to(dav_propfind_file))
+                            .route(web::get().to(download_file))
+                            .route(web::head().to(download_file)),
+                    ),
+            ),
+    );
+}
@@ -0,0 +1,423 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use std::env;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How requests with a trailing slash are routed, from `TRAILING_SLASH`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingSlashMode {
+    /// `/health/` is served as `/health`
+    Trim,
+    /// Only `/health/` is served; `/health` returns 404
+    Require,
+    /// Paths must match routes exactly
+    Off,
+}
+
+impl TrailingSlashMode {
+    /// Reads `TRAILING_SLASH`, defaulting to `trim`
+    pub fn from_env() -> Result<Self, String> {
+        match env::var("TRAILING_SLASH")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "trim" => Ok(Self::Trim),
+            "require" => Ok(Self::Require),
+            "off" => Ok(Self::Off),
+            other => Err(format!(
+                "Invalid TRAILING_SLASH '{}', expected trim, require or off",
+                other
+            )),
+        }
+    }
+
+    /// Whether paths are normalized before routing
+    pub fn normalizes(&self) -> bool {
+        *self != Self::Off
+    }
+}
+
+/// Which route groups sit behind bearer authentication
+#[derive(Debug, Clone, Copy)]
+pub struct AuthRequirements {
+    /// `POST /api/upload` and its progress endpoint, from `REQUIRE_AUTH_UPLOAD`
+    pub upload: bool,
+    /// `GET /api/files/{filename}` and derived views, from `REQUIRE_AUTH_DOWNLOAD`
+    pub download: bool,
+    /// `GET /api/files`, from `REQUIRE_AUTH_LIST`
+    pub list: bool,
+    /// Admin, receipt and WebSocket routes; always on outside tests
+    pub api: bool,
+}
+
+impl AuthRequirements {
+    /// Reads the `REQUIRE_AUTH_*` flags; anything but `false`/`0` keeps auth on
+    pub fn from_env() -> Self {
+        let required = |var: &str| {
+            env::var(var)
+                .map(|v| v != "false" && v != "0")
+                .unwrap_or(true)
+        };
+        Self {
+            upload: required("REQUIRE_AUTH_UPLOAD"),
+            download: required("REQUIRE_AUTH_DOWNLOAD"),
+            list: required("REQUIRE_AUTH_LIST"),
+            api: true,
+        }
+    }
+}
+
+/// Path prefix all routes are mounted under, from `ROUTE_PREFIX` (e.g. `/uploads`).
+///
+/// Normalized to a leading slash and no trailing slash; empty mounts at the root.
+pub fn route_prefix() -> String {
+    let prefix = env::var("ROUTE_PREFIX").unwrap_or_default();
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+/// Rejects paths without a trailing slash; runs before `NormalizePath` trims it
+pub async fn require_trailing_slash(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if !req.path().ends_with('/') {
+        return Err(actix_web::error::ErrorNotFound("Not found"));
+    }
+    next.call(req).await
+}
+
+/// Largest total size of request headers accepted, from `MAX_HEADER_BYTES`; unset or 0
+/// leaves only the server's built-in limit
+pub fn max_header_bytes() -> Option<usize> {
+    env::var("MAX_HEADER_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+}
+
+/// Rejects requests whose headers, counted as `name: value\r\n` lines, exceed
+/// `MAX_HEADER_BYTES` with 431
+pub async fn limit_header_size(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    if let Some(limit) = max_header_bytes() {
+        let size: usize = req
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        if size > limit {
+            log::warn!(
+                "Rejecting request with {} header bytes (limit {})",
+                size,
+                limit
+            );
+            return Err(actix_web::error::InternalError::new(
+                "Request headers too large",
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            )
+            .into());
+        }
+    }
+    next.call(req).await
+}
+
+/// Whether requests without a `User-Agent` are rejected, from `REQUIRE_USER_AGENT`
+pub fn require_user_agent() -> bool {
+    env::var("REQUIRE_USER_AGENT")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+
+/// Rejects requests with a missing or blank `User-Agent` with 400, which filters out
+/// trivial scanners
+pub async fn reject_missing_user_agent(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let present = req
+        .headers()
+        .get(header::USER_AGENT)
+        .is_some_and(|v| !v.as_bytes().trim_ascii().is_empty());
+    if !present {
+        log::warn!("Rejecting request to {} without a User-Agent", req.path());
+        return Err(actix_web::error::ErrorBadRequest(
+            "User-Agent header is required",
+        ));
+    }
+    next.call(req).await
+}
+
+/// Longest a handler may take to produce a response, from `REQUEST_DEADLINE_SECS`; unset
+/// or 0 disables the deadline
+pub fn request_deadline() -> Option<Duration> {
+    env::var("REQUEST_DEADLINE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&v| v > 0)
+        .map(Duration::from_secs)
+}
+
+/// When the current request's deadline passes, for handlers whose work continues in
+/// the response body (streamed upload results)
+#[derive(Clone, Copy)]
+pub struct RequestDeadline(pub Instant);
+
+/// Aborts requests that outlive `REQUEST_DEADLINE_SECS` with 503. The handler future is
+/// dropped, so guards such as name reservations and unfinished upload files are cleaned
+/// up; a response body that has already started streaming is not cut off.
+pub async fn enforce_deadline(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(deadline) = request_deadline() else {
+        return next.call(req).await;
+    };
+    let path = req.path().to_string();
+    let expires = Instant::now() + deadline;
+    req.extensions_mut().insert(RequestDeadline(expires));
+    match tokio::time::timeout_at(expires, next.call(req)).await {
+        Ok(result) => result,
+        Err(_) => {
+            log::warn!("Request to {} exceeded the {:?} deadline", path, deadline);
+            Err(actix_web::error::ErrorServiceUnavailable(
+                "Request deadline exceeded",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, app_with_auth, MockKeycloak, TestEnv};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{init_service, try_call_service, TestRequest};
+    use actix_web::{middleware, web, App, HttpResponse};
+
+    /// Status of `req` against the app as `main` wires it
+    async fn status_of(req: TestRequest) -> StatusCode {
+        let app = init_service(app()).await;
+        match try_call_service(&app, req.to_request()).await {
+            Ok(resp) => resp.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    /// Status of `GET path` with `TRAILING_SLASH` set to `mode`
+    async fn trailing_slash_status(mode: &str, path: &str) -> StatusCode {
+        let _env = TestEnv::new().with("TRAILING_SLASH", mode);
+        status_of(TestRequest::get().uri(path)).await
+    }
+
+    #[test]
+    fn trailing_slash_mode_parses() {
+        let mut env = TestEnv::new();
+        env.remove("TRAILING_SLASH");
+        assert_eq!(TrailingSlashMode::from_env(), Ok(TrailingSlashMode::Trim));
+        env.set("TRAILING_SLASH", "Require");
+        assert_eq!(
+            TrailingSlashMode::from_env(),
+            Ok(TrailingSlashMode::Require)
+        );
+        env.set("TRAILING_SLASH", "off");
+        assert_eq!(TrailingSlashMode::from_env(), Ok(TrailingSlashMode::Off));
+        env.set("TRAILING_SLASH", "sometimes");
+        assert!(TrailingSlashMode::from_env().is_err());
+    }
+
+    #[actix_web::test]
+    async fn trailing_slash_modes_route_as_documented() {
+        assert_eq!(
+            trailing_slash_status("trim", "/health").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            trailing_slash_status("trim", "/health/").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            trailing_slash_status("require", "/health/").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            trailing_slash_status("require", "/health").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            trailing_slash_status("off", "/health").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            trailing_slash_status("off", "/health/").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn route_prefix_is_normalized() {
+        let mut env = TestEnv::new();
+        env.remove("ROUTE_PREFIX");
+        assert_eq!(route_prefix(), "");
+        env.set("ROUTE_PREFIX", "/");
+        assert_eq!(route_prefix(), "");
+        env.set("ROUTE_PREFIX", "uploads/");
+        assert_eq!(route_prefix(), "/uploads");
+        env.set("ROUTE_PREFIX", " /files/v1 ");
+        assert_eq!(route_prefix(), "/files/v1");
+    }
+
+    #[actix_web::test]
+    async fn routes_resolve_only_under_the_prefix() {
+        let _env = TestEnv::new().with("ROUTE_PREFIX", "/uploads");
+        assert_eq!(
+            status_of(TestRequest::get().uri("/uploads/health")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_of(TestRequest::get().uri("/health")).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn auth_requirements_default_to_required() {
+        let mut env = TestEnv::new();
+        env.remove("REQUIRE_AUTH_UPLOAD");
+        env.set("REQUIRE_AUTH_DOWNLOAD", "false");
+        env.set("REQUIRE_AUTH_LIST", "0");
+        let auth = AuthRequirements::from_env();
+        assert!(auth.upload);
+        assert!(!auth.download);
+        assert!(!auth.list);
+        env.set("REQUIRE_AUTH_DOWNLOAD", "no");
+        assert!(AuthRequirements::from_env().download);
+    }
+
+    #[actix_web::test]
+    async fn public_downloads_keep_uploads_authenticated() {
+        let keycloak = MockKeycloak::start().await;
+        let mut env = TestEnv::new()
+            .with("REQUIRE_AUTH_DOWNLOAD", "false")
+            .with("REQUIRE_AUTH_UPLOAD", "true");
+        keycloak.configure(&mut env);
+        let auth = AuthRequirements::from_env();
+        let bearer = (
+            header::AUTHORIZATION,
+            format!("Bearer {}", keycloak.token("alice")),
+        );
+
+        let app = init_service(app_with_auth(auth)).await;
+        let status = |req: TestRequest| async {
+            match try_call_service(&app, req.to_request()).await {
+                Ok(resp) => resp.status(),
+                Err(e) => e.as_response_error().status_code(),
+            }
+        };
+
+        // Both reach their handlers: there is no such file, and no multipart body
+        let download = || TestRequest::get().uri("/api/files/a.txt");
+        assert_eq!(status(download()).await, StatusCode::NOT_FOUND);
+        let upload = || TestRequest::post().uri("/api/upload");
+        assert_eq!(status(upload()).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(upload().insert_header(bearer)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    /// Status of `GET /health` with an `X-Padding` header of `padding` bytes
+    async fn header_size_status(padding: usize) -> StatusCode {
+        let req = TestRequest::get()
+            .uri("/health")
+            .insert_header(("X-Padding", "a".repeat(padding)));
+        status_of(req).await
+    }
+
+    #[actix_web::test]
+    async fn oversized_headers_are_rejected_with_431() {
+        let _env = TestEnv::new().with("MAX_HEADER_BYTES", "1024");
+        assert_eq!(max_header_bytes(), Some(1024));
+        // "x-padding: " plus the value and line ending is exactly the limit
+        assert_eq!(header_size_status(1024 - 13).await, StatusCode::OK);
+        assert_eq!(
+            header_size_status(1024 - 12).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        assert_eq!(
+            header_size_status(8192).await,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn header_limit_is_optional() {
+        let mut env = TestEnv::new();
+        env.remove("MAX_HEADER_BYTES");
+        assert_eq!(max_header_bytes(), None);
+        env.set("MAX_HEADER_BYTES", "0");
+        assert_eq!(max_header_bytes(), None);
+        env.set("MAX_HEADER_BYTES", "lots");
+        assert_eq!(max_header_bytes(), None);
+    }
+
+    #[actix_web::test]
+    async fn slow_requests_are_cut_off_at_the_deadline() {
+        async fn slow() -> HttpResponse {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            HttpResponse::Ok().finish()
+        }
+
+        let _env = TestEnv::new().with("REQUEST_DEADLINE_SECS", "1");
+        let app = init_service(
+            App::new()
+                .wrap(middleware::from_fn(enforce_deadline))
+                .route("/slow", web::get().to(slow))
+                .route("/fast", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let started = tokio::time::Instant::now();
+        let req = TestRequest::get().uri("/slow").to_request();
+        let err = try_call_service(&app, req).await.err().unwrap();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let req = TestRequest::get().uri("/fast").to_request();
+        assert!(try_call_service(&app, req).await.is_ok());
+    }
+
+    /// Status of `GET /health` with `user_agent`
+    async fn user_agent_status(user_agent: Option<&str>) -> StatusCode {
+        let mut req = TestRequest::get().uri("/health");
+        if let Some(user_agent) = user_agent {
+            req = req.insert_header((header::USER_AGENT, user_agent));
+        }
+        status_of(req).await
+    }
+
+    #[actix_web::test]
+    async fn user_agent_is_only_required_when_enabled() {
+        let mut env = TestEnv::new();
+        env.remove("REQUIRE_USER_AGENT");
+        assert_eq!(user_agent_status(None).await, StatusCode::OK);
+
+        env.set("REQUIRE_USER_AGENT", "true");
+        assert_eq!(user_agent_status(None).await, StatusCode::BAD_REQUEST);
+        assert_eq!(user_agent_status(Some("  ")).await, StatusCode::BAD_REQUEST);
+        assert_eq!(user_agent_status(Some("curl/8.5")).await, StatusCode::OK);
+    }
+}
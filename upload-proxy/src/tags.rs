@@ -0,0 +1,122 @@
+use actix_web::HttpRequest;
+use std::env;
+
+use crate::auth::AuthenticatedUser;
+
+/// Comma-separated tags the client attaches to every file in the upload
+pub const TAGS_HEADER: &str = "X-Upload-Tags";
+
+/// Most tags a client may attach, from `MAX_TAGS_PER_FILE`
+fn max_tags_per_file() -> usize {
+    env::var("MAX_TAGS_PER_FILE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(20)
+}
+
+/// Longest tag a client may attach, in characters, from `MAX_TAG_LEN`
+fn max_tag_len() -> usize {
+    env::var("MAX_TAG_LEN")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(64)
+}
+
+/// Tags added for each role the uploader holds, from `ROLE_DEFAULT_TAGS` as
+/// `role:tag,tag;role:tag`, e.g. `finance:department=finance`
+fn role_default_tags(roles: &[String]) -> Vec<String> {
+    let config = env::var("ROLE_DEFAULT_TAGS").unwrap_or_default();
+    config
+        .split(';')
+        .filter_map(|entry| entry.split_once(':'))
+        .filter(|(role, _)| roles.iter().any(|r| r == role.trim()))
+        .flat_map(|(_, tags)| tags.split(','))
+        .map(|tag| tag.trim().to_string())
+        .collect()
+}
+
+/// Client-supplied tags followed by the defaults for the uploader's roles, without
+/// blanks or duplicates. 400 when the client sends more than `MAX_TAGS_PER_FILE` tags
+/// or one longer than `MAX_TAG_LEN`; role defaults don't count towards either.
+pub fn upload_tags(
+    req: &HttpRequest,
+    user: Option<&AuthenticatedUser>,
+) -> Result<Vec<String>, actix_web::Error> {
+    let client: Vec<String> = req
+        .headers()
+        .get(TAGS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    let max_tags = max_tags_per_file();
+    if client.len() > max_tags {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "At most {} tags per file",
+            max_tags
+        )));
+    }
+    let max_len = max_tag_len();
+    if client.iter().any(|tag| tag.chars().count() > max_len) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Tags may be at most {} characters",
+            max_len
+        )));
+    }
+
+    let defaults = user.map_or_else(Vec::new, |user| role_default_tags(&user.roles));
+    let mut tags: Vec<String> = Vec::new();
+    for tag in client.into_iter().chain(defaults) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+
+    #[test]
+    fn role_defaults_apply_for_each_role_held() {
+        let _env = TestEnv::new().with(
+            "ROLE_DEFAULT_TAGS",
+            "finance: department=finance, audited ;legal:department=legal;bad-entry",
+        );
+        let roles =
+            |roles: &[&str]| -> Vec<String> { roles.iter().map(|r| r.to_string()).collect() };
+        assert_eq!(
+            role_default_tags(&roles(&["finance"])),
+            ["department=finance", "audited"]
+        );
+        assert_eq!(
+            role_default_tags(&roles(&["legal", "finance"])),
+            ["department=finance", "audited", "department=legal"]
+        );
+        assert!(role_default_tags(&roles(&["user"])).is_empty());
+    }
+
+    fn client_tags(header: &str) -> Result<Vec<String>, actix_web::Error> {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((TAGS_HEADER, header))
+            .to_http_request();
+        upload_tags(&req, None)
+    }
+
+    #[test]
+    fn client_tags_are_trimmed_and_limited() {
+        let _env = TestEnv::new()
+            .with("MAX_TAGS_PER_FILE", "3")
+            .with("MAX_TAG_LEN", "5");
+        assert_eq!(client_tags(" a , b,, c ").unwrap(), ["a", "b", "c"]);
+
+        let error = client_tags("a,b,c,d").unwrap_err();
+        assert_eq!(error.to_string(), "At most 3 tags per file");
+        let error = client_tags("a,toolong").unwrap_err();
+        assert_eq!(error.to_string(), "Tags may be at most 5 characters");
+    }
+}
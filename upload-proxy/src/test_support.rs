@@ -0,0 +1,480 @@
+//! Helpers shared by the unit and handler tests
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::{self, Next};
+use actix_web::test::TestRequest;
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tempfile::TempDir;
+
+use crate::auth::AuthenticatedUser;
+use crate::downloads::{DownloadCache, DownloadLimiter};
+use crate::events::EventBus;
+use crate::filename::{FilenameRules, NameReservations};
+use crate::handlers::ListPaging;
+use crate::jwks::JwksCache;
+use crate::metadata::{read_metadata, UploadMetadata};
+use crate::progress::ProgressTracker;
+use crate::ratelimit::RateLimiter;
+use crate::routes::{configure, wrap_middleware, MiddlewareSettings};
+use crate::routing::AuthRequirements;
+use crate::timestamps::TimestampConfig;
+use crate::window::UploadWindow;
+
+/// Configuration is read from the environment, which is shared by every test thread, so
+/// tests that set it or run code that reads it hold this lock
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// Header naming the subject [`test_user`] authenticates the request as
+pub const TEST_USER_HEADER: &str = "X-Test-User";
+/// Comma-separated roles granted to the [`TEST_USER_HEADER`] subject
+pub const TEST_ROLES_HEADER: &str = "X-Test-Roles";
+
+/// A scratch `UPLOADS_DIR` and `METADATA_FILE`, plus environment overrides that are
+/// undone when it drops
+pub struct TestEnv {
+    saved: Vec<(String, Option<String>)>,
+    dir: TempDir,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl TestEnv {
+    pub fn new() -> Self {
+        let lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut env = Self {
+            saved: Vec::new(),
+            dir: tempfile::tempdir().expect("failed to create a temporary directory"),
+            _lock: lock,
+        };
+        let uploads_dir = env.uploads_dir().display().to_string();
+        let metadata_file = env.metadata_file();
+        env.set("UPLOADS_DIR", &uploads_dir);
+        env.set("METADATA_FILE", &metadata_file);
+        env
+    }
+
+    /// Sets `key` for the rest of the test
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.save(key);
+        env::set_var(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.save(key);
+        env::remove_var(key);
+    }
+
+    fn save(&mut self, key: &str) {
+        if !self.saved.iter().any(|(saved, _)| saved == key) {
+            self.saved.push((key.to_string(), env::var(key).ok()));
+        }
+    }
+
+    /// Scratch directory the uploads directory and metadata file live in
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().to_path_buf()
+    }
+
+    pub fn uploads_dir(&self) -> PathBuf {
+        self.dir.path().join("uploads")
+    }
+
+    pub fn metadata_file(&self) -> String {
+        self.dir.path().join("uploads.json").display().to_string()
+    }
+
+    /// Stored metadata entries, oldest first
+    pub fn entries(&self) -> Vec<UploadMetadata> {
+        read_metadata(&self.metadata_file()).expect("failed to read metadata")
+    }
+
+    /// Replaces the metadata file with `entries`, as if they had been uploaded
+    pub fn seed(&self, entries: &[UploadMetadata]) {
+        let json = serde_json::to_string(entries).expect("failed to serialize metadata");
+        std::fs::write(self.metadata_file(), json).expect("failed to write metadata");
+    }
+
+    /// Names of the files in the uploads directory, sorted
+    pub fn stored_files(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(self.uploads_dir()) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for (key, value) in self.saved.drain(..).rev() {
+            match value {
+                Some(value) => env::set_var(&key, value),
+                None => env::remove_var(&key),
+            }
+        }
+    }
+}
+
+/// Stands in for the bearer middleware: a request with [`TEST_USER_HEADER`] is
+/// authenticated as that subject
+pub async fn test_user(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    if let Some(sub) = header(TEST_USER_HEADER) {
+        let roles = header(TEST_ROLES_HEADER)
+            .map(|roles| roles.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        req.extensions_mut()
+            .insert(AuthenticatedUser { sub, roles });
+    }
+    next.call(req).await
+}
+
+/// The app `main` serves, with shared state built from the current environment.
+/// Authentication is replaced by [`test_user`].
+pub fn app() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    app_with_auth(AuthRequirements {
+        upload: false,
+        download: false,
+        list: false,
+        api: false,
+    })
+}
+
+/// [`app`] with the routes `auth` marks behind real bearer authentication
+pub fn app_with_auth(
+    auth: AuthRequirements,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let settings = MiddlewareSettings::from_env().expect("invalid middleware settings");
+    wrap_middleware(App::new(), settings)
+        .wrap(middleware::from_fn(test_user))
+        .app_data(web::Data::new(
+            FilenameRules::from_env().expect("invalid filename rules"),
+        ))
+        .app_data(web::Data::new(JwksCache::new()))
+        .app_data(web::Data::new(DownloadCache::from_env()))
+        .app_data(web::Data::new(DownloadLimiter::from_env()))
+        .app_data(web::Data::new(EventBus::new(16)))
+        .app_data(web::Data::new(
+            ListPaging::from_env().expect("invalid list paging"),
+        ))
+        .app_data(web::Data::new(ProgressTracker::default()))
+        .app_data(web::Data::new(
+            RateLimiter::from_env().expect("invalid rate limit"),
+        ))
+        .app_data(web::Data::new(NameReservations::default()))
+        .app_data(web::Data::new(
+            TimestampConfig::from_env().expect("invalid timestamp format"),
+        ))
+        .app_data(web::Data::new(
+            UploadWindow::from_env().expect("invalid upload window"),
+        ))
+        .configure(|cfg| configure(cfg, auth))
+}
+
+/// Serves [`app`] on a local port with one worker, so requests share its state, and
+/// returns its base URL. For what needs a real connection, such as WebSockets.
+pub fn serve() -> String {
+    let server = HttpServer::new(app)
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("failed to bind the test server");
+    let url = format!("http://{}", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+    url
+}
+
+/// A `multipart/form-data` body built part by part
+#[derive(Default)]
+pub struct Form {
+    body: Vec<u8>,
+}
+
+impl Form {
+    const BOUNDARY: &'static str = "test-boundary-7MA4YWxkTrZu0gW";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a `file` part sent as `application/octet-stream`
+    pub fn file(self, filename: &str, content: &[u8]) -> Self {
+        self.part(Some(filename), Some("application/octet-stream"), content)
+    }
+
+    /// Adds a `file` part; `None` leaves out the filename or `Content-Type`
+    pub fn part(
+        mut self,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+        content: &[u8],
+    ) -> Self {
+        let mut headers = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"",
+            Self::BOUNDARY
+        );
+        if let Some(filename) = filename {
+            headers.push_str(&format!("; filename=\"{}\"", filename));
+        }
+        headers.push_str("\r\n");
+        if let Some(content_type) = content_type {
+            headers.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        headers.push_str("\r\n");
+        self.body.extend_from_slice(headers.as_bytes());
+        self.body.extend_from_slice(content);
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    /// The `Content-Type` and body of the finished form
+    pub fn finish(mut self) -> (String, Vec<u8>) {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", Self::BOUNDARY).as_bytes());
+        (
+            format!("multipart/form-data; boundary={}", Self::BOUNDARY),
+            self.body,
+        )
+    }
+
+    /// A `POST` of this form to `uri`
+    pub fn post(self, uri: &str) -> TestRequest {
+        let (content_type, body) = self.finish();
+        TestRequest::post()
+            .uri(uri)
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+    }
+}
+
+/// Signing keys of the test realm, by key ID; their public halves are in
+/// `testdata/jwks.json`
+const SIGNING_KEYS: [(&str, &str); 2] = [
+    ("test-key-1", include_str!("../testdata/test-key-1.pem")),
+    ("test-key-2", include_str!("../testdata/test-key-2.pem")),
+];
+
+/// What the mock Keycloak serves, shared with its handler
+#[derive(Default)]
+struct MockRealm {
+    fetches: AtomicUsize,
+    /// Key IDs the JWKS currently publishes
+    published: Mutex<Vec<String>>,
+    /// Added to every JWKS response, to widen races between concurrent fetches
+    delay: Mutex<Duration>,
+    /// Accounts the admin API knows, by subject, with whether each is enabled
+    users: Mutex<HashMap<String, bool>>,
+}
+
+/// Access token the mock hands out for the client credentials grant
+const ADMIN_TOKEN: &str = "mock-admin-token";
+
+/// A Keycloak stand-in serving the `upload-realm` JWKS on a local port. Responses
+/// carry an ETag and honour `If-None-Match`. The token endpoint and the admin API's
+/// user lookup are served too, for the accounts added with [`MockKeycloak::add_user`].
+pub struct MockKeycloak {
+    pub url: String,
+    realm: Arc<MockRealm>,
+}
+
+impl MockKeycloak {
+    /// Starts serving with `test-key-1` published
+    pub async fn start() -> Self {
+        let realm = Arc::new(MockRealm::default());
+        realm
+            .published
+            .lock()
+            .unwrap()
+            .push("test-key-1".to_string());
+        let data = web::Data::from(realm.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/realms/upload-realm/protocol/openid-connect/certs",
+                    web::get().to(serve_jwks),
+                )
+                .route(
+                    "/realms/upload-realm/protocol/openid-connect/token",
+                    web::post().to(serve_token),
+                )
+                .route(
+                    "/admin/realms/upload-realm/users/{id}",
+                    web::get().to(serve_user),
+                )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0))
+        .expect("failed to bind the mock Keycloak");
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        Self { url, realm }
+    }
+
+    /// Points `KEYCLOAK_URL` (and the other settings token validation requires) here
+    pub fn configure(&self, env: &mut TestEnv) {
+        env.set("KEYCLOAK_URL", &self.url);
+        env.set("CLIENT_ID", "upload-client");
+        env.set("CLIENT_SECRET", "secret");
+        env.remove("KEYCLOAK_REALM");
+        env.remove("JWT_AUDIENCE");
+    }
+
+    pub fn jwks_url(&self) -> String {
+        format!(
+            "{}/realms/upload-realm/protocol/openid-connect/certs",
+            self.url
+        )
+    }
+
+    pub fn issuer(&self) -> String {
+        format!("{}/realms/upload-realm", self.url)
+    }
+
+    /// Number of JWKS requests served so far
+    pub fn fetches(&self) -> usize {
+        self.realm.fetches.load(Ordering::SeqCst)
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        *self.realm.delay.lock().unwrap() = delay;
+    }
+
+    /// Replaces the published key set
+    pub fn publish(&self, kids: &[&str]) {
+        *self.realm.published.lock().unwrap() = kids.iter().map(|kid| kid.to_string()).collect();
+    }
+
+    /// Makes `sub` known to the admin API; other subjects are reported as deleted
+    pub fn add_user(&self, sub: &str, enabled: bool) {
+        self.realm
+            .users
+            .lock()
+            .unwrap()
+            .insert(sub.to_string(), enabled);
+    }
+
+    /// Claims of a valid, freshly issued token for `sub`
+    pub fn claims(&self, sub: &str) -> Value {
+        let now = jsonwebtoken::get_current_timestamp();
+        json!({
+            "sub": sub,
+            "iss": self.issuer(),
+            "aud": "upload-client",
+            "azp": "upload-client",
+            "iat": now,
+            "exp": now + 300,
+        })
+    }
+
+    /// Signs `claims` with the key `kid`
+    pub fn sign(&self, kid: &str, claims: &Value) -> String {
+        let (_, pem) = SIGNING_KEYS
+            .iter()
+            .find(|(key, _)| *key == kid)
+            .expect("unknown test key");
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.to_string());
+        let key = EncodingKey::from_rsa_pem(pem.as_bytes()).expect("invalid test key");
+        jsonwebtoken::encode(&header, claims, &key).expect("failed to sign test token")
+    }
+
+    /// A valid token for `sub`, signed with `test-key-1`
+    pub fn token(&self, sub: &str) -> String {
+        self.sign("test-key-1", &self.claims(sub))
+    }
+}
+
+async fn serve_jwks(req: HttpRequest, realm: web::Data<MockRealm>) -> HttpResponse {
+    realm.fetches.fetch_add(1, Ordering::SeqCst);
+    let delay = *realm.delay.lock().unwrap();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+    let published = realm.published.lock().unwrap().clone();
+    let etag = format!("\"{}\"", published.join("+"));
+    let unchanged = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes());
+    if unchanged {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+    let jwks: Value = serde_json::from_str(include_str!("../testdata/jwks.json")).unwrap();
+    let keys: Vec<&Value> = jwks["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|key| published.iter().any(|kid| key["kid"] == kid.as_str()))
+        .collect();
+    HttpResponse::Ok()
+        .insert_header((header::ETAG, etag))
+        .json(json!({ "keys": keys }))
+}
+
+async fn serve_token() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "access_token": ADMIN_TOKEN, "token_type": "Bearer" }))
+}
+
+async fn serve_user(
+    req: HttpRequest,
+    id: web::Path<String>,
+    realm: web::Data<MockRealm>,
+) -> HttpResponse {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .is_some_and(|value| value.as_bytes() == format!("Bearer {}", ADMIN_TOKEN).as_bytes());
+    if !authorized {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match realm.users.lock().unwrap().get(id.as_str()) {
+        Some(enabled) => HttpResponse::Ok().json(json!({ "id": *id, "enabled": enabled })),
+        None => HttpResponse::NotFound().json(json!({ "error": "User not found" })),
+    }
+}
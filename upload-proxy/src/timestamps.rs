@@ -0,0 +1,223 @@
+use chrono::{DateTime, SecondsFormat};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+
+/// How upload timestamps are written out, from `TIMESTAMP_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// e.g. `2024-05-01T12:00:00.123456789+00:00` (default)
+    Rfc3339,
+    /// Milliseconds since the Unix epoch, as a JSON number
+    EpochMs,
+}
+
+impl TimestampFormat {
+    /// An RFC 3339 timestamp in this format; values that don't parse are passed through
+    /// unchanged
+    pub fn render(self, timestamp: &str) -> Timestamp {
+        if self == TimestampFormat::EpochMs {
+            if let Ok(at) = DateTime::parse_from_rfc3339(timestamp) {
+                return Timestamp::EpochMs(at.timestamp_millis());
+            }
+        }
+        Timestamp::Text(timestamp.to_string())
+    }
+
+    /// Rewrites the RFC 3339 string at `value` in this format
+    pub fn rewrite(self, value: &mut Value) {
+        if let Some(timestamp) = value.as_str() {
+            *value = serde_json::json!(self.render(timestamp));
+        }
+    }
+}
+
+/// A timestamp as written out in a response or the metadata file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Text(String),
+    EpochMs(i64),
+}
+
+/// Configured format plus whether it also applies to the metadata file, loaded once at
+/// startup
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampConfig {
+    pub format: TimestampFormat,
+    /// From `TIMESTAMP_FORMAT_IN_STORAGE`; otherwise `uploads.json` keeps RFC 3339
+    pub in_storage: bool,
+}
+
+impl Default for TimestampConfig {
+    fn default() -> Self {
+        Self {
+            format: TimestampFormat::Rfc3339,
+            in_storage: false,
+        }
+    }
+}
+
+impl TimestampConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let format = match env::var("TIMESTAMP_FORMAT")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "rfc3339" => TimestampFormat::Rfc3339,
+            "epoch_ms" => TimestampFormat::EpochMs,
+            other => {
+                return Err(format!(
+                    "Invalid TIMESTAMP_FORMAT '{}', expected rfc3339 or epoch_ms",
+                    other
+                ))
+            }
+        };
+        let in_storage = env::var("TIMESTAMP_FORMAT_IN_STORAGE")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        Ok(Self { format, in_storage })
+    }
+
+    /// Format of the timestamps in the metadata file
+    pub fn storage_format(&self) -> TimestampFormat {
+        if self.in_storage {
+            self.format
+        } else {
+            TimestampFormat::Rfc3339
+        }
+    }
+}
+
+/// Reads either format back as RFC 3339, which is what ordering and comparisons use
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match Timestamp::deserialize(deserializer)? {
+        Timestamp::Text(text) => Ok(text),
+        Timestamp::EpochMs(ms) => DateTime::from_timestamp_millis(ms)
+            .map(|at| at.to_rfc3339_opts(SecondsFormat::AutoSi, false))
+            .ok_or_else(|| de::Error::custom(format!("Timestamp {} is out of range", ms))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{app, Form, TestEnv, TEST_USER_HEADER};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::json;
+
+    const AT: &str = "2024-05-01T12:00:00.123+00:00";
+
+    #[test]
+    fn timestamps_render_in_the_configured_format() {
+        assert_eq!(json!(TimestampFormat::Rfc3339.render(AT)), json!(AT));
+        assert_eq!(
+            json!(TimestampFormat::EpochMs.render(AT)),
+            json!(1714564800123i64)
+        );
+        assert_eq!(
+            json!(TimestampFormat::EpochMs.render("yesterday")),
+            json!("yesterday")
+        );
+    }
+
+    #[test]
+    fn storage_keeps_rfc3339_unless_asked() {
+        let mut config = TimestampConfig {
+            format: TimestampFormat::EpochMs,
+            in_storage: false,
+        };
+        assert_eq!(config.storage_format(), TimestampFormat::Rfc3339);
+        config.in_storage = true;
+        assert_eq!(config.storage_format(), TimestampFormat::EpochMs);
+
+        let mut value = json!(AT);
+        config.storage_format().rewrite(&mut value);
+        assert_eq!(value, json!(1714564800123i64));
+    }
+
+    #[test]
+    fn either_format_reads_back_as_rfc3339() {
+        let read = |value: Value| deserialize(value).unwrap();
+        assert_eq!(read(json!(AT)), AT);
+        assert_eq!(
+            read(json!(1714564800123i64)),
+            "2024-05-01T12:00:00.123+00:00"
+        );
+        assert!(deserialize(json!(i64::MAX)).is_err());
+    }
+
+    #[test]
+    fn format_is_read_from_the_environment() {
+        let mut env = TestEnv::new();
+        env.remove("TIMESTAMP_FORMAT");
+        env.remove("TIMESTAMP_FORMAT_IN_STORAGE");
+        let config = TimestampConfig::from_env().unwrap();
+        assert_eq!(config.format, TimestampFormat::Rfc3339);
+        assert!(!config.in_storage);
+
+        env.set("TIMESTAMP_FORMAT", "EPOCH_MS");
+        env.set("TIMESTAMP_FORMAT_IN_STORAGE", "true");
+        let config = TimestampConfig::from_env().unwrap();
+        assert_eq!(config.format, TimestampFormat::EpochMs);
+        assert!(config.in_storage);
+        env.set("TIMESTAMP_FORMAT", "unix");
+        assert!(TimestampConfig::from_env().is_err());
+    }
+
+    /// Uploads `a.txt` and returns the upload's and the listing's JSON, plus the
+    /// metadata file
+    async fn upload_and_list() -> (Value, Value, Value) {
+        let app = init_service(app()).await;
+        let req = Form::new()
+            .file("a.txt", b"data")
+            .post("/api/upload")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let uploaded: Value = read_body_json(resp).await;
+        let req = TestRequest::get()
+            .uri("/api/files")
+            .insert_header((TEST_USER_HEADER, "alice"))
+            .to_request();
+        let listed: Value = read_body_json(call_service(&app, req).await).await;
+        (uploaded, listed["files"][0].clone(), stored())
+    }
+
+    fn stored() -> Value {
+        let metadata_file = env::var("METADATA_FILE").unwrap();
+        serde_json::from_slice(&std::fs::read(metadata_file).unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn epoch_ms_responses_leave_the_metadata_file_in_rfc3339() {
+        let mut env = TestEnv::new().with("TIMESTAMP_FORMAT", "epoch_ms");
+        env.remove("TIMESTAMP_FORMAT_IN_STORAGE");
+        let (uploaded, listed, stored) = upload_and_list().await;
+
+        assert!(uploaded["timestamp"].is_i64(), "{}", uploaded);
+        assert!(listed["timestamp"].is_i64(), "{}", listed);
+        let timestamp = stored[0]["timestamp"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert_eq!(
+            uploaded["timestamp"],
+            json!(TimestampFormat::EpochMs.render(timestamp))
+        );
+    }
+
+    #[actix_web::test]
+    async fn epoch_ms_in_storage_is_read_back() {
+        let _env = TestEnv::new()
+            .with("TIMESTAMP_FORMAT", "epoch_ms")
+            .with("TIMESTAMP_FORMAT_IN_STORAGE", "true");
+        let (uploaded, listed, stored) = upload_and_list().await;
+
+        assert!(stored[0]["timestamp"].is_i64(), "{}", stored);
+        assert_eq!(listed["timestamp"], stored[0]["timestamp"]);
+        assert_eq!(uploaded["timestamp"], stored[0]["timestamp"]);
+    }
+}
@@ -0,0 +1,112 @@
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+
+/// Header carrying the client's SHA-256 tree hash of the uploaded file
+pub const TREE_HASH_HEADER: &str = "X-Tree-Hash";
+
+/// Leaf size of the tree, as used by Amazon Glacier
+const CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Lowercase hex tree hash sent with the request, if any; 400 when malformed
+pub fn expected_tree_hash(req: &HttpRequest) -> Result<Option<String>, actix_web::Error> {
+    let Some(value) = req.headers().get(TREE_HASH_HEADER) else {
+        return Ok(None);
+    };
+    let hash = value.to_str().unwrap_or_default().trim().to_lowercase();
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "{} must be 64 hex characters",
+            TREE_HASH_HEADER
+        )));
+    }
+    Ok(Some(hash))
+}
+
+/// Incremental SHA-256 tree hash: every 1 MiB chunk is hashed as it streams in, and
+/// pairs of hashes are combined level by level up to a single root
+#[derive(Default)]
+pub struct TreeHasher {
+    chunk: Sha256,
+    chunk_len: usize,
+    leaves: Vec<[u8; 32]>,
+}
+
+impl TreeHasher {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = data.len().min(CHUNK_BYTES - self.chunk_len);
+            self.chunk.update(&data[..take]);
+            self.chunk_len += take;
+            data = &data[take..];
+            if self.chunk_len == CHUNK_BYTES {
+                self.leaves.push(self.chunk.finalize_reset().into());
+                self.chunk_len = 0;
+            }
+        }
+    }
+
+    /// Hex root hash; an empty file hashes as a single empty chunk
+    pub fn finalize(mut self) -> String {
+        if self.chunk_len > 0 || self.leaves.is_empty() {
+            self.leaves.push(self.chunk.finalize().into());
+        }
+        let mut level = self.leaves;
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => Sha256::new()
+                        .chain_update(left)
+                        .chain_update(right)
+                        .finalize()
+                        .into(),
+                    // An odd hash out is promoted to the next level unchanged
+                    _ => pair[0],
+                })
+                .collect();
+        }
+        hex::encode(level[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_hash(data: &[u8]) -> String {
+        let mut hasher = TreeHasher::default();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+        parts
+            .iter()
+            .fold(Sha256::new(), |hasher, part| hasher.chain_update(part))
+            .finalize()
+            .into()
+    }
+
+    #[test]
+    fn single_chunks_hash_like_plain_sha256() {
+        assert_eq!(tree_hash(b"hello"), hex::encode(sha256(&[b"hello"])));
+        assert_eq!(tree_hash(b""), hex::encode(sha256(&[b""])));
+    }
+
+    #[test]
+    fn chunk_hashes_are_combined_pairwise() {
+        let data: Vec<u8> = (0..CHUNK_BYTES * 2 + 10).map(|i| i as u8).collect();
+        let (first, rest) = data.split_at(CHUNK_BYTES);
+        let (second, third) = rest.split_at(CHUNK_BYTES);
+        let pair = sha256(&[&sha256(&[first]), &sha256(&[second])]);
+        let root = sha256(&[&pair, &sha256(&[third])]);
+        assert_eq!(tree_hash(&data), hex::encode(root));
+
+        // Chunk boundaries don't depend on how the stream is split
+        let mut hasher = TreeHasher::default();
+        for piece in data.chunks(100_000) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finalize(), hex::encode(root));
+    }
+}
@@ -0,0 +1,177 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use chrono::{DateTime, FixedOffset, NaiveTime, Offset, Timelike, Utc};
+use std::env;
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// Daily hours during which uploads are accepted, from `UPLOAD_WINDOW`
+#[derive(Debug, Clone, Copy)]
+pub struct UploadWindow {
+    /// Seconds after local midnight the window opens
+    start: u32,
+    /// Seconds after local midnight the window closes; before `start` for windows that
+    /// span midnight
+    end: u32,
+    offset: FixedOffset,
+}
+
+fn parse_time(value: &str) -> Result<u32, String> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M")
+        .map(|time| time.num_seconds_from_midnight())
+        .map_err(|_| format!("Invalid UPLOAD_WINDOW time '{}', expected HH:MM", value))
+}
+
+fn parse_offset(value: &str) -> Result<FixedOffset, String> {
+    if value.eq_ignore_ascii_case("UTC") || value == "Z" {
+        return Ok(Utc.fix());
+    }
+    let invalid = || {
+        format!(
+            "Invalid UPLOAD_WINDOW timezone '{}', expected UTC or an offset like +02:00",
+            value
+        )
+    };
+    let (sign, rest) = match value.as_bytes().first() {
+        Some(b'+') => (1, &value[1..]),
+        Some(b'-') => (-1, &value[1..]),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes > 59 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+impl UploadWindow {
+    /// Reads `UPLOAD_WINDOW` as `HH:MM-HH:MM`, optionally followed by `UTC` or an offset
+    /// such as `+02:00` (the default is UTC); unset accepts uploads at any time
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match env::var("UPLOAD_WINDOW") {
+            Ok(value) if !value.trim().is_empty() => Self::parse(&value).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split_whitespace();
+        let hours = parts.next().unwrap_or_default();
+        let offset = match parts.next() {
+            Some(zone) => parse_offset(zone)?,
+            None => parse_offset("UTC")?,
+        };
+        if parts.next().is_some() {
+            return Err(format!("Invalid UPLOAD_WINDOW '{}'", value));
+        }
+        let (start, end) = hours.split_once('-').ok_or_else(|| {
+            format!(
+                "Invalid UPLOAD_WINDOW '{}', expected HH:MM-HH:MM [timezone]",
+                value
+            )
+        })?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(format!("UPLOAD_WINDOW '{}' is empty", value));
+        }
+        Ok(Self { start, end, offset })
+    }
+
+    /// Seconds until the window next opens, or `None` while it is open at `now`
+    pub fn retry_after(&self, now: DateTime<Utc>) -> Option<u32> {
+        let time = now
+            .with_timezone(&self.offset)
+            .time()
+            .num_seconds_from_midnight();
+        let open = if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+        if open {
+            return None;
+        }
+        Some((self.start + SECS_PER_DAY - time) % SECS_PER_DAY)
+    }
+
+    /// 503 with `Retry-After` when uploads are refused at `now`
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), actix_web::Error> {
+        let Some(retry_after) = self.retry_after(now) else {
+            return Ok(());
+        };
+        log::warn!(
+            "Rejecting upload outside the upload window; reopens in {}s",
+            retry_after
+        );
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(serde_json::json!({
+                "error": "outside_upload_window",
+                "retry_after_secs": retry_after,
+            }));
+        Err(
+            actix_web::error::InternalError::from_response("Outside upload window", response)
+                .into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestEnv;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn uploads_are_accepted_inside_the_window() {
+        let window = UploadWindow::parse("08:00-20:00").unwrap();
+        assert_eq!(window.retry_after(at(8, 0)), None);
+        assert_eq!(window.retry_after(at(19, 59)), None);
+        assert_eq!(window.retry_after(at(20, 0)), Some(12 * 3600));
+        assert_eq!(window.retry_after(at(7, 30)), Some(30 * 60));
+        assert!(window.check(at(12, 0)).is_ok());
+    }
+
+    #[test]
+    fn windows_may_span_midnight_in_another_timezone() {
+        // 22:00-06:00 at +02:00 is 20:00-04:00 UTC
+        let window = UploadWindow::parse("22:00-06:00 +02:00").unwrap();
+        assert_eq!(window.retry_after(at(20, 0)), None);
+        assert_eq!(window.retry_after(at(3, 59)), None);
+        assert_eq!(window.retry_after(at(4, 0)), Some(16 * 3600));
+    }
+
+    #[test]
+    fn uploads_outside_the_window_get_retry_after() {
+        let window = UploadWindow::parse("08:00-20:00 UTC").unwrap();
+        let response = window.check(at(6, 0)).unwrap_err().error_response();
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "7200");
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        for value in [
+            "08:00",
+            "8-20",
+            "08:00-08:00",
+            "08:00-20:00 CET",
+            "08:00-20:00 +15:00",
+            "08:00-20:00 UTC extra",
+        ] {
+            assert!(UploadWindow::parse(value).is_err(), "{}", value);
+        }
+        let mut env = TestEnv::new();
+        env.remove("UPLOAD_WINDOW");
+        assert!(UploadWindow::from_env().unwrap().is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use actix_web::http::header::{self, HeaderMap};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+
+use crate::errors::accept_quality;
+
+/// Whether the client asked for XML over JSON, as some legacy integrations do
+pub fn prefers_xml(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let xml = accept_quality(accept, "application/xml").max(accept_quality(accept, "text/xml"));
+    xml > accept_quality(accept, "application/json")
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn write_element(out: &mut String, name: &str, value: &Value) {
+    match value {
+        // Absent fields are omitted, as `skip_serializing_if` does for JSON
+        Value::Null => {}
+        Value::Object(fields) => {
+            let _ = write!(out, "<{}>", name);
+            for (field, value) in fields {
+                write_element(out, field, value);
+            }
+            let _ = write!(out, "</{}>", name);
+        }
+        Value::Array(items) => {
+            let _ = write!(out, "<{}>", name);
+            for item in items {
+                write_element(out, "item", item);
+            }
+            let _ = write!(out, "</{}>", name);
+        }
+        Value::String(text) => {
+            let _ = write!(out, "<{0}>{1}</{0}>", name, escape(text));
+        }
+        Value::Bool(_) | Value::Number(_) => {
+            let _ = write!(out, "<{0}>{1}</{0}>", name, value);
+        }
+    }
+}
+
+/// Serializes `value` as an XML document whose fields become child elements of `root`
+/// and whose list items are `<item>` elements
+pub fn to_xml(root: &str, value: &impl Serialize) -> Result<String, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    write_element(&mut out, root, &value);
+    out.push('\n');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_become_nested_elements() {
+        let value = serde_json::json!({
+            "name": "a <b> & 'c'",
+            "size": 5,
+            "ok": true,
+            "missing": null,
+            "files": [{ "name": "x" }, { "name": "y" }],
+        });
+        assert_eq!(
+            to_xml("upload", &value).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<upload>\
+             <files><item><name>x</name></item><item><name>y</name></item></files>\
+             <name>a &lt;b&gt; &amp; &apos;c&apos;</name><ok>true</ok><size>5</size>\
+             </upload>\n"
+        );
+    }
+
+    #[test]
+    fn xml_is_only_chosen_when_preferred() {
+        let prefers = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            prefers_xml(&headers)
+        };
+        assert!(prefers("application/xml"));
+        assert!(prefers("text/xml, application/json;q=0.5"));
+        assert!(!prefers("application/json, application/xml"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers_xml(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,61 @@
+# Writes x25519.age and identity.txt: age v1 encryption to a fixed X25519 recipient with
+# pinned randomness, written from the spec at https://age-encryption.org/v1 on top of
+# pyca/cryptography. Run from this directory; `age -d -i identity.txt x25519.age`
+# prints the plaintext.
+import base64, hmac, hashlib
+from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+from cryptography.hazmat.primitives import hashes, serialization
+
+raw = lambda k: k.public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+# RFC 7748 section 6.1: Alice's key is the ephemeral share, Bob's the recipient
+alice = X25519PrivateKey.from_private_bytes(bytes.fromhex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a"))
+bob = X25519PrivateKey.from_private_bytes(bytes.fromhex("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb"))
+share, recipient = raw(alice.public_key()), raw(bob.public_key())
+assert share.hex() == "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+assert recipient.hex() == "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"
+secret = alice.exchange(bob.public_key())
+assert secret.hex() == "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742"
+
+file_key = bytes(range(16))
+nonce = bytes(range(0x10, 0x20))
+plaintext = b"age interop vector\n"
+
+def hkdf(salt, ikm, info):
+    return HKDF(hashes.SHA256(), 32, salt, info).derive(ikm)
+
+b64 = lambda b: base64.b64encode(b).rstrip(b"=").decode()
+wrap = ChaCha20Poly1305(hkdf(share + recipient, secret, b"age-encryption.org/v1/X25519"))
+wrapped = wrap.encrypt(bytes(12), file_key, None)
+header = "age-encryption.org/v1\n-> X25519 %s\n%s\n---" % (b64(share), b64(wrapped))
+mac = hmac.new(hkdf(b"", file_key, b"header"), header.encode(), hashlib.sha256).digest()
+header += " %s\n" % b64(mac)
+payload = ChaCha20Poly1305(hkdf(nonce, file_key, b"payload"))
+body = payload.encrypt(bytes(11) + b"\x01", plaintext, None)
+open("x25519.age", "wb").write(header.encode() + nonce + body)
+
+# Bob's identity in age's Bech32 form, for `age -d -i identity.txt x25519.age`
+CHARSET = "qpzry9x8gf2tvdw0s3jn54khce6mua7l"
+def polymod(values):
+    gen = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3]
+    chk = 1
+    for v in values:
+        top = chk >> 25
+        chk = (chk & 0x1ffffff) << 5 ^ v
+        for i in range(5):
+            chk ^= gen[i] if (top >> i) & 1 else 0
+    return chk
+def bech32(hrp, data):
+    acc = bits = 0; out = []
+    for b in data:
+        acc = acc << 8 | b; bits += 8
+        while bits >= 5:
+            bits -= 5; out.append(acc >> bits & 31)
+    if bits: out.append(acc << (5 - bits) & 31)
+    exp = [ord(c) >> 5 for c in hrp] + [0] + [ord(c) & 31 for c in hrp]
+    chk = polymod(exp + out + [0] * 6) ^ 1
+    out += [chk >> 5 * (5 - i) & 31 for i in range(6)]
+    return hrp + "1" + "".join(CHARSET[d] for d in out)
+bob_raw = bob.private_bytes(serialization.Encoding.Raw, serialization.PrivateFormat.Raw, serialization.NoEncryption())
+open("identity.txt", "w").write("# public key: %s\n%s\n" % (bech32("age", recipient), bech32("age-secret-key-", bob_raw).upper()))